@@ -0,0 +1,310 @@
+//! `#[script_api]`, which exposes the methods of a `State` to scripts as natives.
+
+extern crate proc_macro;
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Error, FnArg, ImplItem, ItemImpl, ReturnType, Type};
+
+/// Turns the methods in an `impl` block of a `State` into natives that scripts can call.
+///
+/// ```ignore
+/// #[script_api]
+/// impl Board {
+///     /// read_button() -> number
+///     fn read_button(&mut self) -> i32 {
+///         self.button.is_pressed() as i32
+///     }
+///
+///     /// invert(buffer)
+///     fn invert(&mut self, buffer: &mut [u8]) {
+///         buffer.iter_mut().for_each(|byte| *byte = !*byte);
+///     }
+/// }
+/// ```
+///
+/// Every method with a `self` receiver becomes a native with the same name, other functions in
+/// the block are left alone. Arguments and results can be:
+///
+/// - `i32`, a number
+/// - `bool`, a number that is 0 for false and anything else for true
+/// - `&[u8]` or `&mut [u8]`, the bytes of a bit buffer. A native that takes a `&mut [u8]` traps
+///   if a script passes it the same buffer twice.
+/// - `&str`, a string literal
+/// - `()` as the result, for a native that returns nothing
+/// - `Result<T, TrapKind>` as the result, to trap the script
+///
+/// The block gets three constants, with the natives in the order of the methods:
+///
+/// - `NATIVES`, the `Native` of every method, to register with `Runtime::with_natives` after
+///   `Native::DEFAULTS`
+/// - `SIGNATURES`, the signatures of `NATIVES`, for compiling scripts without a runtime
+/// - `MANIFEST`, the signatures as `manifest::write` writes them. Appended to the manifest of
+///   `Native::DEFAULTS`, this is the manifest of the host.
+#[proc_macro_attribute]
+pub fn script_api(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let item = parse_macro_input!(item as ItemImpl);
+    let result = if attr.is_empty() {
+        expand(&item)
+    } else {
+        Err(Error::new(
+            TokenStream::from(attr).span(),
+            "script_api takes no arguments",
+        ))
+    };
+    match result {
+        Ok(natives) => quote!(#item #natives).into(),
+        Err(error) => {
+            let error = error.to_compile_error();
+            quote!(#item #error).into()
+        }
+    }
+}
+
+/// How a Rust type is passed to or from a script.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Number,
+    Bool,
+    Buffer,
+    BufferMut,
+    Str,
+    Unit,
+}
+
+impl Kind {
+    fn of_arg(ty: &Type) -> Result<Self, Error> {
+        match ty {
+            Type::Reference(reference) if is_byte_slice(&reference.elem) => {
+                Ok(if reference.mutability.is_some() {
+                    Kind::BufferMut
+                } else {
+                    Kind::Buffer
+                })
+            }
+            Type::Reference(reference)
+                if reference.mutability.is_none() && is_path(&reference.elem, "str") =>
+            {
+                Ok(Kind::Str)
+            }
+            ty if is_path(ty, "i32") => Ok(Kind::Number),
+            ty if is_path(ty, "bool") => Ok(Kind::Bool),
+            ty => Err(Error::new(
+                ty.span(),
+                "script_api arguments have to be i32, bool, &[u8], &mut [u8] or &str",
+            )),
+        }
+    }
+
+    /// The kind of a result, and whether it is wrapped in a `Result`.
+    fn of_result(output: &ReturnType) -> Result<(Self, bool), Error> {
+        let ty = match output {
+            ReturnType::Default => return Ok((Kind::Unit, false)),
+            ReturnType::Type(_, ty) => ty,
+        };
+        if let Some(ok) = result_ok_type(ty) {
+            return Ok((Self::of_plain_result(ok)?, true));
+        }
+        Ok((Self::of_plain_result(ty)?, false))
+    }
+
+    fn of_plain_result(ty: &Type) -> Result<Self, Error> {
+        match ty {
+            Type::Tuple(tuple) if tuple.elems.is_empty() => Ok(Kind::Unit),
+            ty if is_path(ty, "i32") => Ok(Kind::Number),
+            ty if is_path(ty, "bool") => Ok(Kind::Bool),
+            ty => Err(Error::new(
+                ty.span(),
+                "script_api results have to be (), i32 or bool, optionally in a Result",
+            )),
+        }
+    }
+
+    /// The name of the type in a manifest, and the `Type` variant in `shared`.
+    fn script_type(self) -> Option<(&'static str, TokenStream)> {
+        match self {
+            Kind::Number | Kind::Bool => Some(("number", quote!(::shared::Type::Number))),
+            Kind::Buffer | Kind::BufferMut => Some(("buffer", quote!(::shared::Type::Buffer))),
+            Kind::Str => Some(("string", quote!(::shared::Type::String))),
+            Kind::Unit => None,
+        }
+    }
+}
+
+fn is_byte_slice(ty: &Type) -> bool {
+    matches!(ty, Type::Slice(slice) if is_path(&slice.elem, "u8"))
+}
+
+fn is_path(ty: &Type, name: &str) -> bool {
+    matches!(ty, Type::Path(path) if path.qself.is_none() && path.path.is_ident(name))
+}
+
+/// `T` if `ty` is `Result<T, _>`.
+fn result_ok_type(ty: &Type) -> Option<&Type> {
+    let path = match ty {
+        Type::Path(path) if path.qself.is_none() => &path.path,
+        _ => return None,
+    };
+    let segment = path.segments.last()?;
+    if segment.ident != "Result" {
+        return None;
+    }
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) => match args.args.first()? {
+            syn::GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn expand(item: &ItemImpl) -> Result<TokenStream, Error> {
+    if item.trait_.is_some() {
+        return Err(Error::new(
+            item.span(),
+            "script_api goes on an inherent impl block",
+        ));
+    }
+    if !item.generics.params.is_empty() {
+        return Err(Error::new(
+            item.generics.span(),
+            "script_api does not support generic impl blocks",
+        ));
+    }
+    let self_ty = &item.self_ty;
+
+    let mut natives = Vec::new();
+    let mut signatures = Vec::new();
+    let mut manifest = String::new();
+    for method in item.items.iter().filter_map(|item| match item {
+        ImplItem::Fn(method) if method.sig.receiver().is_some() => Some(method),
+        _ => None,
+    }) {
+        let name = &method.sig.ident;
+        let args = method
+            .sig
+            .inputs
+            .iter()
+            .filter_map(|arg| match arg {
+                FnArg::Typed(arg) => Some(Kind::of_arg(&arg.ty)),
+                FnArg::Receiver(_) => None,
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // `MAX_ARGS` is only known to `shared`, so the amount of arguments is checked where the
+        // handler is compiled
+        let arg_count = args.len();
+        let message = format!("`{}` takes more arguments than `shared::MAX_ARGS`", name);
+        let check_args = quote_spanned! {method.sig.inputs.span()=>
+            const _: () = ::core::assert!(#arg_count <= ::shared::MAX_ARGS, #message);
+        };
+        let (result, fallible) = Kind::of_result(&method.sig.output)?;
+
+        let arg_types: Vec<_> = args.iter().filter_map(|arg| arg.script_type()).collect();
+        let arg_names: Vec<_> = arg_types.iter().map(|(name, _)| *name).collect();
+        manifest.push_str(&format!("{}({})", name, arg_names.join(", ")));
+        if let Some((result, _)) = result.script_type() {
+            manifest.push_str(&format!(" -> {}", result));
+        }
+        manifest.push('\n');
+
+        let name_str = name.to_string();
+        let arg_types = arg_types.iter().map(|(_, ty)| ty);
+        let result_type = match result.script_type() {
+            Some((_, ty)) => quote!(::core::option::Option::Some(#ty)),
+            None => quote!(::core::option::Option::None),
+        };
+        let signature = quote! {
+            ::shared::Signature {
+                name: #name_str,
+                args: &[#(#arg_types),*],
+                result: #result_type,
+            }
+        };
+
+        // the buffers are borrowed from the memory of the runtime while the state is borrowed for
+        // the call. Natives that change a buffer get every buffer at once, which traps if the
+        // same buffer is passed twice.
+        let buffers: Vec<_> = (0..args.len())
+            .filter(|idx| matches!(args[*idx], Kind::Buffer | Kind::BufferMut))
+            .collect();
+        let names: Vec<_> = buffers
+            .iter()
+            .map(|idx| format_ident!("buffer{}", idx))
+            .collect();
+        let unpack = if args.contains(&Kind::BufferMut) {
+            quote!(let [#(#names),*] = runtime.memory.buffers_mut([#(args[#buffers]),*])?;)
+        } else {
+            quote!(#(let #names = runtime.memory.buffer(args[#buffers])?;)*)
+        };
+        // strings live in the constant pool, not in the runtime, so they are not borrowed
+        let strings: Vec<_> = (0..args.len())
+            .filter(|idx| args[*idx] == Kind::Str)
+            .collect();
+        let string_names = strings.iter().map(|idx| format_ident!("string{}", idx));
+        let unpack = quote! {
+            #(let #string_names = runtime.string(args[#strings])?;)*
+            #unpack
+        };
+        let pass = args.iter().enumerate().map(|(idx, arg)| {
+            let buffer = format_ident!("buffer{}", idx);
+            match arg {
+                Kind::Number => quote!(args[#idx]),
+                Kind::Bool => quote!(args[#idx] != 0),
+                Kind::Buffer if args.contains(&Kind::BufferMut) => quote!(&*#buffer),
+                Kind::Buffer | Kind::BufferMut => quote!(#buffer),
+                Kind::Str => {
+                    let string = format_ident!("string{}", idx);
+                    quote!(#string)
+                }
+                Kind::Unit => unreachable!(),
+            }
+        });
+        let result_value = if fallible {
+            quote!(result?)
+        } else {
+            quote!(result)
+        };
+        let pack = match result {
+            Kind::Number => quote!(#result_value),
+            Kind::Bool => quote!(#result_value as i32),
+            _ => quote!({
+                let () = #result_value;
+                0
+            }),
+        };
+        natives.push(quote! {
+            ::shared::Native {
+                signature: #signature,
+                handler: {
+                    #check_args
+                    fn handler(
+                        runtime: &mut ::shared::Runtime<'_, #self_ty>,
+                        args: [i32; ::shared::MAX_ARGS],
+                    ) -> ::core::result::Result<i32, ::shared::TrapKind> {
+                        #unpack
+                        let result = runtime.state.#name(#(#pass),*);
+                        ::core::result::Result::Ok(#pack)
+                    }
+                    handler
+                },
+            }
+        });
+        signatures.push(signature);
+    }
+
+    let count = natives.len();
+    Ok(quote! {
+        impl #self_ty {
+            /// The natives generated by `script_api`, in the order of the methods.
+            pub const NATIVES: [::shared::Native<Self>; #count] = [#(#natives),*];
+            /// The signatures of `NATIVES`.
+            pub const SIGNATURES: [::shared::Signature<'static>; #count] = [#(#signatures),*];
+            /// The manifest of `NATIVES`, to append to the manifest of `Native::DEFAULTS`.
+            pub const MANIFEST: &'static str = #manifest;
+        }
+    })
+}
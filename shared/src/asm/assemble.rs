@@ -0,0 +1,804 @@
+use crate::container::{features, Header, HEADER_SIZE};
+use crate::debug_info;
+use crate::instructions::{Instructions, MethodRef, VariableRef};
+use crate::natives::{self, manifest, Signature, Type, DEFAULT_SIGNATURES, MAX_ARGS};
+use crate::pool::{self, Constant};
+use arrayvec::ArrayVec;
+use core::convert::TryFrom;
+use core::str::FromStr;
+
+/// The most labels a listing can have.
+pub const MAX_LABELS: usize = 512;
+
+/// The offset of every label, in the order of the listing.
+type Labels<'a> = ArrayVec<[(&'a str, u16); MAX_LABELS]>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Error<'a> {
+    /// The line the error is on, starting at 1. 0 for errors that are not on a single line.
+    pub line: usize,
+    pub kind: ErrorKind<'a>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind<'a> {
+    UnknownMnemonic(&'a str),
+    UnknownMethod(&'a str),
+    InvalidOperand(&'a str),
+    InvalidNumber(&'a str),
+    WrongOperandCount {
+        expected: usize,
+        found: usize,
+    },
+    UnknownLabel(&'a str),
+    DuplicateLabel(&'a str),
+    /// The listing has more than `MAX_LABELS` labels.
+    TooManyLabels,
+    BufferTooSmall,
+    ProgramTooLarge,
+}
+
+/// Assembles a listing into a bytecode container in `buffer`, returning the amount of bytes
+/// written. Assembling the output of `disassemble` gives back the same bytecode.
+///
+/// The offsets at the start of a line are ignored, so lines can be added to a listing without
+/// renumbering it. Without directives, the container requires no features, counts every slot
+/// up to the highest one used, is marked with the hash of the natives it resolves calls against
+/// and starts at offset 0. A constant pool is only added if there
+/// are `.const` directives, and debug info if there are `.line`, `.name` or `.on_reload`
+/// directives.
+///
+/// The labels are found in a pass over the listing before it is assembled, so a listing can
+/// jump forward, and can have at most `MAX_LABELS` of them.
+///
+/// Calls are resolved against `Native::DEFAULTS`, see `assemble_with_natives` for other natives.
+pub fn assemble<'a>(source: &'a str, buffer: &mut [u8]) -> Result<usize, Error<'a>> {
+    assemble_with_natives(source, buffer, &DEFAULT_SIGNATURES)
+}
+
+/// Like `assemble`, resolving calls by name against `natives`. A call can also refer to a native
+/// by its index, as `call #n(...)`.
+pub fn assemble_with_natives<'a>(
+    source: &'a str,
+    buffer: &mut [u8],
+    natives: &[Signature],
+) -> Result<usize, Error<'a>> {
+    if buffer.len() < HEADER_SIZE {
+        return Err(Error {
+            line: 0,
+            kind: ErrorKind::BufferTooSmall,
+        });
+    }
+    let mut header = Header {
+        required_features: 0,
+        variable_count: 0,
+        entry_point: 0,
+        code_len: 0,
+        natives_hash: manifest::hash(natives),
+        pool_len: 0,
+    };
+    let labels = find_labels(source)?;
+    let mut variable_count = None;
+    let mut constant_count = 0usize;
+    let mut has_debug_info = false;
+    let mut reload_entry = None;
+    let mut offset = 0;
+    for (idx, line) in source.lines().enumerate() {
+        let error = |kind| Error {
+            line: idx + 1,
+            kind,
+        };
+        let mut line = parse_line(line).map_err(error)?;
+        match &mut line {
+            Line::Features(features) => header.required_features = *features,
+            Line::Variables(count) => variable_count = Some(*count),
+            Line::Natives(hash) => header.natives_hash = *hash,
+            Line::Entry(target) => header.entry_point = resolve(&labels, *target).map_err(error)?,
+            Line::ReloadEntry(target) => {
+                reload_entry = Some(resolve(&labels, *target).map_err(error)?);
+                has_debug_info = true;
+            }
+            Line::Call(instruction, name) => {
+                if let Instructions::CallMethod { method, .. } = instruction {
+                    *method = resolve_native(natives, name, method.arg_len()).map_err(error)?;
+                }
+                header.variable_count = header.variable_count.max(slots_used(instruction));
+            }
+            Line::Instruction(instruction, target) => {
+                if let Some(target) = target {
+                    if let Some(jump) = jump_target(instruction) {
+                        *jump = resolve(&labels, *target).map_err(error)?;
+                    }
+                }
+                header.variable_count = header.variable_count.max(slots_used(instruction));
+            }
+            Line::Const(_) => constant_count += 1,
+            Line::Source(_) | Line::Name(..) => has_debug_info = true,
+            Line::Empty | Line::Label(_) | Line::Byte(_) => {}
+        }
+
+        let size = line.size();
+        if offset + size > u16::MAX as usize {
+            return Err(error(ErrorKind::ProgramTooLarge));
+        }
+        let code = buffer
+            .get_mut(HEADER_SIZE + offset..HEADER_SIZE + offset + size)
+            .ok_or_else(|| error(ErrorKind::BufferTooSmall))?;
+        match line {
+            Line::Byte(byte) => code[0] = byte,
+            Line::Instruction(instruction, _) | Line::Call(instruction, _) => {
+                instruction.write(code)
+            }
+            _ => {}
+        }
+        offset += size;
+    }
+
+    header.variable_count = variable_count.unwrap_or(header.variable_count);
+    header.code_len = offset as u16;
+    if constant_count > 0 {
+        header.required_features |= features::CONSTANT_POOL;
+        header.pool_len = write_pool(source, constant_count, &header, buffer)?;
+    }
+    header.write(buffer);
+    let mut len = header.len();
+
+    if has_debug_info {
+        // every line is known to parse by now
+        let lines = || source.lines().filter_map(|line| parse_line(line).ok());
+        let source_lines = lines()
+            .scan(0, |offset, line| {
+                let start = *offset;
+                *offset += line.size();
+                Some((start as u16, line))
+            })
+            .filter_map(|(offset, line)| match line {
+                Line::Source(line) => Some((offset, line)),
+                _ => None,
+            });
+        let names = lines().filter_map(|line| match line {
+            Line::Name(slot, ty, name) => Some((slot, ty, name)),
+            _ => None,
+        });
+        len += debug_info::write(&mut buffer[len..], reload_entry, source_lines, names).ok_or(
+            Error {
+                line: 0,
+                kind: ErrorKind::BufferTooSmall,
+            },
+        )?;
+    }
+    Ok(len)
+}
+
+/// Writes the constants of the `.const` directives to the pool of `header`, returning the length
+/// of the pool.
+fn write_pool<'a>(
+    source: &'a str,
+    count: usize,
+    header: &Header,
+    buffer: &mut [u8],
+) -> Result<u16, Error<'a>> {
+    let error = |kind| Error { line: 0, kind };
+    let count = u8::try_from(count).map_err(|_| error(ErrorKind::ProgramTooLarge))?;
+    let pool_start = header.pool_range().start;
+    let mut writer = buffer
+        .get_mut(pool_start..)
+        .and_then(|buffer| pool::Writer::new(buffer, count))
+        .ok_or_else(|| error(ErrorKind::BufferTooSmall))?;
+    let mut text = [0; 255];
+    // every line is known to parse by now
+    for line in source.lines().filter_map(|line| parse_line(line).ok()) {
+        let constant = match line {
+            Line::Const(Constant::String(escaped)) => {
+                Constant::String(unescape(escaped, &mut text).unwrap_or_default())
+            }
+            Line::Const(constant) => constant,
+            _ => continue,
+        };
+        writer
+            .push(constant)
+            .ok_or_else(|| error(ErrorKind::BufferTooSmall))?;
+    }
+    u16::try_from(writer.finish()).map_err(|_| error(ErrorKind::ProgramTooLarge))
+}
+
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    Label(&'a str),
+    Offset(u16),
+}
+
+enum Line<'a> {
+    Empty,
+    Label(&'a str),
+    Features(u16),
+    Variables(u16),
+    /// `.natives`, the hash of the natives the code was written against
+    Natives(u32),
+    Entry(Target<'a>),
+    /// `.on_reload`, where `Runtime::reload` continues
+    ReloadEntry(Target<'a>),
+    Byte(u8),
+    /// `.const`, the next constant in the pool. Strings are still escaped.
+    Const(Constant<'a>),
+    /// `.line`, the script line the next instructions come from
+    Source(u32),
+    /// `.name`, the name and type of a variable slot
+    Name(u8, Type, &'a str),
+    /// An instruction, and the label or offset it jumps to
+    Instruction(Instructions, Option<Target<'a>>),
+    /// A call, and the name of the native it calls. The native is resolved once the natives
+    /// are known.
+    Call(Instructions, &'a str),
+}
+
+impl Line<'_> {
+    fn size(&self) -> usize {
+        match self {
+            Line::Byte(_) => 1,
+            Line::Instruction(instruction, _) | Line::Call(instruction, _) => instruction.size(),
+            _ => 0,
+        }
+    }
+}
+
+/// Finds the offset of every label in `source`, reporting the first line that does not parse.
+fn find_labels(source: &str) -> Result<Labels<'_>, Error<'_>> {
+    let mut labels = Labels::new();
+    let mut offset = 0usize;
+    for (idx, line) in source.lines().enumerate() {
+        let error = |kind| Error {
+            line: idx + 1,
+            kind,
+        };
+        match parse_line(line).map_err(error)? {
+            Line::Label(name) if labels.iter().any(|(other, _)| *other == name) => {
+                return Err(error(ErrorKind::DuplicateLabel(name)))
+            }
+            // a listing too large for its offsets is reported while assembling it
+            Line::Label(name) => labels
+                .try_push((name, offset as u16))
+                .map_err(|_| error(ErrorKind::TooManyLabels))?,
+            line => offset += line.size(),
+        }
+    }
+    Ok(labels)
+}
+
+/// The offset of a target, from the labels found by `find_labels`.
+fn resolve<'a>(labels: &Labels<'a>, target: Target<'a>) -> Result<u16, ErrorKind<'a>> {
+    match target {
+        Target::Offset(offset) => Ok(offset),
+        Target::Label(name) => labels
+            .iter()
+            .find(|(label, _)| *label == name)
+            .map(|(_, offset)| *offset)
+            .ok_or(ErrorKind::UnknownLabel(name)),
+    }
+}
+
+/// The native called `name`, or `#n` for the native at index `n`, taking `arg_len` arguments.
+fn resolve_native<'a>(
+    natives: &[Signature],
+    name: &'a str,
+    arg_len: usize,
+) -> Result<MethodRef, ErrorKind<'a>> {
+    let index = match name.strip_prefix('#') {
+        Some(index) => number(index)?,
+        None => {
+            let (index, signature) =
+                natives::find(natives, name).ok_or(ErrorKind::UnknownMethod(name))?;
+            if signature.args.len() != arg_len {
+                return Err(ErrorKind::WrongOperandCount {
+                    expected: signature.args.len(),
+                    found: arg_len,
+                });
+            }
+            index
+        }
+    };
+    MethodRef::new(index, arg_len).ok_or(ErrorKind::InvalidNumber(name))
+}
+
+fn jump_target(instruction: &mut Instructions) -> Option<&mut u16> {
+    match instruction {
+        Instructions::Jump { target }
+        | Instructions::JumpIfTrue { target }
+        | Instructions::JumpIfFalse { target } => Some(target),
+        _ => None,
+    }
+}
+
+/// The amount of variable slots needed to run `instruction`.
+fn slots_used(instruction: &Instructions) -> u16 {
+    let mut operands = [VariableRef::None; MAX_ARGS + 1];
+    let target = match *instruction {
+        Instructions::CallMethod {
+            result_variable,
+            args,
+            ..
+        } => {
+            operands[0] = result_variable;
+            operands[1..].copy_from_slice(&args);
+            None
+        }
+        Instructions::CompareEquals { left, right }
+        | Instructions::CompareLessThan { left, right }
+        | Instructions::CompareLessOrEqualTo { left, right } => {
+            operands[..2].copy_from_slice(&[left, right]);
+            None
+        }
+        Instructions::Move { target, value } => {
+            operands[0] = value;
+            Some(target)
+        }
+        Instructions::Add {
+            target,
+            left,
+            right,
+        }
+        | Instructions::Subtract {
+            target,
+            left,
+            right,
+        }
+        | Instructions::Multiply {
+            target,
+            left,
+            right,
+        }
+        | Instructions::ShiftLeft {
+            target,
+            left,
+            right,
+        } => {
+            operands[..2].copy_from_slice(&[left, right]);
+            Some(target)
+        }
+        Instructions::Jump { .. }
+        | Instructions::JumpIfTrue { .. }
+        | Instructions::JumpIfFalse { .. } => return 0,
+    };
+    let slots = operands.iter().filter_map(|operand| match operand {
+        VariableRef::Idx(idx) => Some(*idx),
+        _ => None,
+    });
+    target
+        .into_iter()
+        .chain(slots)
+        .map(|idx| idx as u16 + 1)
+        .max()
+        .unwrap_or(0)
+}
+
+fn parse_line(line: &str) -> Result<Line<'_>, ErrorKind<'_>> {
+    // A string can contain a `;`, so constants are parsed before removing the comment
+    if let Some(rest) = line.trim_start().strip_prefix(".const") {
+        if rest.starts_with(char::is_whitespace) {
+            return parse_const(rest.trim_start()).map(Line::Const);
+        }
+    }
+    let line = line.split(';').next().unwrap_or_default().trim();
+    if line.is_empty() {
+        return Ok(Line::Empty);
+    }
+    if let Some(name) = line.strip_suffix(':') {
+        return if is_identifier(name) {
+            Ok(Line::Label(name))
+        } else {
+            Err(ErrorKind::InvalidOperand(name))
+        };
+    }
+
+    let (mut word, mut rest) = split_word(line);
+    // Skip the offset column of a listing
+    if word.len() == 4 && word.bytes().all(|b| b.is_ascii_hexdigit()) && !rest.is_empty() {
+        let (next_word, next_rest) = split_word(rest);
+        word = next_word;
+        rest = next_rest;
+    }
+
+    if word == "call" {
+        return parse_call(rest);
+    }
+    let mut operands = [""; 3];
+    let mut count = 0;
+    if !rest.is_empty() {
+        for operand in rest.split(',') {
+            if count < operands.len() {
+                operands[count] = operand.trim();
+            }
+            count += 1;
+        }
+    }
+    let expect = |expected: usize| {
+        if count == expected {
+            Ok(())
+        } else {
+            Err(ErrorKind::WrongOperandCount {
+                expected,
+                found: count,
+            })
+        }
+    };
+    let [a, b, c] = operands;
+
+    let line = match word {
+        ".features" => {
+            expect(1)?;
+            Line::Features(number(a)?)
+        }
+        ".variables" => {
+            expect(1)?;
+            Line::Variables(number(a)?)
+        }
+        ".natives" => {
+            expect(1)?;
+            Line::Natives(number(a)?)
+        }
+        ".entry" => {
+            expect(1)?;
+            Line::Entry(target(a)?)
+        }
+        ".on_reload" => {
+            expect(1)?;
+            Line::ReloadEntry(target(a)?)
+        }
+        ".byte" => {
+            expect(1)?;
+            Line::Byte(number(a)?)
+        }
+        ".line" => {
+            expect(1)?;
+            Line::Source(number(a)?)
+        }
+        ".name" if b.is_empty() => return Err(ErrorKind::InvalidOperand(b)),
+        ".name" => {
+            expect(3)?;
+            let ty = Type::from_name(c).ok_or(ErrorKind::InvalidOperand(c))?;
+            Line::Name(slot(a)?, ty, b)
+        }
+        "jump" | "jump_if_true" | "jump_if_false" => {
+            expect(1)?;
+            let instruction = match word {
+                "jump" => Instructions::Jump { target: 0 },
+                "jump_if_true" => Instructions::JumpIfTrue { target: 0 },
+                _ => Instructions::JumpIfFalse { target: 0 },
+            };
+            Line::Instruction(instruction, Some(target(a)?))
+        }
+        "cmp_eq" | "cmp_lt" | "cmp_le" => {
+            expect(2)?;
+            let (left, right) = (operand(a)?, operand(b)?);
+            Line::Instruction(
+                match word {
+                    "cmp_eq" => Instructions::CompareEquals { left, right },
+                    "cmp_lt" => Instructions::CompareLessThan { left, right },
+                    _ => Instructions::CompareLessOrEqualTo { left, right },
+                },
+                None,
+            )
+        }
+        "move" => {
+            expect(2)?;
+            Line::Instruction(
+                Instructions::Move {
+                    target: slot(a)?,
+                    value: operand(b)?,
+                },
+                None,
+            )
+        }
+        "add" | "sub" | "mul" | "shl" => {
+            expect(3)?;
+            let (target, left, right) = (slot(a)?, operand(b)?, operand(c)?);
+            let instruction = match word {
+                "add" => Instructions::Add {
+                    target,
+                    left,
+                    right,
+                },
+                "sub" => Instructions::Subtract {
+                    target,
+                    left,
+                    right,
+                },
+                "mul" => Instructions::Multiply {
+                    target,
+                    left,
+                    right,
+                },
+                _ => Instructions::ShiftLeft {
+                    target,
+                    left,
+                    right,
+                },
+            };
+            Line::Instruction(instruction, None)
+        }
+        _ => return Err(ErrorKind::UnknownMnemonic(word)),
+    };
+    Ok(line)
+}
+
+/// Parses the operands of `call method(args) -> result`.
+fn parse_call(rest: &str) -> Result<Line<'_>, ErrorKind<'_>> {
+    let (name, rest) = rest
+        .split_once('(')
+        .ok_or(ErrorKind::InvalidOperand(rest))?;
+    let (args, result) = rest
+        .split_once(')')
+        .ok_or(ErrorKind::InvalidOperand(rest))?;
+    let name = name.trim();
+
+    let mut arg_refs = [VariableRef::None; MAX_ARGS];
+    let mut count = 0;
+    if !args.trim().is_empty() {
+        for arg in args.split(',') {
+            if count < MAX_ARGS {
+                arg_refs[count] = operand(arg.trim())?;
+            }
+            count += 1;
+        }
+    }
+    // the native is filled in by `assemble`
+    let method = MethodRef::new(0, count).ok_or(ErrorKind::WrongOperandCount {
+        expected: MAX_ARGS,
+        found: count,
+    })?;
+
+    let result = result.trim();
+    let result_variable = if result.is_empty() {
+        VariableRef::None
+    } else {
+        let result = result
+            .strip_prefix("->")
+            .ok_or(ErrorKind::InvalidOperand(result))?;
+        operand(result.trim())?
+    };
+    Ok(Line::Call(
+        Instructions::CallMethod {
+            result_variable,
+            method,
+            args: arg_refs,
+        },
+        name,
+    ))
+}
+
+/// Parses the value of `.const`: `#n` for a number, `0x` and hex digits for bits, or a string in
+/// quotes.
+fn parse_const(text: &str) -> Result<Constant<'_>, ErrorKind<'_>> {
+    let quoted = match text.strip_prefix('"') {
+        Some(quoted) => quoted,
+        None => {
+            let text = text.split(';').next().unwrap_or_default().trim();
+            return if let Some(num) = text.strip_prefix('#') {
+                num.parse()
+                    .map(Constant::Number)
+                    .map_err(|_| ErrorKind::InvalidNumber(text))
+            } else if let Some(bits) = text.strip_prefix("0x") {
+                u128::from_str_radix(bits, 16)
+                    .map(Constant::Bits)
+                    .map_err(|_| ErrorKind::InvalidNumber(text))
+            } else {
+                Err(ErrorKind::InvalidOperand(text))
+            };
+        }
+    };
+
+    let mut escaped = false;
+    let end = quoted.find(|c| {
+        let end = c == '"' && !escaped;
+        escaped = c == '\\' && !escaped;
+        end
+    });
+    let end = end.ok_or(ErrorKind::InvalidOperand(text))?;
+    let (value, rest) = (&quoted[..end], quoted[end + 1..].trim_start());
+    if !rest.is_empty() && !rest.starts_with(';') {
+        return Err(ErrorKind::InvalidOperand(rest));
+    }
+    match unescape(value, &mut [0; 255]) {
+        Some(_) => Ok(Constant::String(value)),
+        None => Err(ErrorKind::InvalidOperand(value)),
+    }
+}
+
+/// Replaces the escapes `\\`, `\"` and `\xNN` in `text`, writing the result to `buffer`. Returns
+/// `None` for an invalid escape, or if the result is too long or not UTF-8.
+fn unescape<'b>(text: &str, buffer: &'b mut [u8; 255]) -> Option<&'b str> {
+    let mut bytes = text.bytes();
+    let mut len = 0;
+    while let Some(byte) = bytes.next() {
+        let byte = match byte {
+            b'\\' => match bytes.next()? {
+                b'x' => {
+                    let hex = [bytes.next()?, bytes.next()?];
+                    u8::from_str_radix(core::str::from_utf8(&hex).ok()?, 16).ok()?
+                }
+                escaped @ (b'\\' | b'"') => escaped,
+                _ => return None,
+            },
+            byte => byte,
+        };
+        *buffer.get_mut(len)? = byte;
+        len += 1;
+    }
+    core::str::from_utf8(&buffer[..len]).ok()
+}
+
+fn split_word(line: &str) -> (&str, &str) {
+    match line.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (line, ""),
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// An unsigned number, in decimal or in hex with a `0x` prefix.
+fn number<T: TryFrom<u32>>(text: &str) -> Result<T, ErrorKind<'_>> {
+    let number = match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    number
+        .ok()
+        .and_then(|number| T::try_from(number).ok())
+        .ok_or(ErrorKind::InvalidNumber(text))
+}
+
+fn target(text: &str) -> Result<Target<'_>, ErrorKind<'_>> {
+    if is_identifier(text) {
+        Ok(Target::Label(text))
+    } else {
+        number(text).map(Target::Offset)
+    }
+}
+
+fn slot(text: &str) -> Result<u8, ErrorKind<'_>> {
+    match operand(text)? {
+        VariableRef::Idx(idx) => Ok(idx),
+        _ => Err(ErrorKind::InvalidOperand(text)),
+    }
+}
+
+fn operand(text: &str) -> Result<VariableRef, ErrorKind<'_>> {
+    if text == "_" {
+        Ok(VariableRef::None)
+    } else if let Some(idx) = text.strip_prefix('%') {
+        idx.parse()
+            .map(VariableRef::Idx)
+            .map_err(|_| ErrorKind::InvalidNumber(text))
+    } else if let Some(idx) = text.strip_prefix('$') {
+        idx.parse()
+            .map(VariableRef::Const)
+            .map_err(|_| ErrorKind::InvalidNumber(text))
+    } else if let Some(num) = text.strip_prefix('#') {
+        i32::from_str(num)
+            .map(VariableRef::Num)
+            .or_else(|_| f32::from_str(num).map(VariableRef::Float))
+            .map_err(|_| ErrorKind::InvalidNumber(text))
+    } else {
+        Err(ErrorKind::InvalidOperand(text))
+    }
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_round_trip() {
+    let script = r#"
+buffer = get_bit_buffer(10)
+for x in 0, 10:
+    if x > 2 and not x == 5:
+        set_bit_buffer_index(buffer, x)
+set_frame_buffer(buffer)
+"#;
+    let mut bytecode = [0u8; 256];
+    let options = crate::compiler::Options {
+        optimize: true,
+        debug_info: true,
+        ..Default::default()
+    };
+    let len = crate::compiler::compile_with_options(script, &mut bytecode, &options).unwrap();
+    let mut listing = std::string::String::new();
+    super::disassemble(&bytecode[..len], Some(script), &mut listing).unwrap();
+    assert!(listing.contains("\n.name %1, x, number\n"));
+    assert!(listing.contains("\n.line 3 ; for x in 0, 10:\n"));
+
+    let mut assembled = [0u8; 256];
+    let assembled_len = assemble(&listing, &mut assembled).unwrap();
+    assert_eq!(&bytecode[..len], &assembled[..assembled_len]);
+
+    // Code that does not decode survives as well
+    bytecode[HEADER_SIZE + 0x05] = 0xFF;
+    listing.clear();
+    super::disassemble(&bytecode[..len], None, &mut listing).unwrap();
+    let assembled_len = assemble(&listing, &mut assembled).unwrap();
+    assert_eq!(
+        &bytecode[HEADER_SIZE..len],
+        &assembled[HEADER_SIZE..assembled_len]
+    );
+    let mut relisted = std::string::String::new();
+    super::disassemble(&assembled[..assembled_len], None, &mut relisted).unwrap();
+    // which now has a valid header again
+    assert!(relisted.ends_with(listing.split_once('\n').unwrap().1));
+}
+
+#[test]
+fn test_assemble() {
+    let source = r#"
+; sets the bits 0, 2, 4 and 6
+    call get_bit_buffer(#8) -> %0
+    move %1, #0
+loop:
+    call set_bit_buffer_index(%0, %1)
+    add %1, %1, #2
+    cmp_lt %1, #8
+    jump_if_true loop
+    call set_frame_buffer(%0)
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = assemble(source, &mut bytecode).unwrap();
+    assert_eq!(2, Header::read(&bytecode[..len]).unwrap().variable_count);
+
+    let mut runtime = crate::runtime::Runtime::new(
+        &mut bytecode[..len],
+        crate::test_state::TestState::default(),
+    )
+    .unwrap();
+    while runtime.program_counter < runtime.bytecode.len() {
+        runtime.step().unwrap();
+    }
+    assert_eq!(std::vec![0b0101_0101], runtime.state.screens);
+
+    let mut error = |source| {
+        assemble(source, &mut bytecode)
+            .map(|_| ())
+            .map_err(|e| (e.line, e.kind))
+    };
+    assert_eq!(
+        Err((2, ErrorKind::UnknownLabel("end"))),
+        error("move %0, #1\njump end")
+    );
+    assert_eq!(
+        Err((3, ErrorKind::DuplicateLabel("a"))),
+        error("a:\nmove %0, #1\na:")
+    );
+    assert_eq!(
+        Err((1, ErrorKind::InvalidOperand("#1"))),
+        error("add #1, %0, %0")
+    );
+    assert_eq!(
+        Err((
+            1,
+            ErrorKind::WrongOperandCount {
+                expected: 2,
+                found: 1
+            }
+        )),
+        error("call xy_to_buffer_index(%0) -> %1")
+    );
+    assert_eq!(Err((1, ErrorKind::UnknownMnemonic("halt"))), error("halt"));
+    assert_eq!(
+        Err((1, ErrorKind::InvalidOperand("\\q"))),
+        error(".const \"\\q\"")
+    );
+
+    // Constants, which are not numbers, survive a round trip
+    let source = ".const #-7\n.const 0xff00\n.const \"a;\\\"\\x01\"\nmove %0, $0";
+    let len = assemble(source, &mut bytecode).unwrap();
+    let mut listing = std::string::String::new();
+    super::disassemble(&bytecode[..len], None, &mut listing).unwrap();
+    assert!(
+        listing.contains("\n.const #-7 ; $0\n.const 0xff00 ; $1\n.const \"a;\\\"\\x01\" ; $2\n")
+    );
+    assert!(listing.contains("  move %0, $0\n"));
+    let mut assembled = [0u8; 256];
+    let assembled_len = assemble(&listing, &mut assembled).unwrap();
+    assert_eq!(&bytecode[..len], &assembled[..assembled_len]);
+}
@@ -0,0 +1,312 @@
+use super::{mnemonic, Operand};
+use crate::container::{Header, HEADER_SIZE, MAGIC};
+use crate::debug_info::DebugInfo;
+use crate::instructions::{Instructions, VariableRef};
+use crate::natives::{self, Signature, DEFAULT_SIGNATURES};
+use crate::pool::{Constant, ConstantPool};
+use core::fmt::{self, Write};
+
+/// Writes a listing of a bytecode container to `out`.
+///
+/// The constant pool is listed after the header. If the container has debug info, every script
+/// line and variable name, and the `on_reload:` handler, are listed as well.
+/// Passing the script as `source` adds the text of each line in a comment.
+///
+/// A container that does not validate is still listed, with the error in a comment, as that is
+/// usually when a listing is needed the most. Nothing is allocated, so finding the labels takes
+/// a pass over the code for every instruction.
+///
+/// Calls are listed with the names of `Native::DEFAULTS`, without checking them against the
+/// `.natives` hash of the container, see `disassemble_with_natives` for other
+/// natives.
+pub fn disassemble(bytecode: &[u8], source: Option<&str>, out: &mut impl Write) -> fmt::Result {
+    disassemble_with_natives(bytecode, source, &DEFAULT_SIGNATURES, out)
+}
+
+/// Like `disassemble`, listing calls with the names of `natives`. A call of a native that is not
+/// in `natives`, or with the wrong amount of arguments, is listed by its index, as `call #n(...)`.
+pub fn disassemble_with_natives(
+    bytecode: &[u8],
+    source: Option<&str>,
+    natives: &[Signature],
+    out: &mut impl Write,
+) -> fmt::Result {
+    let (code, entry_point, debug_info) = match Header::read(bytecode) {
+        Ok(header) => {
+            let code = &bytecode[header.code_range()];
+            writeln!(out, ".features {:#06x}", header.required_features)?;
+            writeln!(out, ".variables {}", header.variable_count)?;
+            writeln!(out, ".natives {:#010x}", header.natives_hash)?;
+            write!(out, ".entry ")?;
+            write_target(out, code, header.entry_point)?;
+            writeln!(out)?;
+            match ConstantPool::read(&bytecode[header.pool_range()]) {
+                Some(constants) => {
+                    for (idx, constant) in constants.iter().enumerate() {
+                        write_constant(out, constant)?;
+                        writeln!(out, " ; ${}", idx)?;
+                    }
+                }
+                None => writeln!(out, "; invalid constant pool")?,
+            }
+            let debug_info = match DebugInfo::read(&bytecode[header.len()..]) {
+                Ok(debug_info) => debug_info,
+                Err(error) => {
+                    writeln!(out, "; invalid debug info: {:?}", error)?;
+                    None
+                }
+            };
+            (code, Some(header.entry_point), debug_info)
+        }
+        Err(error) => {
+            writeln!(out, "; invalid container: {:?}", error)?;
+            if bytecode.starts_with(&MAGIC) {
+                (&bytecode[HEADER_SIZE.min(bytecode.len())..], None, None)
+            } else {
+                (bytecode, None, None)
+            }
+        }
+    };
+
+    let reload_entry = debug_info.and_then(|debug_info| debug_info.reload_entry());
+    if let Some(reload_entry) = reload_entry {
+        write!(out, ".on_reload ")?;
+        write_target(out, code, reload_entry)?;
+        writeln!(out)?;
+    }
+    let entries = [entry_point, reload_entry];
+    for (slot, ty, name) in debug_info.iter().flat_map(DebugInfo::variables) {
+        writeln!(out, ".name %{}, {}, {}", slot, name, ty.name())?;
+    }
+    let mut lines = debug_info.iter().flat_map(DebugInfo::lines).peekable();
+    let mut offset = 0;
+    while offset < code.len() {
+        if is_target(code, offset, &entries) {
+            writeln!(out, "L{:04X}:", offset)?;
+        }
+        while let Some((_, line)) = lines.next_if(|(start, _)| *start as usize <= offset) {
+            write_line(out, source, line)?;
+        }
+        write!(out, "{:04X}  ", offset)?;
+        match Instructions::decode(&code[offset..]) {
+            Ok(instruction) => {
+                write_instruction(out, code, natives, &instruction)?;
+                offset += instruction.size();
+            }
+            Err(_) => {
+                writeln!(out, ".byte {:#04x}", code[offset])?;
+                offset += 1;
+            }
+        }
+    }
+    if is_target(code, code.len(), &entries) {
+        writeln!(out, "L{:04X}:", code.len())?;
+    }
+    for (_, line) in lines {
+        write_line(out, source, line)?;
+    }
+    Ok(())
+}
+
+fn write_line(out: &mut impl Write, source: Option<&str>, line: u32) -> fmt::Result {
+    write!(out, ".line {}", line)?;
+    let text = source.and_then(|source| source.lines().nth((line as usize).checked_sub(1)?));
+    match text {
+        Some(text) => writeln!(out, " ; {}", text.trim()),
+        None => writeln!(out),
+    }
+}
+
+fn write_constant(out: &mut impl Write, constant: Constant) -> fmt::Result {
+    match constant {
+        Constant::Number(num) => write!(out, ".const #{}", num),
+        Constant::Bits(bits) => write!(out, ".const {:#x}", bits),
+        Constant::String(text) => {
+            write!(out, ".const \"")?;
+            for c in text.chars() {
+                match c {
+                    '\\' | '"' => write!(out, "\\{}", c)?,
+                    c if c.is_control() => {
+                        for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                            write!(out, "\\x{:02x}", byte)?;
+                        }
+                    }
+                    c => out.write_char(c)?,
+                }
+            }
+            write!(out, "\"")
+        }
+    }
+}
+
+fn write_instruction(
+    out: &mut impl Write,
+    code: &[u8],
+    natives: &[Signature],
+    instruction: &Instructions,
+) -> fmt::Result {
+    write!(out, "{}", mnemonic(instruction))?;
+    match *instruction {
+        Instructions::CallMethod {
+            result_variable,
+            method,
+            args,
+        } => {
+            match natives::get(natives, method.index()) {
+                Some(signature) if signature.args.len() == method.arg_len() => {
+                    write!(out, " {}(", signature.name)?
+                }
+                _ => write!(out, " #{}(", method.index())?,
+            }
+            for (idx, arg) in args[..method.arg_len()].iter().enumerate() {
+                if idx > 0 {
+                    write!(out, ", ")?;
+                }
+                write!(out, "{}", Operand(*arg))?;
+            }
+            write!(out, ")")?;
+            if result_variable != VariableRef::None {
+                write!(out, " -> {}", Operand(result_variable))?;
+            }
+        }
+        Instructions::CompareEquals { left, right }
+        | Instructions::CompareLessThan { left, right }
+        | Instructions::CompareLessOrEqualTo { left, right } => {
+            write!(out, " {}, {}", Operand(left), Operand(right))?
+        }
+        Instructions::Move { target, value } => write!(out, " %{}, {}", target, Operand(value))?,
+        Instructions::Add {
+            target,
+            left,
+            right,
+        }
+        | Instructions::Subtract {
+            target,
+            left,
+            right,
+        }
+        | Instructions::Multiply {
+            target,
+            left,
+            right,
+        }
+        | Instructions::ShiftLeft {
+            target,
+            left,
+            right,
+        } => write!(out, " %{}, {}, {}", target, Operand(left), Operand(right))?,
+        Instructions::Jump { target }
+        | Instructions::JumpIfTrue { target }
+        | Instructions::JumpIfFalse { target } => {
+            write!(out, " ")?;
+            write_target(out, code, target)?;
+        }
+    }
+    writeln!(out)
+}
+
+/// Writes a jump target as a label, or as a plain offset if no instruction starts there.
+fn write_target(out: &mut impl Write, code: &[u8], target: u16) -> fmt::Result {
+    if is_boundary(code, target as usize) {
+        write!(out, "L{:04X}", target)
+    } else {
+        write!(out, "{:#06x}", target)
+    }
+}
+
+/// Size of the instruction at `offset`, or 1 for a byte that does not decode.
+fn item_size(code: &[u8], offset: usize) -> usize {
+    Instructions::decode(&code[offset..]).map_or(1, |instruction| instruction.size())
+}
+
+fn is_boundary(code: &[u8], target: usize) -> bool {
+    let mut offset = 0;
+    while offset < target && offset < code.len() {
+        offset += item_size(code, offset);
+    }
+    offset == target
+}
+
+/// Whether `offset` needs a label, because it is jumped to or is one of the `entries`.
+fn is_target(code: &[u8], target: usize, entries: &[Option<u16>]) -> bool {
+    if entries
+        .iter()
+        .flatten()
+        .any(|entry| *entry as usize == target)
+    {
+        return true;
+    }
+    let mut offset = 0;
+    while offset < code.len() {
+        if let Ok(
+            Instructions::Jump { target: jump }
+            | Instructions::JumpIfTrue { target: jump }
+            | Instructions::JumpIfFalse { target: jump },
+        ) = Instructions::decode(&code[offset..])
+        {
+            if jump as usize == target {
+                return true;
+            }
+        }
+        offset += item_size(code, offset);
+    }
+    false
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_disassemble() {
+    let script = r#"
+buffer = get_bit_buffer(10)
+for x in 0, 10:
+    set_bit_buffer_index(buffer, x)
+set_frame_buffer(buffer)
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = crate::compiler::compile(script, &mut bytecode).unwrap();
+    let mut listing = std::string::String::new();
+    disassemble(&bytecode[..len], None, &mut listing).unwrap();
+    assert_eq!(
+        "\
+.features 0x0000
+.variables 2
+.natives 0x0918e6b2
+.entry L0000
+L0000:
+0000  call get_bit_buffer(#10) -> %0
+0005  move %1, #0
+L0008:
+0008  cmp_lt %1, #10
+000C  jump_if_false L001E
+000F  call set_bit_buffer_index(%0, %1)
+0016  add %1, %1, #1
+001B  jump L0008
+L001E:
+001E  call set_frame_buffer(%0)
+",
+        listing
+    );
+
+    // Corrupt the `move`. The listing goes out of sync until the loop body, decoding calls of
+    // natives that are listed by index, and neither jump lands on an instruction anymore.
+    bytecode[HEADER_SIZE + 0x05] = 0xFF;
+    listing.clear();
+    disassemble(&bytecode[..len], None, &mut listing).unwrap();
+    let (error, listing) = listing.split_once('\n').unwrap();
+    assert!(error.starts_with("; invalid container: ChecksumMismatch"));
+    assert_eq!(
+        "\
+0000  call get_bit_buffer(#10) -> %0
+0005  .byte 0xff
+0006  call #3() -> #0
+0009  call #12() -> %154
+000D  .byte 0x00
+000E  .byte 0x1e
+000F  call set_bit_buffer_index(%0, %1)
+0016  add %1, %1, #1
+001B  jump 0x0008
+001E  call set_frame_buffer(%0)
+",
+        listing
+    );
+}
@@ -0,0 +1,80 @@
+//! A textual form of the bytecode, for reading what ended up on a device and for writing
+//! bytecode by hand.
+//!
+//! ```text
+//! .features 0x0000
+//! .variables 2
+//! .natives 0x0918e6b2
+//! .entry L0000
+//! L0000:
+//! 0000  call get_bit_buffer(#10) -> %0
+//! 0009  move %1, #0
+//! L0010:
+//! 0010  cmp_lt %1, #10
+//! 0018  jump_if_false L002E
+//! 001B  call set_bit_buffer_index(%0, %1)
+//! 0022  add %1, %1, #1
+//! 002B  jump L0010
+//! L002E:
+//! 002E  call set_frame_buffer(%0)
+//! ```
+//!
+//! - `%n` is variable slot `n`, `#n` a number, `$n` constant `n` in the pool and `_` no value.
+//!   Floats are written with a `.` or an exponent, to tell them apart from integers.
+//! - The constant pool is written as a `.const` for every constant, in order of index: `#n` for
+//!   a number, `0x` and hex digits for bits, or a string in quotes with `\\`, `\"` and `\xNN`
+//!   escapes.
+//! - `.natives` is the hash of the natives the code was compiled against, see
+//!   `natives::manifest`.
+//! - A call names the native it calls, or gives its index as `#n` if the native is not known.
+//! - Every offset that is jumped to gets a label named after it, `L002E` for offset `0x2E`.
+//! - Every instruction starts with its offset in the code, for matching it up with a hex dump.
+//! - Bytes that do not decode into an instruction are written as `.byte 0xNN`.
+//! - Debug info is written as `.name %n, name, type` for every variable, `.line n` before the first
+//!   instruction of every script line, and `.on_reload` with the label of the `on_reload:`
+//!   handler.
+//! - Everything after a `;` is a comment.
+//!
+//! Hand-written code can use any name for its labels, and leave out the offsets.
+
+mod assemble;
+mod disassemble;
+
+pub use self::assemble::{assemble, assemble_with_natives, Error, ErrorKind, MAX_LABELS};
+pub use self::disassemble::{disassemble, disassemble_with_natives};
+
+use crate::instructions::{Instructions, VariableRef};
+use core::fmt;
+
+fn mnemonic(instruction: &Instructions) -> &'static str {
+    match instruction {
+        Instructions::CallMethod { .. } => "call",
+        Instructions::CompareEquals { .. } => "cmp_eq",
+        Instructions::CompareLessThan { .. } => "cmp_lt",
+        Instructions::CompareLessOrEqualTo { .. } => "cmp_le",
+        Instructions::Move { .. } => "move",
+        Instructions::Add { .. } => "add",
+        Instructions::Subtract { .. } => "sub",
+        Instructions::Multiply { .. } => "mul",
+        Instructions::ShiftLeft { .. } => "shl",
+        Instructions::Jump { .. } => "jump",
+        Instructions::JumpIfTrue { .. } => "jump_if_true",
+        Instructions::JumpIfFalse { .. } => "jump_if_false",
+    }
+}
+
+/// Displays an operand in the listing format.
+struct Operand(VariableRef);
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            VariableRef::None => f.write_str("_"),
+            VariableRef::Idx(idx) => write!(f, "%{}", idx),
+            VariableRef::Num(num) => write!(f, "#{}", num),
+            // `Debug` always includes a `.` or exponent, and round-trips exactly
+            VariableRef::Float(num) => write!(f, "#{:?}", num),
+            VariableRef::Const(idx) => write!(f, "${}", idx),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Prints a listing of a bytecode container.
+//!
+//! Usage: `disassemble [--source SCRIPT] [FILE]`, reading from stdin without a file. The input
+//! can be the raw bytes, or a hex dump of them like `45 53 4C 42 ...` or `0x45, 0x53, ...`.
+//! With the script the bytecode was compiled from, the listing shows the text of every line
+//! that the debug info refers to.
+
+use std::io::Read;
+
+fn main() {
+    let mut args = std::env::args().skip(1).peekable();
+    let source = if args.peek().map(String::as_str) == Some("--source") {
+        args.next();
+        let path = args.next().unwrap_or_else(|| {
+            eprintln!("--source needs the path of the script");
+            std::process::exit(1);
+        });
+        Some(read(&path))
+    } else {
+        None
+    };
+    let source = source.map(|source| String::from_utf8_lossy(&source).into_owned());
+
+    let input = match args.next() {
+        Some(path) => read(&path),
+        None => {
+            let mut input = Vec::new();
+            if let Err(e) = std::io::stdin().read_to_end(&mut input) {
+                eprintln!("Could not read stdin: {}", e);
+                std::process::exit(1);
+            }
+            input
+        }
+    };
+    let bytecode = parse_hex(&input).unwrap_or(input);
+
+    let mut listing = String::new();
+    shared::asm::disassemble(&bytecode, source.as_deref(), &mut listing)
+        .expect("Writing to a String can not fail");
+    print!("{}", listing);
+}
+
+fn read(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Could not read {}: {}", path, e);
+        std::process::exit(1);
+    })
+}
+
+/// Parses a hex dump, returning `None` if `input` is not one.
+fn parse_hex(input: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(input).ok()?;
+    let mut result = Vec::new();
+    for word in text.split(|c: char| c.is_whitespace() || c == ',') {
+        let word = word.trim_start_matches("0x");
+        if word.len() % 2 != 0 {
+            return None;
+        }
+        for idx in (0..word.len()).step_by(2) {
+            result.push(u8::from_str_radix(word.get(idx..idx + 2)?, 16).ok()?);
+        }
+    }
+    Some(result)
+}
@@ -1,12 +1,14 @@
 use super::tokens::Token;
+use super::Error;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use core::ops::Range;
-use std::println;
+use core::iter::Peekable;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Ast<'a> {
     ConstantNum(i32),
+    /// Only allowed as an argument of a native that takes a string, see `types::check`.
+    String(&'a str),
     Variable {
         name: &'a str,
     },
@@ -14,216 +16,434 @@ pub enum Ast<'a> {
         var_name: &'a str,
         rhs: Box<Ast<'a>>,
     },
+    Const {
+        name: &'a str,
+        value: Box<Ast<'a>>,
+    },
     Method {
         method_name: &'a str,
         args: Vec<Ast<'a>>,
     },
     Expression {
-        left: &'a str,
+        left: Box<Ast<'a>>,
         operation: Operation,
         right: Box<Ast<'a>>,
     },
+    Not {
+        value: Box<Ast<'a>>,
+    },
     Loop {
         statements: Vec<Ast<'a>>,
     },
+    /// Skipped when running the script, but run first when new bytecode is loaded over a running
+    /// script with `Runtime::reload`.
+    OnReload {
+        statements: Vec<Ast<'a>>,
+    },
     For {
-        range: Range<i32>,
         var_name: &'a str,
+        start: Box<Ast<'a>>,
+        end: Box<Ast<'a>>,
+        statements: Vec<Ast<'a>>,
+    },
+    If {
+        condition: Box<Ast<'a>>,
         statements: Vec<Ast<'a>>,
     },
     Block {
         statements: Vec<Ast<'a>>,
     },
+    /// Marks that the statements after it are on this line of the script, for the debug info.
+    Line(u32),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operation {
     Minus,
     Plus,
     Multiply,
+    /// Only produced by the optimizer, as a cheaper form of multiplying by a power of two.
+    ShiftLeft,
+    Equals,
+    LessThan,
+    LessOrEqualTo,
+    GreaterThan,
+    GreaterOrEqualTo,
+    And,
+    Or,
 }
 
+/// Precedence of `not`, which binds looser than comparisons but tighter than `and`.
+const NOT_PRECEDENCE: u8 = 3;
+
 impl Operation {
     pub fn from_token(token: &Token) -> Option<Self> {
         match token {
             Token::Minus => Some(Self::Minus),
             Token::Plus => Some(Self::Plus),
             Token::Multiply => Some(Self::Multiply),
+            Token::Equals => Some(Self::Equals),
+            Token::LessThan => Some(Self::LessThan),
+            Token::LessOrEqualTo => Some(Self::LessOrEqualTo),
+            Token::GreaterThan => Some(Self::GreaterThan),
+            Token::GreaterOrEqualTo => Some(Self::GreaterOrEqualTo),
+            Token::And => Some(Self::And),
+            Token::Or => Some(Self::Or),
             _ => None,
         }
     }
+
+    /// Binding strength of this operation, higher binds tighter.
+    pub fn precedence(self) -> u8 {
+        match self {
+            Self::Or => 1,
+            Self::And => 2,
+            Self::Equals
+            | Self::LessThan
+            | Self::LessOrEqualTo
+            | Self::GreaterThan
+            | Self::GreaterOrEqualTo => 4,
+            Self::Minus | Self::Plus => 5,
+            Self::Multiply | Self::ShiftLeft => 6,
+        }
+    }
+
+    /// Applies this operation to two constants. Returns `None` if the result overflows an `i32`.
+    pub fn evaluate(self, left: i32, right: i32) -> Option<i32> {
+        match self {
+            Self::Minus => left.checked_sub(right),
+            Self::Plus => left.checked_add(right),
+            Self::Multiply => left.checked_mul(right),
+            Self::ShiftLeft if (0..31).contains(&right) => left.checked_mul(1 << right),
+            Self::ShiftLeft => None,
+            Self::Equals => Some((left == right) as i32),
+            Self::LessThan => Some((left < right) as i32),
+            Self::LessOrEqualTo => Some((left <= right) as i32),
+            Self::GreaterThan => Some((left > right) as i32),
+            Self::GreaterOrEqualTo => Some((left >= right) as i32),
+            Self::And => Some((left != 0 && right != 0) as i32),
+            Self::Or => Some((left != 0 || right != 0) as i32),
+        }
+    }
 }
 
-pub fn tokens_to_ast<'a>(mut tokens: &[Token<'a>]) -> Ast<'a> {
-    let result = parse_block(&mut tokens, 0, false);
-    println!("{:#?}", result);
-    println!("{:?}", tokens);
-    assert!(tokens.is_empty());
+pub fn tokens_to_ast<'a>(tokens: impl Iterator<Item = Token<'a>>) -> Result<Ast<'a>, Error<'a>> {
+    let mut parser = Parser {
+        tokens: tokens.peekable(),
+    };
+    let statements = parser.parse_block(0)?;
+    if let Some(token) = parser.tokens.next() {
+        return Err(Error::UnexpectedToken {
+            expected: "end of script",
+            found: Some(token),
+        });
+    }
+
+    Ok(Ast::Block { statements })
+}
 
-    Ast::Block { statements: result }
+struct Parser<'a, I: Iterator<Item = Token<'a>>> {
+    tokens: Peekable<I>,
 }
 
-fn parse_block<'a>(tokens: &mut &[Token<'a>], ident: u8, nested: bool) -> Vec<Ast<'a>> {
-    let mut result = Vec::<Ast<'a>>::new();
-    let mut last_len = tokens.len() + 1;
-    while !tokens.is_empty() {
-        if cfg!(debug_assertions) && last_len == tokens.len() {
-            panic!("Infinite loop detected, aborting\n{:?}", tokens);
+impl<'a, I: Iterator<Item = Token<'a>>> Parser<'a, I> {
+    fn expect(
+        &mut self,
+        expected_token: Token<'a>,
+        expected: &'static str,
+    ) -> Result<(), Error<'a>> {
+        match self.tokens.next() {
+            Some(token) if token == expected_token => Ok(()),
+            found => Err(Error::UnexpectedToken { expected, found }),
         }
-        last_len = tokens.len();
-
-        let (first, second) = (tokens.get(0), tokens.get(1));
-        match (first, second) {
-            (Some(Token::Ident(n)), _) => {
-                if *n == ident {
-                    // same block
-                    *tokens = &tokens[1..];
-                } else if n + 1 == ident {
-                    // end block
-                    break;
-                } else {
-                    panic!("Unknown ident: {}, expected {} or {}", n, ident - 1, ident);
-                }
+    }
+
+    fn expect_word(&mut self, expected: &'static str) -> Result<&'a str, Error<'a>> {
+        match self.tokens.next() {
+            Some(Token::Word(word)) => Ok(word),
+            found => Err(Error::UnexpectedToken { expected, found }),
+        }
+    }
+
+    fn expect_end_statement(&mut self) -> Result<(), Error<'a>> {
+        match self.tokens.next() {
+            Some(Token::EndStatement) | None => Ok(()),
+            found => Err(Error::UnexpectedToken {
+                expected: "end of line",
+                found,
+            }),
+        }
+    }
+
+    fn parse_block(&mut self, ident: u8) -> Result<Vec<Ast<'a>>, Error<'a>> {
+        let mut result = Vec::new();
+        while let Some(token) = self.tokens.peek() {
+            let line_ident = match token {
+                Token::Ident(n) => *n,
+                _ => 0,
+            };
+            if line_ident < ident {
+                // end block
+                break;
+            } else if line_ident > ident {
+                return Err(Error::InvalidIndentation {
+                    expected: ident,
+                    found: line_ident,
+                });
             }
-            (Some(Token::Word(method_name)), Some(Token::BananaOpen)) => {
-                *tokens = &tokens[2..];
-                let args = parse_method_args(tokens);
-                result.push(Ast::Method { method_name, args });
-                if nested {
-                    break;
-                }
-                assert_eq!(tokens.first(), Some(&Token::EndStatement));
-                *tokens = &tokens[1..];
+            if line_ident > 0 {
+                self.tokens.next();
             }
-            (Some(Token::Number(num)), Some(Token::EndStatement))
-                if nested && result.is_empty() =>
-            {
-                result.push(Ast::ConstantNum(*num));
-                *tokens = &tokens[1..];
-                return result;
+            if let Some(&Token::Line(line)) = self.tokens.peek() {
+                self.tokens.next();
+                result.push(Ast::Line(line));
             }
-            (Some(Token::Word(var_name)), Some(Token::Assign)) => {
-                *tokens = &tokens[2..];
-                let mut rhs = parse_block(tokens, u8::max_value(), true);
-                let rhs = if rhs.len() == 1 {
-                    Box::new(rhs.pop().unwrap())
-                } else {
-                    Box::new(Ast::Block { statements: rhs })
-                };
-                result.push(Ast::Assign { var_name, rhs });
-                assert_eq!(tokens.first(), Some(&Token::EndStatement));
-                *tokens = &tokens[1..];
+            result.push(self.parse_statement(ident)?);
+        }
+
+        Ok(result)
+    }
+
+    fn parse_statement(&mut self, ident: u8) -> Result<Ast<'a>, Error<'a>> {
+        let statement = match self.tokens.next() {
+            Some(Token::Const) => {
+                let name = self.expect_word("constant name")?;
+                self.expect(Token::Assign, "`=`")?;
+                let value = Box::new(self.parse_expression(0)?);
+                Ast::Const { name, value }
             }
-            (Some(Token::Loop), Some(Token::Colon)) => {
-                *tokens = &tokens[2..];
-                assert_eq!(tokens.first(), Some(&Token::EndStatement));
-                *tokens = &tokens[1..];
-                let statements = parse_block(tokens, ident + 1, false);
-                result.push(Ast::Loop { statements });
+            Some(Token::Loop) => {
+                self.expect(Token::Colon, "`:`")?;
+                self.expect_end_statement()?;
+                let statements = self.parse_block(ident + 1)?;
+                return Ok(Ast::Loop { statements });
             }
-            (Some(Token::If), _) => {
-                *tokens = &tokens[1..];
-                let statements = parse_block(tokens, u8::max_value(), false);
-                panic!("If statements: {:?}", statements);
+            Some(Token::OnReload) if ident > 0 => return Err(Error::NestedOnReload),
+            Some(Token::OnReload) => {
+                self.expect(Token::Colon, "`:`")?;
+                self.expect_end_statement()?;
+                let statements = self.parse_block(ident + 1)?;
+                return Ok(Ast::OnReload { statements });
             }
-            (Some(Token::For), Some(Token::Word(var_name))) => {
-                *tokens = &tokens[2..];
-                assert_eq!(tokens.first(), Some(&Token::In));
-                *tokens = &tokens[1..];
-                let (start, end) = match &tokens[..5] {
-                    [Token::Number(start), Token::Comma, Token::Number(end), Token::Colon, Token::EndStatement] => {
-                        (*start, *end)
-                    }
-                    tokens => panic!(
-                        "Expected `for {} in <start>,<end>:`, got `for {} in {:?}`",
-                        var_name, var_name, tokens
-                    ),
-                };
-                *tokens = &tokens[5..];
-                let statements = parse_block(tokens, ident + 1, false);
-                result.push(Ast::For {
-                    range: start..end,
+            Some(Token::For) => {
+                let var_name = self.expect_word("loop variable")?;
+                self.expect(Token::In, "`in`")?;
+                let start = Box::new(self.parse_expression(0)?);
+                self.expect(Token::Comma, "`,`")?;
+                let end = Box::new(self.parse_expression(0)?);
+                self.expect(Token::Colon, "`:`")?;
+                self.expect_end_statement()?;
+                let statements = self.parse_block(ident + 1)?;
+                return Ok(Ast::For {
                     var_name,
+                    start,
+                    end,
                     statements,
                 });
             }
-            (Some(Token::For), stmt) => {
-                panic!("Malformed for statement, expected word, got {:?}", stmt);
-            }
-            (Some(Token::Word(var)), Some(t)) if Operation::from_token(t).is_some() => {
-                let op = Operation::from_token(t).unwrap();
-                *tokens = &tokens[2..];
-                let (token_length, rhs) = match &tokens {
-                    &[Token::Number(n)] => (1, Ast::ConstantNum(*n)),
-                    &[Token::Word(word)] => (1, Ast::Variable { name: word }),
-                    t if t.len() > 1 => (
-                        0,
-                        Ast::Block {
-                            statements: parse_block(tokens, u8::max_value(), true),
-                        },
-                    ),
-                    _ => panic!("Unterminated statement: {:?} {:?}", var, op),
-                };
-                result.push(Ast::Expression {
-                    left: var,
-                    operation: op,
-                    right: Box::new(rhs),
+            Some(Token::If) => {
+                let condition = Box::new(self.parse_expression(0)?);
+                self.expect(Token::Colon, "`:`")?;
+                self.expect_end_statement()?;
+                let statements = self.parse_block(ident + 1)?;
+                return Ok(Ast::If {
+                    condition,
+                    statements,
                 });
-                *tokens = &tokens[token_length..];
             }
+            Some(Token::Word(var_name)) => match self.tokens.next() {
+                Some(Token::Assign) => Ast::Assign {
+                    var_name,
+                    rhs: Box::new(self.parse_expression(0)?),
+                },
+                Some(token @ (Token::PlusAssign | Token::MinusAssign)) => {
+                    let operation = if token == Token::PlusAssign {
+                        Operation::Plus
+                    } else {
+                        Operation::Minus
+                    };
+                    let right = Box::new(self.parse_expression(0)?);
+                    Ast::Assign {
+                        var_name,
+                        rhs: Box::new(Ast::Expression {
+                            left: Box::new(Ast::Variable { name: var_name }),
+                            operation,
+                            right,
+                        }),
+                    }
+                }
+                Some(Token::BananaOpen) => self.parse_method(var_name)?,
+                found => {
+                    return Err(Error::UnexpectedToken {
+                        expected: "assignment or method call",
+                        found,
+                    })
+                }
+            },
+            found => {
+                return Err(Error::UnexpectedToken {
+                    expected: "statement",
+                    found,
+                })
+            }
+        };
+        self.expect_end_statement()?;
+        Ok(statement)
+    }
 
-            (first, second) => {
-                println!("{:#?}", result);
-                panic!("Unknown combination: {:?} + {:?}", first, second);
+    /// Parses the arguments of a method call, the opening `(` has already been consumed.
+    fn parse_method(&mut self, method_name: &'a str) -> Result<Ast<'a>, Error<'a>> {
+        let mut args = Vec::new();
+        if self.tokens.peek() == Some(&Token::BananaClose) {
+            self.tokens.next();
+            return Ok(Ast::Method { method_name, args });
+        }
+        loop {
+            args.push(self.parse_expression(0)?);
+            match self.tokens.next() {
+                Some(Token::Comma) => {}
+                Some(Token::BananaClose) => break,
+                found => {
+                    return Err(Error::UnexpectedToken {
+                        expected: "`,` or `)`",
+                        found,
+                    })
+                }
             }
         }
+        Ok(Ast::Method { method_name, args })
     }
 
-    result
-}
-
-fn parse_method_args<'a>(tokens: &mut &[Token<'a>]) -> Vec<Ast<'a>> {
-    let mut args = Vec::new();
-
-    while !tokens.is_empty() {
-        let next_indicator_index = tokens
-            .iter()
-            .position(|t| matches!(t, Token::BananaClose | Token::BananaOpen | Token::Comma))
-            .unwrap();
-        let next_indicator = &tokens[next_indicator_index];
-        if let Token::BananaOpen = next_indicator {
-            // nested method call
-            assert_eq!(1, next_indicator_index);
-            let method_name = match &tokens[0] {
-                Token::Word(name) => name,
-                x => panic!("Expected method name, got {:?}", x),
-            };
-            *tokens = &tokens[2..];
-            let nested_args = parse_method_args(tokens);
-            args.push(Ast::Method {
-                method_name,
-                args: nested_args,
-            });
-        } else if next_indicator_index == 0 {
-            *tokens = &tokens[1..];
-            break;
-        } else if next_indicator_index == 1 {
-            if let Token::Number(num) = tokens[0] {
-                args.push(Ast::ConstantNum(num));
-            } else if let Token::Word(name) = tokens[0] {
-                args.push(Ast::Variable { name });
+    /// Parses an expression, only consuming operations that bind at least as tight as
+    /// `min_precedence`.
+    fn parse_expression(&mut self, min_precedence: u8) -> Result<Ast<'a>, Error<'a>> {
+        let mut left =
+            if self.tokens.peek() == Some(&Token::Not) && min_precedence <= NOT_PRECEDENCE {
+                self.tokens.next();
+                Ast::Not {
+                    value: Box::new(self.parse_expression(NOT_PRECEDENCE + 1)?),
+                }
             } else {
-                panic!("Unexpected function argument: {:?}", tokens[0]);
+                self.parse_operand()?
+            };
+        while let Some(operation) = self.tokens.peek().and_then(Operation::from_token) {
+            let precedence = operation.precedence();
+            if precedence < min_precedence {
+                break;
             }
-        } else {
-            let mut nested_tokens = &tokens[..next_indicator_index];
-            let statements = parse_block(&mut nested_tokens, u8::max_value(), true);
-            args.push(Ast::Block { statements })
+            self.tokens.next();
+            let right = self.parse_expression(precedence + 1)?;
+            left = Ast::Expression {
+                left: Box::new(left),
+                operation,
+                right: Box::new(right),
+            };
         }
-        *tokens = &tokens[next_indicator_index + 1..];
-        if let Token::BananaClose = next_indicator {
-            break;
+        Ok(left)
+    }
+
+    fn parse_operand(&mut self) -> Result<Ast<'a>, Error<'a>> {
+        match self.tokens.next() {
+            Some(Token::Number(num)) => Ok(Ast::ConstantNum(num)),
+            Some(Token::String(text)) => Ok(Ast::String(text)),
+            Some(Token::Word(word)) if word.starts_with('"') => Err(Error::UnterminatedString),
+            Some(Token::Minus) => Ok(Ast::Expression {
+                left: Box::new(Ast::ConstantNum(0)),
+                operation: Operation::Minus,
+                right: Box::new(self.parse_operand()?),
+            }),
+            Some(Token::BananaOpen) => {
+                let result = self.parse_expression(0)?;
+                self.expect(Token::BananaClose, "`)`")?;
+                Ok(result)
+            }
+            Some(Token::Word(word)) if word.bytes().all(|b| b.is_ascii_digit()) => {
+                // `tokenize` only leaves numbers as words if they do not fit in an i32
+                Err(Error::Overflow)
+            }
+            Some(Token::Word(name)) => {
+                if self.tokens.peek() == Some(&Token::BananaOpen) {
+                    self.tokens.next();
+                    self.parse_method(name)
+                } else {
+                    Ok(Ast::Variable { name })
+                }
+            }
+            found => Err(Error::UnexpectedToken {
+                expected: "expression",
+                found,
+            }),
         }
     }
-    args
+}
+
+/// Removes every `Ast::Line`, for comparing scripts that only differ in layout.
+#[cfg(test)]
+pub fn strip_lines(ast: &mut Ast) {
+    if let Ast::Block { statements }
+    | Ast::Loop { statements }
+    | Ast::OnReload { statements }
+    | Ast::If { statements, .. }
+    | Ast::For { statements, .. } = ast
+    {
+        statements.retain(|statement| !matches!(statement, Ast::Line(_)));
+        statements.iter_mut().for_each(strip_lines);
+    }
+}
+
+#[test]
+fn test_parse_precedence() {
+    use super::tokens::tokenize;
+
+    let ast = tokens_to_ast(tokenize("if not a + 1 * b == 3 and c:\n    c -= 1")).unwrap();
+    let variable = |name| Box::new(Ast::Variable { name });
+    let expression = |left, operation, right| {
+        Box::new(Ast::Expression {
+            left,
+            operation,
+            right,
+        })
+    };
+    let condition = expression(
+        Box::new(Ast::Not {
+            value: expression(
+                expression(
+                    variable("a"),
+                    Operation::Plus,
+                    expression(
+                        Box::new(Ast::ConstantNum(1)),
+                        Operation::Multiply,
+                        variable("b"),
+                    ),
+                ),
+                Operation::Equals,
+                Box::new(Ast::ConstantNum(3)),
+            ),
+        }),
+        Operation::And,
+        variable("c"),
+    );
+    let statements = vec![
+        Ast::Line(2),
+        Ast::Assign {
+            var_name: "c",
+            rhs: expression(
+                variable("c"),
+                Operation::Minus,
+                Box::new(Ast::ConstantNum(1)),
+            ),
+        },
+    ];
+    assert_eq!(
+        Ast::Block {
+            statements: vec![
+                Ast::Line(1),
+                Ast::If {
+                    condition,
+                    statements
+                }
+            ]
+        },
+        ast
+    );
 }
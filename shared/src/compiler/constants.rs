@@ -0,0 +1,151 @@
+use super::ast::Ast;
+use super::Error;
+use alloc::vec::Vec;
+
+/// Evaluates everything in the script that is known at compile time.
+///
+/// `const` declarations are removed from the tree and every use of them is replaced by their
+/// value. Expressions whose operands are all constant are replaced by their result, so
+/// `get_bit_buffer(WIDTH * HEIGHT)` ends up as a single number. A constant is visible from its
+/// declaration until the end of the block it is declared in.
+pub fn fold<'a>(ast: &mut Ast<'a>) -> Result<(), Error<'a>> {
+    fold_ast(ast, &mut Vec::new())
+}
+
+fn fold_block<'a>(
+    statements: &mut Vec<Ast<'a>>,
+    constants: &mut Vec<(&'a str, i32)>,
+) -> Result<(), Error<'a>> {
+    let scope = constants.len();
+    let mut result = Ok(());
+    statements.retain_mut(|statement| {
+        if result.is_err() {
+            return true;
+        }
+        if let Ast::Const { name, value } = statement {
+            result = declare(name, value, constants);
+            false
+        } else {
+            result = fold_ast(statement, constants);
+            true
+        }
+    });
+    constants.truncate(scope);
+    result
+}
+
+fn declare<'a>(
+    name: &'a str,
+    value: &mut Ast<'a>,
+    constants: &mut Vec<(&'a str, i32)>,
+) -> Result<(), Error<'a>> {
+    if lookup(name, constants).is_some() {
+        return Err(Error::ConstantRedefined(name));
+    }
+    fold_ast(value, constants)?;
+    match value {
+        Ast::ConstantNum(value) => {
+            constants.push((name, *value));
+            Ok(())
+        }
+        _ => Err(Error::NotConstant(name)),
+    }
+}
+
+fn lookup(name: &str, constants: &[(&str, i32)]) -> Option<i32> {
+    constants
+        .iter()
+        .rev()
+        .find(|(constant, _)| *constant == name)
+        .map(|(_, value)| *value)
+}
+
+fn fold_ast<'a>(ast: &mut Ast<'a>, constants: &mut Vec<(&'a str, i32)>) -> Result<(), Error<'a>> {
+    match ast {
+        Ast::ConstantNum(_) | Ast::String(_) | Ast::Line(_) => {}
+        Ast::Variable { name } => {
+            if let Some(value) = lookup(name, constants) {
+                *ast = Ast::ConstantNum(value);
+            }
+        }
+        Ast::Assign { var_name, rhs } => {
+            if lookup(var_name, constants).is_some() {
+                return Err(Error::AssignToConstant(var_name));
+            }
+            fold_ast(rhs, constants)?;
+        }
+        Ast::Const { name, value } => declare(name, value, constants)?,
+        Ast::Method { args, .. } => {
+            for arg in args {
+                fold_ast(arg, constants)?;
+            }
+        }
+        Ast::Expression {
+            left,
+            operation,
+            right,
+        } => {
+            fold_ast(left, constants)?;
+            fold_ast(right, constants)?;
+            if let (Ast::ConstantNum(left), Ast::ConstantNum(right)) = (&**left, &**right) {
+                let value = operation.evaluate(*left, *right).ok_or(Error::Overflow)?;
+                *ast = Ast::ConstantNum(value);
+            }
+        }
+        Ast::Not { value } => {
+            fold_ast(value, constants)?;
+            if let Ast::ConstantNum(value) = **value {
+                *ast = Ast::ConstantNum((value == 0) as i32);
+            }
+        }
+        Ast::Loop { statements } | Ast::OnReload { statements } | Ast::Block { statements } => {
+            fold_block(statements, constants)?
+        }
+        Ast::For {
+            var_name,
+            start,
+            end,
+            statements,
+        } => {
+            if lookup(var_name, constants).is_some() {
+                return Err(Error::AssignToConstant(var_name));
+            }
+            fold_ast(start, constants)?;
+            fold_ast(end, constants)?;
+            fold_block(statements, constants)?;
+        }
+        Ast::If {
+            condition,
+            statements,
+        } => {
+            fold_ast(condition, constants)?;
+            fold_block(statements, constants)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_fold_constants() {
+    use super::{ast::tokens_to_ast, tokens::tokenize};
+
+    let script = r#"
+const WIDTH = 10
+const HEIGHT = WIDTH - 2 * 3
+buffer = get_bit_buffer(WIDTH*HEIGHT)
+for x in 0,WIDTH - 1:
+    set_bit_buffer_index(buffer, x + HEIGHT * 2)
+"#;
+    let mut ast = tokens_to_ast(tokenize(script)).unwrap();
+    fold(&mut ast).unwrap();
+    super::ast::strip_lines(&mut ast);
+    let mut expected = tokens_to_ast(tokenize(
+        "buffer = get_bit_buffer(40)\nfor x in 0,9:\n    set_bit_buffer_index(buffer, x + 8)",
+    ))
+    .unwrap();
+    super::ast::strip_lines(&mut expected);
+    assert_eq!(expected, ast);
+
+    let mut ast = tokens_to_ast(tokenize("const BIG = 65536\nx = BIG * BIG")).unwrap();
+    assert_eq!(Err(Error::Overflow), fold(&mut ast));
+}
@@ -0,0 +1,356 @@
+use super::*;
+use crate::compiler::Error;
+use crate::instructions::{Instructions, VariableRef};
+use crate::pool::Constant;
+use crate::runtime::VARIABLE_COUNT;
+
+pub struct Emitted<'a> {
+    /// Amount of bytes written
+    pub len: usize,
+    /// Amount of variable slots the bytecode uses
+    pub variable_count: usize,
+    /// Offset of the first instruction of each script line, in order of the offsets
+    pub lines: Vec<(usize, u32)>,
+    /// The variable index each script variable ended up in
+    pub variables: Vec<(u8, &'a str)>,
+    /// Numbers in the constant pool, in order of their index
+    pub constants: Vec<i32>,
+    /// Strings in the constant pool, in order of their index after the numbers
+    pub strings: Vec<&'a str>,
+    /// Offset of the `on_reload:` handler
+    pub reload_entry: Option<usize>,
+}
+
+/// Writes the program as bytecode into `buffer`.
+///
+/// Blocks that can not be reached from the entry point or the `on_reload:` handler are left
+/// out, and jumps to the block that directly follows are elided. Numbers that are used often
+/// enough to make up for the space they take in the constant pool are moved there, followed by
+/// every string.
+pub fn emit<'a>(program: &Program<'a>, buffer: &mut [u8]) -> Result<Emitted<'a>, Error<'static>> {
+    let layout = reachable_layout(program);
+    let (slots, variable_count) = allocate_slots(program, &layout)?;
+    let constants = pool_constants(program, &slots, &layout);
+
+    // Every jump has the same size, so the first pass can calculate where each block starts
+    // without knowing the jump targets.
+    let mut offsets = alloc::vec![0usize; program.blocks.len()];
+    let mut end = 0;
+    for (idx, block) in layout.iter().enumerate() {
+        offsets[block.0] = end;
+        let next = layout.get(idx + 1).copied();
+        for instruction in block_instructions(program, &slots, &constants, *block, next, |_| 0) {
+            end += instruction.size();
+        }
+    }
+    if end > u16::MAX as usize {
+        return Err(Error::ProgramTooLarge);
+    }
+    if end > buffer.len() {
+        return Err(Error::BufferTooSmall);
+    }
+
+    let target = |block: Option<BlockId>| block.map_or(end, |block| offsets[block.0]) as u16;
+    let mut len = 0;
+    let mut lines = Vec::new();
+    for (idx, block) in layout.iter().enumerate() {
+        let next = layout.get(idx + 1).copied();
+        let mut block_lines = program.blocks[block.0].lines.iter().peekable();
+        for (idx, instruction) in
+            block_instructions(program, &slots, &constants, *block, next, target)
+                .iter()
+                .enumerate()
+        {
+            while let Some((_, line)) = block_lines.next_if(|(start, _)| *start <= idx) {
+                lines.push((len, *line));
+            }
+            instruction.write(&mut buffer[len..]);
+            len += instruction.size();
+        }
+        // lines without any instructions left, like an elided jump
+        lines.extend(block_lines.map(|(_, line)| (len, *line)));
+    }
+    let variables = program
+        .slots
+        .iter()
+        .zip(&slots)
+        .filter_map(|(kind, index)| match kind {
+            SlotKind::Variable(name) => Some((*index, *name)),
+            _ => None,
+        })
+        .collect();
+    Ok(Emitted {
+        len,
+        variable_count,
+        lines,
+        variables,
+        constants,
+        strings: program.strings.clone(),
+        reload_entry: program.reload_entry.map(|block| offsets[block.0]),
+    })
+}
+
+/// Picks the numbers to put in the constant pool, most bytes saved first.
+fn pool_constants(program: &Program, slots: &[u8], layout: &[BlockId]) -> Vec<i32> {
+    let mut uses: Vec<(i32, usize)> = Vec::new();
+    for (idx, block) in layout.iter().enumerate() {
+        let next = layout.get(idx + 1).copied();
+        for instruction in block_instructions(program, slots, &[], *block, next, |_| 0) {
+            for operand in operands(&instruction) {
+                if let VariableRef::Num(num) = operand {
+                    match uses.iter_mut().find(|(constant, _)| *constant == num) {
+                        Some((_, count)) => *count += 1,
+                        None => uses.push((num, 1)),
+                    }
+                }
+            }
+        }
+    }
+
+    // every constant also has an offset in the pool
+    let cost = 2 + Constant::Number(0).size();
+    let mut saved: Vec<(i32, usize)> = uses
+        .into_iter()
+        .map(|(num, count)| {
+            let saved_per_use = VariableRef::Num(num)
+                .size()
+                .saturating_sub(VariableRef::Const(0).size());
+            (num, (count * saved_per_use).saturating_sub(cost))
+        })
+        .filter(|(_, saved)| *saved > 0)
+        .collect();
+    saved.sort_by_key(|(num, saved)| (core::cmp::Reverse(*saved), *num));
+    saved.truncate(u8::MAX as usize - program.strings.len());
+    // the length of the pool and the amount of constants in it
+    if saved.iter().map(|(_, saved)| saved).sum::<usize>() <= 3 {
+        return Vec::new();
+    }
+    saved.into_iter().map(|(num, _)| num).collect()
+}
+
+/// Every operand an instruction reads.
+fn operands(instruction: &Instructions) -> ArrayVec<[VariableRef; MAX_ARGS]> {
+    let mut result = ArrayVec::new();
+    match instruction {
+        Instructions::CallMethod { method, args, .. } => {
+            result.extend(args.iter().take(method.arg_len()).copied())
+        }
+        Instructions::CompareEquals { left, right }
+        | Instructions::CompareLessThan { left, right }
+        | Instructions::CompareLessOrEqualTo { left, right }
+        | Instructions::Add { left, right, .. }
+        | Instructions::Subtract { left, right, .. }
+        | Instructions::Multiply { left, right, .. }
+        | Instructions::ShiftLeft { left, right, .. } => {
+            result.push(*left);
+            result.push(*right);
+        }
+        Instructions::Move { value, .. } => result.push(*value),
+        Instructions::Jump { .. }
+        | Instructions::JumpIfTrue { .. }
+        | Instructions::JumpIfFalse { .. } => {}
+    }
+    result
+}
+
+/// The blocks in `program.layout` that can be reached from the entry point or the `on_reload:`
+/// handler.
+fn reachable_layout(program: &Program) -> Vec<BlockId> {
+    let mut reachable = alloc::vec![false; program.blocks.len()];
+    let mut todo: Vec<BlockId> = program.layout.first().copied().into_iter().collect();
+    todo.extend(program.reload_entry);
+    while let Some(block) = todo.pop() {
+        if !core::mem::replace(&mut reachable[block.0], true) {
+            todo.extend(program.blocks[block.0].terminator.successors());
+        }
+    }
+    program
+        .layout
+        .iter()
+        .copied()
+        .filter(|block| reachable[block.0])
+        .collect()
+}
+
+/// Maps every slot onto a runtime variable index, returning the indices and how many are used.
+///
+/// Variables and hidden slots each get their own index. Temporaries only live within a single
+/// statement, so their indices are reused once they are no longer needed.
+fn allocate_slots(
+    program: &Program,
+    layout: &[BlockId],
+) -> Result<(Vec<u8>, usize), Error<'static>> {
+    let mut live_ranges = alloc::vec![(usize::MAX, 0); program.slots.len()];
+    let mut position = 0;
+    for block in layout {
+        let block = &program.blocks[block.0];
+        let instruction_slots = block.instructions.iter().map(Instruction::slots);
+        for slots in instruction_slots.chain(core::iter::once(block.terminator.slots())) {
+            for slot in slots {
+                let (start, end) = &mut live_ranges[slot.0];
+                *start = (*start).min(position);
+                *end = position;
+            }
+            position += 1;
+        }
+    }
+
+    let mut result = alloc::vec![0; program.slots.len()];
+    let mut next_index = 0;
+    for (idx, kind) in program.slots.iter().enumerate() {
+        if *kind != SlotKind::Temporary {
+            result[idx] = next_index;
+            next_index += 1;
+        }
+    }
+
+    let mut temporaries: Vec<usize> = (0..program.slots.len())
+        .filter(|idx| {
+            program.slots[*idx] == SlotKind::Temporary && live_ranges[*idx].0 != usize::MAX
+        })
+        .collect();
+    temporaries.sort_by_key(|idx| live_ranges[*idx].0);
+    let mut active: Vec<(usize, usize)> = Vec::new();
+    let mut free = Vec::new();
+    for temporary in temporaries {
+        let (start, end) = live_ranges[temporary];
+        active.retain(|(active_end, index)| {
+            if *active_end < start {
+                free.push(*index);
+            }
+            *active_end >= start
+        });
+        let index = free.pop().unwrap_or_else(|| {
+            next_index += 1;
+            next_index - 1
+        });
+        result[temporary] = index;
+        active.push((end, index));
+    }
+
+    if next_index > VARIABLE_COUNT {
+        return Err(Error::TooManyVariables);
+    }
+    let result = result.into_iter().map(|index| index as u8).collect();
+    Ok((result, next_index))
+}
+
+/// Translates a single block into instructions. `target` gives the offset of a block, or of the
+/// end of the program for `None`. Numbers in `constants` are referred to by their index, and
+/// strings by their index after them.
+fn block_instructions(
+    program: &Program,
+    slots: &[u8],
+    constants: &[i32],
+    block: BlockId,
+    next: Option<BlockId>,
+    target: impl Fn(Option<BlockId>) -> u16,
+) -> Vec<Instructions> {
+    let variable = |value: Value| match value {
+        Value::Slot(slot) => VariableRef::Idx(slots[slot.0]),
+        Value::Constant(num) => match constants.iter().position(|constant| *constant == num) {
+            Some(idx) => VariableRef::Const(idx as u8),
+            None => VariableRef::Num(num),
+        },
+        Value::String(idx) => VariableRef::Const((constants.len() + idx) as u8),
+    };
+    let block = &program.blocks[block.0];
+    let mut result = Vec::with_capacity(block.instructions.len() + 2);
+    for instruction in &block.instructions {
+        result.push(match instruction {
+            Instruction::Copy { target, value } => Instructions::Move {
+                target: slots[target.0],
+                value: variable(*value),
+            },
+            Instruction::Binary {
+                target,
+                operation,
+                left,
+                right,
+            } => {
+                let (target, left, right) = (slots[target.0], variable(*left), variable(*right));
+                match operation {
+                    BinaryOperation::Add => Instructions::Add {
+                        target,
+                        left,
+                        right,
+                    },
+                    BinaryOperation::Subtract => Instructions::Subtract {
+                        target,
+                        left,
+                        right,
+                    },
+                    BinaryOperation::Multiply => Instructions::Multiply {
+                        target,
+                        left,
+                        right,
+                    },
+                    BinaryOperation::ShiftLeft => Instructions::ShiftLeft {
+                        target,
+                        left,
+                        right,
+                    },
+                }
+            }
+            Instruction::Call {
+                target,
+                method,
+                args,
+            } => {
+                let mut arg_refs = [VariableRef::None; MAX_ARGS];
+                for (arg_ref, arg) in arg_refs.iter_mut().zip(args) {
+                    *arg_ref = variable(*arg);
+                }
+                Instructions::CallMethod {
+                    result_variable: match target {
+                        Some(target) => VariableRef::Idx(slots[target.0]),
+                        None => VariableRef::None,
+                    },
+                    method: *method,
+                    args: arg_refs,
+                }
+            }
+        });
+    }
+
+    match block.terminator {
+        Terminator::Jump(block) if Some(block) == next => {}
+        Terminator::Jump(block) => result.push(Instructions::Jump {
+            target: target(Some(block)),
+        }),
+        Terminator::Branch {
+            comparison,
+            left,
+            right,
+            if_true,
+            if_false,
+        } => {
+            let (left, right) = (variable(left), variable(right));
+            result.push(match comparison {
+                Comparison::Equals => Instructions::CompareEquals { left, right },
+                Comparison::LessThan => Instructions::CompareLessThan { left, right },
+                Comparison::LessOrEqualTo => Instructions::CompareLessOrEqualTo { left, right },
+            });
+            if Some(if_true) == next {
+                result.push(Instructions::JumpIfFalse {
+                    target: target(Some(if_false)),
+                });
+            } else {
+                result.push(Instructions::JumpIfTrue {
+                    target: target(Some(if_true)),
+                });
+                if Some(if_false) != next {
+                    result.push(Instructions::Jump {
+                        target: target(Some(if_false)),
+                    });
+                }
+            }
+        }
+        // Jumping to the end of the bytecode stops the runtime
+        Terminator::Return if next.is_some() => result.push(Instructions::Jump {
+            target: target(None),
+        }),
+        Terminator::Return => {}
+    }
+    result
+}
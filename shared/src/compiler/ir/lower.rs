@@ -0,0 +1,396 @@
+use super::*;
+use crate::compiler::ast::{Ast, Operation};
+use crate::compiler::Error;
+use crate::natives::{self, Signature};
+
+/// Lowers a script into basic blocks, resolving calls to the `natives` by name.
+pub fn lower<'a>(ast: &Ast<'a>, natives: &[Signature]) -> Result<Program<'a>, Error<'a>> {
+    let mut lowering = Lowering {
+        natives,
+        program: Program::default(),
+        current: BlockId(0),
+        line: 0,
+    };
+    let entry = lowering.new_block();
+    lowering.start_block(entry);
+    lowering.lower_statement(ast)?;
+    Ok(lowering.program)
+}
+
+struct Lowering<'a, 'n> {
+    natives: &'n [Signature<'n>],
+    program: Program<'a>,
+    current: BlockId,
+    /// The script line of the statement being lowered
+    line: u32,
+}
+
+impl<'a> Lowering<'a, '_> {
+    fn new_block(&mut self) -> BlockId {
+        self.program.blocks.push(Block {
+            instructions: Vec::new(),
+            terminator: Terminator::Return,
+            lines: Vec::new(),
+        });
+        BlockId(self.program.blocks.len() - 1)
+    }
+
+    /// Continues lowering in `block`, placing it after the previously started block.
+    fn start_block(&mut self, block: BlockId) {
+        self.current = block;
+        self.program.layout.push(block);
+    }
+
+    fn push(&mut self, instruction: Instruction) {
+        self.program.blocks[self.current.0]
+            .instructions
+            .push(instruction);
+    }
+
+    /// Marks the instructions pushed after this as coming from `line`.
+    fn mark_line(&mut self, line: u32) {
+        self.line = line;
+        let block = &mut self.program.blocks[self.current.0];
+        block.lines.push((block.instructions.len(), line));
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        self.program.blocks[self.current.0].terminator = terminator;
+    }
+
+    fn new_slot(&mut self, kind: SlotKind<'a>) -> Slot {
+        self.program.slots.push(kind);
+        Slot(self.program.slots.len() - 1)
+    }
+
+    fn variable(&mut self, name: &'a str) -> Slot {
+        match self
+            .program
+            .slots
+            .iter()
+            .position(|slot| *slot == SlotKind::Variable(name))
+        {
+            Some(idx) => Slot(idx),
+            None => self.new_slot(SlotKind::Variable(name)),
+        }
+    }
+
+    /// The index of a string literal in `Program::strings`, adding it if it is new.
+    fn string(&mut self, text: &'a str) -> Result<usize, Error<'a>> {
+        if text.len() > u8::MAX as usize {
+            return Err(Error::StringTooLong(text));
+        }
+        let strings = &mut self.program.strings;
+        if let Some(idx) = strings.iter().position(|other| *other == text) {
+            return Ok(idx);
+        }
+        // the constant pool holds at most 255 constants
+        if strings.len() == u8::MAX as usize {
+            return Err(Error::ProgramTooLarge);
+        }
+        strings.push(text);
+        Ok(strings.len() - 1)
+    }
+
+    fn lower_statement(&mut self, ast: &Ast<'a>) -> Result<(), Error<'a>> {
+        match ast {
+            Ast::Assign { var_name, rhs } => {
+                let target = self.variable(var_name);
+                self.lower_into(rhs, target)?;
+            }
+            Ast::Method { method_name, args } => self.lower_call(method_name, args, None)?,
+            Ast::Loop { statements } => {
+                let header = self.new_block();
+                self.terminate(Terminator::Jump(header));
+                self.start_block(header);
+                for statement in statements {
+                    self.lower_statement(statement)?;
+                }
+                self.terminate(Terminator::Jump(header));
+                // anything after the loop is unreachable, but still needs a block to go in
+                let after = self.new_block();
+                self.start_block(after);
+            }
+            Ast::OnReload { statements } => {
+                if self.program.reload_entry.is_some() {
+                    return Err(Error::OnReloadRedefined);
+                }
+                // the handler is only entered by a reload, so running the script skips it
+                let (body, after) = (self.new_block(), self.new_block());
+                self.terminate(Terminator::Jump(after));
+                self.start_block(body);
+                self.program.reload_entry = Some(body);
+                for statement in statements {
+                    self.lower_statement(statement)?;
+                }
+                self.terminate(Terminator::Jump(after));
+                self.start_block(after);
+            }
+            Ast::For {
+                var_name,
+                start,
+                end,
+                statements,
+            } => {
+                let variable = self.variable(var_name);
+                self.lower_into(start, variable)?;
+                let end = match **end {
+                    Ast::ConstantNum(end) => Value::Constant(end),
+                    ref end => {
+                        // the end of the range is only evaluated once
+                        let slot = self.new_slot(SlotKind::Hidden);
+                        self.lower_into(end, slot)?;
+                        Value::Slot(slot)
+                    }
+                };
+                let line = self.line;
+                let (header, body, exit) = (self.new_block(), self.new_block(), self.new_block());
+                self.terminate(Terminator::Jump(header));
+                self.start_block(header);
+                self.terminate(Terminator::Branch {
+                    comparison: Comparison::LessThan,
+                    left: Value::Slot(variable),
+                    right: end,
+                    if_true: body,
+                    if_false: exit,
+                });
+                self.start_block(body);
+                for statement in statements {
+                    self.lower_statement(statement)?;
+                }
+                self.mark_line(line);
+                self.push(Instruction::Binary {
+                    target: variable,
+                    operation: BinaryOperation::Add,
+                    left: Value::Slot(variable),
+                    right: Value::Constant(1),
+                });
+                self.terminate(Terminator::Jump(header));
+                self.start_block(exit);
+            }
+            Ast::If {
+                condition,
+                statements,
+            } => {
+                let (body, exit) = (self.new_block(), self.new_block());
+                self.lower_condition(condition, body, exit)?;
+                self.start_block(body);
+                for statement in statements {
+                    self.lower_statement(statement)?;
+                }
+                self.terminate(Terminator::Jump(exit));
+                self.start_block(exit);
+            }
+            Ast::Block { statements } => {
+                for statement in statements {
+                    self.lower_statement(statement)?;
+                }
+            }
+            Ast::Line(line) => self.mark_line(*line),
+            // constants are resolved by `constants::fold`, and the parser does not allow other
+            // expressions as statements
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn lower_call(
+        &mut self,
+        method_name: &'a str,
+        args: &[Ast<'a>],
+        target: Option<Slot>,
+    ) -> Result<(), Error<'a>> {
+        let (index, signature) =
+            natives::find(self.natives, method_name).ok_or(Error::UnknownMethod(method_name))?;
+        if args.len() != signature.args.len() {
+            return Err(Error::WrongArgumentCount {
+                method: method_name,
+                expected: signature.args.len(),
+                found: args.len(),
+            });
+        }
+        if target.is_some() && signature.result.is_none() {
+            return Err(Error::NoReturnValue(method_name));
+        }
+        // `find` only returns natives that fit in a `MethodRef`
+        let method = MethodRef::new(index, args.len()).ok_or(Error::UnknownMethod(method_name))?;
+        let mut values = ArrayVec::new();
+        for arg in args {
+            values.push(self.lower_value(arg)?);
+        }
+        self.push(Instruction::Call {
+            target,
+            method,
+            args: values,
+        });
+        Ok(())
+    }
+
+    /// Lowers an expression, returning where its result can be found.
+    fn lower_value(&mut self, ast: &Ast<'a>) -> Result<Value, Error<'a>> {
+        match ast {
+            Ast::ConstantNum(num) => Ok(Value::Constant(*num)),
+            Ast::String(text) => self.string(text).map(Value::String),
+            Ast::Variable { name } => Ok(Value::Slot(self.variable(name))),
+            ast => {
+                let slot = self.new_slot(SlotKind::Temporary);
+                self.lower_into(ast, slot)?;
+                Ok(Value::Slot(slot))
+            }
+        }
+    }
+
+    /// Lowers an expression, storing its result in `target`.
+    fn lower_into(&mut self, ast: &Ast<'a>, target: Slot) -> Result<(), Error<'a>> {
+        let operation = match ast {
+            Ast::ConstantNum(_) | Ast::Variable { .. } => {
+                let value = self.lower_value(ast)?;
+                self.push(Instruction::Copy { target, value });
+                return Ok(());
+            }
+            Ast::Method { method_name, args } => {
+                return self.lower_call(method_name, args, Some(target))
+            }
+            Ast::Expression {
+                operation: Operation::Plus,
+                ..
+            } => BinaryOperation::Add,
+            Ast::Expression {
+                operation: Operation::Minus,
+                ..
+            } => BinaryOperation::Subtract,
+            Ast::Expression {
+                operation: Operation::Multiply,
+                ..
+            } => BinaryOperation::Multiply,
+            Ast::Expression {
+                operation: Operation::ShiftLeft,
+                ..
+            } => BinaryOperation::ShiftLeft,
+            _ => {
+                // comparisons and logic operations only exist as jumps, so turn the outcome
+                // into a 1 or a 0
+                let (if_true, if_false, join) =
+                    (self.new_block(), self.new_block(), self.new_block());
+                self.lower_condition(ast, if_true, if_false)?;
+                for (block, value) in [(if_true, 1), (if_false, 0)] {
+                    self.start_block(block);
+                    self.push(Instruction::Copy {
+                        target,
+                        value: Value::Constant(value),
+                    });
+                    self.terminate(Terminator::Jump(join));
+                }
+                self.start_block(join);
+                return Ok(());
+            }
+        };
+        if let Ast::Expression { left, right, .. } = ast {
+            let left = self.lower_value(left)?;
+            let right = self.lower_value(right)?;
+            self.push(Instruction::Binary {
+                target,
+                operation,
+                left,
+                right,
+            });
+        }
+        Ok(())
+    }
+
+    /// Lowers a condition into jumps to `if_true` or `if_false`. This terminates the current
+    /// block.
+    fn lower_condition(
+        &mut self,
+        ast: &Ast<'a>,
+        if_true: BlockId,
+        if_false: BlockId,
+    ) -> Result<(), Error<'a>> {
+        let (comparison, swapped, left, right) = match ast {
+            Ast::ConstantNum(num) => {
+                let target = if *num != 0 { if_true } else { if_false };
+                self.terminate(Terminator::Jump(target));
+                return Ok(());
+            }
+            Ast::Not { value } => return self.lower_condition(value, if_false, if_true),
+            Ast::Expression {
+                left,
+                operation: operation @ (Operation::And | Operation::Or),
+                right,
+            } => {
+                let next = self.new_block();
+                if *operation == Operation::And {
+                    self.lower_condition(left, next, if_false)?;
+                } else {
+                    self.lower_condition(left, if_true, next)?;
+                }
+                self.start_block(next);
+                return self.lower_condition(right, if_true, if_false);
+            }
+            Ast::Expression {
+                left,
+                operation,
+                right,
+            } => match operation {
+                Operation::Equals => (Comparison::Equals, false, left, right),
+                Operation::LessThan => (Comparison::LessThan, false, left, right),
+                Operation::LessOrEqualTo => (Comparison::LessOrEqualTo, false, left, right),
+                Operation::GreaterThan => (Comparison::LessThan, true, left, right),
+                Operation::GreaterOrEqualTo => (Comparison::LessOrEqualTo, true, left, right),
+                _ => return self.lower_truthy(ast, if_true, if_false),
+            },
+            ast => return self.lower_truthy(ast, if_true, if_false),
+        };
+        // the operands are lowered in the order of the script, as they can call natives
+        let mut left = self.lower_value(left)?;
+        let mut right = self.lower_value(right)?;
+        if swapped {
+            core::mem::swap(&mut left, &mut right);
+        }
+        self.terminate(Terminator::Branch {
+            comparison,
+            left,
+            right,
+            if_true,
+            if_false,
+        });
+        Ok(())
+    }
+
+    /// Jumps to `if_true` if the value of `ast` is anything but 0.
+    fn lower_truthy(
+        &mut self,
+        ast: &Ast<'a>,
+        if_true: BlockId,
+        if_false: BlockId,
+    ) -> Result<(), Error<'a>> {
+        let value = self.lower_value(ast)?;
+        self.terminate(Terminator::Branch {
+            comparison: Comparison::Equals,
+            left: value,
+            right: Value::Constant(0),
+            if_true: if_false,
+            if_false: if_true,
+        });
+        Ok(())
+    }
+}
+
+#[test]
+fn test_lower_order() {
+    use super::super::{ast::tokens_to_ast, tokens::tokenize};
+    use crate::natives::DEFAULT_SIGNATURES;
+
+    let script = "if get_bit_buffer(1) > get_bit_buffer(2):\n    a = 1\n";
+    let ast = tokens_to_ast(tokenize(script)).unwrap();
+    let program = lower(&ast, &DEFAULT_SIGNATURES).unwrap();
+    let sizes: Vec<_> = program.blocks[0]
+        .instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Call { args, .. } => Some(args[0]),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(vec![Value::Constant(1), Value::Constant(2)], sizes);
+}
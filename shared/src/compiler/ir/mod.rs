@@ -0,0 +1,176 @@
+//! Three-address intermediate representation between the AST and the bytecode.
+//!
+//! A script is lowered into basic blocks, each ending in a single `Terminator`. All control flow
+//! (`if`, `for`, `loop:`) is expressed as jumps between blocks, and variables are referred to by
+//! `Slot`s that are only mapped onto the runtime's variable indices when emitting bytecode.
+
+mod emit;
+mod lower;
+
+pub use self::emit::{emit, Emitted};
+pub use self::lower::lower;
+
+use crate::instructions::MethodRef;
+use crate::natives::MAX_ARGS;
+use alloc::vec::Vec;
+use arrayvec::ArrayVec;
+
+/// A variable before slot allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotKind<'a> {
+    /// A variable declared in the script.
+    Variable(&'a str),
+    /// A value the compiler has to keep around for the lifetime of the script, like the end of a
+    /// `for` range.
+    Hidden,
+    /// An intermediate result of an expression, only used within a single statement.
+    Temporary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Slot(Slot),
+    Constant(i32),
+    /// A string literal, by its index in `Program::strings`
+    String(usize),
+}
+
+impl Value {
+    pub fn slot(self) -> Option<Slot> {
+        match self {
+            Value::Slot(slot) => Some(slot),
+            Value::Constant(_) | Value::String(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperation {
+    Add,
+    Subtract,
+    Multiply,
+    ShiftLeft,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equals,
+    LessThan,
+    LessOrEqualTo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Copy {
+        target: Slot,
+        value: Value,
+    },
+    Binary {
+        target: Slot,
+        operation: BinaryOperation,
+        left: Value,
+        right: Value,
+    },
+    Call {
+        target: Option<Slot>,
+        method: MethodRef,
+        args: ArrayVec<[Value; MAX_ARGS]>,
+    },
+}
+
+impl Instruction {
+    /// Every slot this instruction reads or writes.
+    pub fn slots(&self) -> ArrayVec<[Slot; 4]> {
+        let mut result = ArrayVec::new();
+        match self {
+            Instruction::Copy { target, value } => {
+                result.push(*target);
+                result.extend(value.slot());
+            }
+            Instruction::Binary {
+                target,
+                left,
+                right,
+                ..
+            } => {
+                result.push(*target);
+                result.extend(left.slot());
+                result.extend(right.slot());
+            }
+            Instruction::Call { target, args, .. } => {
+                result.extend(*target);
+                result.extend(args.iter().filter_map(|arg| arg.slot()));
+            }
+        }
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch {
+        comparison: Comparison,
+        left: Value,
+        right: Value,
+        if_true: BlockId,
+        if_false: BlockId,
+    },
+    /// End of the script
+    Return,
+}
+
+impl Terminator {
+    /// Every slot this terminator reads.
+    pub fn slots(&self) -> ArrayVec<[Slot; 4]> {
+        let mut result = ArrayVec::new();
+        if let Terminator::Branch { left, right, .. } = self {
+            result.extend(left.slot());
+            result.extend(right.slot());
+        }
+        result
+    }
+
+    pub fn successors(&self) -> ArrayVec<[BlockId; 2]> {
+        let mut result = ArrayVec::new();
+        match *self {
+            Terminator::Jump(target) => result.push(target),
+            Terminator::Branch {
+                if_true, if_false, ..
+            } => {
+                result.push(if_true);
+                result.push(if_false);
+            }
+            Terminator::Return => {}
+        }
+        result
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+    /// Script lines, as the index of the first instruction of each line. An index of
+    /// `instructions.len()` refers to the terminator.
+    pub lines: Vec<(usize, u32)>,
+}
+
+#[derive(Debug, Default)]
+pub struct Program<'a> {
+    pub blocks: Vec<Block>,
+    /// Order in which the blocks end up in the bytecode. The first block is the entry point.
+    pub layout: Vec<BlockId>,
+    pub slots: Vec<SlotKind<'a>>,
+    /// The block of the `on_reload:` handler, which is entered instead of the entry point by
+    /// `Runtime::reload`.
+    pub reload_entry: Option<BlockId>,
+    /// The string literals passed to natives, which end up in the constant pool
+    pub strings: Vec<&'a str>,
+}
@@ -1,17 +1,184 @@
 mod ast;
+mod constants;
+mod ir;
+mod optimizer;
+mod peephole;
 mod tokens;
+mod types;
 
-pub fn compile(script: &str, buffer: &mut [u8]) -> Result<usize, Error> {
-    use std::println;
+use crate::natives::{manifest, Signature, Type, DEFAULT_SIGNATURES};
+use crate::pool::{self, Constant};
+use crate::{container, debug_info};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use tokens::Token;
 
-    let mut tokens = tokens::tokenize(script);
-    tokens::optimize(&mut tokens);
+/// Settings for `compile_with_options`.
+#[derive(Debug, Clone)]
+pub struct Options<'n> {
+    /// Run the AST and bytecode optimizers. Turning this off keeps the bytecode close to the
+    /// script, which makes it easier to debug.
+    pub optimize: bool,
+    /// Add a debug info section after the code, mapping it back to the script. `Runtime::reload`
+    /// needs it to keep variables and to find the `on_reload:` handler.
+    pub debug_info: bool,
+    /// The natives of the runtime the script is compiled for. Calls refer to a native by its
+    /// index in this slice, so it has to be in the same order as the natives of the runtime.
+    pub natives: &'n [Signature<'n>],
+}
 
-    let ast = ast::tokens_to_ast(&tokens);
-    println!("{:#?}", ast);
+impl Default for Options<'_> {
+    fn default() -> Self {
+        Self {
+            optimize: true,
+            debug_info: false,
+            natives: &DEFAULT_SIGNATURES,
+        }
+    }
+}
 
-    Ok(0)
+pub fn compile<'a>(script: &'a str, buffer: &mut [u8]) -> Result<usize, Error<'a>> {
+    compile_with_options(script, buffer, &Options::default())
 }
 
-#[derive(Debug)]
-pub enum Error {}
+pub fn compile_with_options<'a>(
+    script: &'a str,
+    buffer: &mut [u8],
+    options: &Options<'_>,
+) -> Result<usize, Error<'a>> {
+    let mut ast = ast::tokens_to_ast(tokens::tokenize(script))?;
+    constants::fold(&mut ast)?;
+    let types = types::check(&ast, options.natives)?;
+    if options.optimize {
+        optimizer::optimize(&mut ast);
+    }
+    let program = ir::lower(&ast, options.natives)?;
+    if buffer.len() < container::HEADER_SIZE {
+        return Err(Error::BufferTooSmall);
+    }
+    let code = &mut buffer[container::HEADER_SIZE..];
+    let ir::Emitted {
+        mut len,
+        variable_count,
+        mut lines,
+        variables,
+        constants,
+        strings,
+        reload_entry,
+    } = ir::emit(&program, code)?;
+    let mut entries: Vec<usize> = reload_entry.into_iter().collect();
+    if options.optimize {
+        len = peephole::optimize(&mut code[..len], &mut lines, &mut entries);
+    }
+
+    let mut header = container::Header {
+        required_features: 0,
+        variable_count: variable_count as u16,
+        entry_point: 0,
+        code_len: len as u16,
+        natives_hash: manifest::hash(options.natives),
+        pool_len: 0,
+    };
+    if !constants.is_empty() || !strings.is_empty() {
+        header.required_features |= container::features::CONSTANT_POOL;
+        let pool_start = header.pool_range().start;
+        let mut writer = pool::Writer::new(
+            buffer.get_mut(pool_start..).ok_or(Error::BufferTooSmall)?,
+            (constants.len() + strings.len()) as u8,
+        )
+        .ok_or(Error::BufferTooSmall)?;
+        let numbers = constants.into_iter().map(Constant::Number);
+        for constant in numbers.chain(strings.into_iter().map(Constant::String)) {
+            writer.push(constant).ok_or(Error::BufferTooSmall)?;
+        }
+        header.pool_len = u16::try_from(writer.finish()).map_err(|_| Error::ProgramTooLarge)?;
+    }
+    header.write(buffer);
+    let mut len = header.len();
+
+    if options.debug_info {
+        dedup_lines(&mut lines);
+        len += debug_info::write(
+            &mut buffer[len..],
+            entries.first().map(|entry| *entry as u16),
+            lines.iter().map(|(offset, line)| (*offset as u16, *line)),
+            variables.into_iter().map(|(slot, name)| {
+                let ty = types.iter().find(|(other, _)| *other == name);
+                (slot, ty.map_or(Type::Number, |(_, ty)| *ty), name)
+            }),
+        )
+        .ok_or(Error::BufferTooSmall)?;
+    }
+    Ok(len)
+}
+
+/// Keeps only the last line of instructions that start on the same offset, as the lines
+/// before it have no code left, and drops lines that continue the line before them.
+fn dedup_lines(lines: &mut Vec<(usize, u32)>) {
+    lines.dedup_by(|(offset, line), previous| {
+        if *offset == previous.0 {
+            previous.1 = *line;
+        }
+        *offset == previous.0
+    });
+    lines.dedup_by_key(|(_, line)| *line);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error<'a> {
+    /// The script contains `found` where `expected` was expected. `found` is `None` at the end of
+    /// the script.
+    UnexpectedToken {
+        expected: &'static str,
+        found: Option<Token<'a>>,
+    },
+    /// A line is indented deeper than the block it is in.
+    InvalidIndentation { expected: u8, found: u8 },
+    /// A number literal or constant expression does not fit in an `i32`.
+    Overflow,
+    /// A string literal has no closing `"` on its line.
+    UnterminatedString,
+    /// A string literal is used anywhere but as an argument of a native that takes a string.
+    MisplacedString,
+    /// A string literal is longer than the 255 bytes a constant can hold.
+    StringTooLong(&'a str),
+    /// The value of this constant can not be computed at compile time.
+    NotConstant(&'a str),
+    /// This constant is declared twice.
+    ConstantRedefined(&'a str),
+    /// This constant is assigned to, or used as a loop variable.
+    AssignToConstant(&'a str),
+    /// There is no native with this name.
+    UnknownMethod(&'a str),
+    /// A method is called with the wrong amount of arguments.
+    WrongArgumentCount {
+        method: &'a str,
+        expected: usize,
+        found: usize,
+    },
+    /// The result of a method that does not return anything is used.
+    NoReturnValue(&'a str),
+    /// Argument `index` of a method call has the wrong type, like a number passed as a buffer.
+    ArgumentType {
+        method: &'a str,
+        index: usize,
+        expected: Type,
+        found: Type,
+    },
+    /// A variable is assigned a value of another type than its first assignment.
+    VariableType {
+        name: &'a str,
+        expected: Type,
+        found: Type,
+    },
+    /// An `on_reload:` handler is inside another block, instead of at the top of the script.
+    NestedOnReload,
+    /// The script has more than one `on_reload:` handler.
+    OnReloadRedefined,
+    /// The script needs more variable slots than the runtime has.
+    TooManyVariables,
+    /// The bytecode container does not fit in the buffer passed to `compile`.
+    BufferTooSmall,
+    /// The bytecode is too large to address with a jump.
+    ProgramTooLarge,
+}
@@ -0,0 +1,348 @@
+use super::ast::{Ast, Operation};
+use alloc::vec::Vec;
+
+/// Variables with a value that is known at this point in the script.
+type Known<'a> = Vec<(&'a str, i32)>;
+
+/// Optimizes a script that has already gone through `constants::fold`.
+///
+/// - Variables that hold a known constant are replaced by that constant, and the results folded.
+/// - `if` statements with a constant condition are removed or replaced by their body.
+/// - Statements after a `loop:` are removed, as a `loop:` never ends, up to the `on_reload:`
+///   handler. A reload continues after the handler with any values, so nothing is known there.
+/// - Assignments to variables that are never read are removed.
+/// - Multiplications by a power of two are replaced by a shift.
+pub fn optimize(ast: &mut Ast) {
+    if let Ast::Block { statements } = ast {
+        optimize_block(statements, &mut Known::new());
+    }
+    loop {
+        let mut read = Vec::new();
+        read_variables(ast, &mut read);
+        if !remove_unused_assignments(ast, &read) {
+            break;
+        }
+    }
+    reduce_strength(ast);
+}
+
+fn set<'a>(known: &mut Known<'a>, name: &'a str, value: Option<i32>) {
+    known.retain(|(known_name, _)| *known_name != name);
+    if let Some(value) = value {
+        known.push((name, value));
+    }
+}
+
+fn forget_assigned<'a>(known: &mut Known<'a>, statements: &[Ast<'a>]) {
+    for statement in statements {
+        match statement {
+            Ast::Assign { var_name, .. } => set(known, var_name, None),
+            Ast::For {
+                var_name,
+                statements,
+                ..
+            } => {
+                set(known, var_name, None);
+                forget_assigned(known, statements);
+            }
+            Ast::Loop { statements }
+            | Ast::OnReload { statements }
+            | Ast::If { statements, .. }
+            | Ast::Block { statements } => forget_assigned(known, statements),
+            _ => {}
+        }
+    }
+}
+
+fn optimize_block<'a>(statements: &mut Vec<Ast<'a>>, known: &mut Known<'a>) {
+    let mut result = Vec::with_capacity(statements.len());
+    let mut reachable = true;
+    for mut statement in statements.drain(..) {
+        if !reachable && !matches!(statement, Ast::OnReload { .. }) {
+            continue;
+        }
+        match &mut statement {
+            Ast::Assign { var_name, rhs } => {
+                optimize_expression(rhs, known);
+                let value = match **rhs {
+                    Ast::ConstantNum(value) => Some(value),
+                    _ => None,
+                };
+                set(known, var_name, value);
+            }
+            Ast::If {
+                condition,
+                statements,
+            } => {
+                optimize_expression(condition, known);
+                match **condition {
+                    Ast::ConstantNum(0) => continue,
+                    Ast::ConstantNum(_) => {
+                        optimize_block(statements, known);
+                        result.append(statements);
+                        reachable = !matches!(result.last(), Some(Ast::Loop { .. }));
+                        continue;
+                    }
+                    _ => {
+                        optimize_block(statements, &mut known.clone());
+                        forget_assigned(known, statements);
+                    }
+                }
+            }
+            Ast::Loop { statements } => {
+                forget_assigned(known, statements);
+                optimize_block(statements, known);
+                // a loop never ends, so nothing after it is reachable
+                reachable = false;
+            }
+            Ast::OnReload { statements } => {
+                known.clear();
+                optimize_block(statements, &mut Known::new());
+                reachable = true;
+            }
+            Ast::For {
+                var_name,
+                start,
+                end,
+                statements,
+            } => {
+                optimize_expression(start, known);
+                optimize_expression(end, known);
+                set(known, var_name, None);
+                forget_assigned(known, statements);
+                optimize_block(statements, &mut known.clone());
+            }
+            Ast::Block { statements } => {
+                optimize_block(statements, known);
+                result.append(statements);
+                continue;
+            }
+            statement => optimize_expression(statement, known),
+        }
+        result.push(statement);
+    }
+    *statements = result;
+}
+
+fn optimize_expression(ast: &mut Ast, known: &Known) {
+    match ast {
+        Ast::Variable { name } => {
+            if let Some((_, value)) = known.iter().find(|(known_name, _)| known_name == name) {
+                *ast = Ast::ConstantNum(*value);
+            }
+        }
+        Ast::Method { args, .. } => {
+            for arg in args {
+                optimize_expression(arg, known);
+            }
+        }
+        Ast::Expression {
+            left,
+            operation,
+            right,
+        } => {
+            optimize_expression(left, known);
+            optimize_expression(right, known);
+            match (&**left, *operation, &**right) {
+                (Ast::ConstantNum(left), operation, Ast::ConstantNum(right)) => {
+                    // overflows are left for the runtime to handle
+                    if let Some(value) = operation.evaluate(*left, *right) {
+                        *ast = Ast::ConstantNum(value);
+                    }
+                }
+                (_, Operation::Plus, Ast::ConstantNum(0))
+                | (_, Operation::Minus, Ast::ConstantNum(0))
+                | (_, Operation::Multiply, Ast::ConstantNum(1)) => {
+                    *ast = core::mem::replace(&mut **left, Ast::ConstantNum(0));
+                }
+                (Ast::ConstantNum(0), Operation::Plus, _)
+                | (Ast::ConstantNum(1), Operation::Multiply, _) => {
+                    *ast = core::mem::replace(&mut **right, Ast::ConstantNum(0));
+                }
+                _ => {}
+            }
+        }
+        Ast::Not { value } => {
+            optimize_expression(value, known);
+            if let Ast::ConstantNum(value) = **value {
+                *ast = Ast::ConstantNum((value == 0) as i32);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn read_variables<'a>(ast: &Ast<'a>, read: &mut Vec<&'a str>) {
+    match ast {
+        Ast::ConstantNum(_) | Ast::String(_) | Ast::Line(_) => {}
+        Ast::Variable { name } => read.push(name),
+        Ast::Assign { rhs: value, .. }
+        | Ast::Const { value, .. }
+        | Ast::Not { value }
+        | Ast::If {
+            condition: value, ..
+        } => read_variables(value, read),
+        Ast::Method { args, .. } => args.iter().for_each(|arg| read_variables(arg, read)),
+        Ast::Expression { left, right, .. }
+        | Ast::For {
+            start: left,
+            end: right,
+            ..
+        } => {
+            read_variables(left, read);
+            read_variables(right, read);
+        }
+        Ast::Loop { .. } | Ast::OnReload { .. } | Ast::Block { .. } => {}
+    }
+    if let Ast::Loop { statements }
+    | Ast::OnReload { statements }
+    | Ast::Block { statements }
+    | Ast::If { statements, .. }
+    | Ast::For { statements, .. } = ast
+    {
+        for statement in statements {
+            read_variables(statement, read);
+        }
+    }
+}
+
+fn has_side_effects(ast: &Ast) -> bool {
+    match ast {
+        Ast::Method { .. } => true,
+        Ast::Expression { left, right, .. } => has_side_effects(left) || has_side_effects(right),
+        Ast::Not { value } => has_side_effects(value),
+        _ => false,
+    }
+}
+
+/// Removes assignments to variables that are not in `read`. Returns `true` if anything changed.
+fn remove_unused_assignments(ast: &mut Ast, read: &[&str]) -> bool {
+    let statements = match ast {
+        Ast::Loop { statements }
+        | Ast::OnReload { statements }
+        | Ast::Block { statements }
+        | Ast::If { statements, .. }
+        | Ast::For { statements, .. } => statements,
+        _ => return false,
+    };
+    let mut changed = false;
+    statements.retain_mut(|statement| {
+        if let Ast::Assign { var_name, rhs } = statement {
+            if !read.contains(var_name) {
+                if let Ast::Method { .. } = **rhs {
+                    *statement = core::mem::replace(&mut **rhs, Ast::ConstantNum(0));
+                    changed = true;
+                } else if !has_side_effects(rhs) {
+                    changed = true;
+                    return false;
+                }
+            }
+        }
+        changed |= remove_unused_assignments(statement, read);
+        true
+    });
+    changed
+}
+
+fn reduce_strength(ast: &mut Ast) {
+    match ast {
+        Ast::Expression {
+            left,
+            operation: operation @ Operation::Multiply,
+            right,
+        } => {
+            if let Ast::ConstantNum(_) = **left {
+                core::mem::swap(left, right);
+            }
+            if let Ast::ConstantNum(value) = **right {
+                if value > 1 && (value as u32).is_power_of_two() {
+                    *operation = Operation::ShiftLeft;
+                    **right = Ast::ConstantNum(value.trailing_zeros() as i32);
+                }
+            }
+            reduce_strength(left);
+            reduce_strength(right);
+        }
+        Ast::Expression { left, right, .. }
+        | Ast::For {
+            start: left,
+            end: right,
+            ..
+        } => {
+            reduce_strength(left);
+            reduce_strength(right);
+        }
+        Ast::Assign { rhs: value, .. }
+        | Ast::Not { value }
+        | Ast::If {
+            condition: value, ..
+        } => reduce_strength(value),
+        Ast::Method { args, .. } => args.iter_mut().for_each(reduce_strength),
+        _ => {}
+    }
+    if let Ast::Loop { statements }
+    | Ast::OnReload { statements }
+    | Ast::Block { statements }
+    | Ast::If { statements, .. }
+    | Ast::For { statements, .. } = ast
+    {
+        statements.iter_mut().for_each(reduce_strength);
+    }
+}
+
+#[test]
+fn test_optimize() {
+    use super::{ast::tokens_to_ast, constants::fold, tokens::tokenize};
+
+    let script = r#"
+width = 8
+unused = width + 1
+buffer = get_bit_buffer(width * width)
+if width > 4:
+    count = get_bit_buffer_index(buffer, 3)
+loop:
+    for x in 0, width:
+        set_bit_buffer_index(buffer, x * 4 + count * 1)
+    width += 1
+set_frame_buffer(buffer)
+"#;
+    let mut ast = tokens_to_ast(tokenize(script)).unwrap();
+    fold(&mut ast).unwrap();
+    optimize(&mut ast);
+
+    let expected = r#"
+width = 8
+buffer = get_bit_buffer(64)
+count = get_bit_buffer_index(buffer, 3)
+loop:
+    for x in 0, width:
+        set_bit_buffer_index(buffer, x * 4 + count)
+    width += 1
+"#;
+    let mut expected = tokens_to_ast(tokenize(expected)).unwrap();
+    // `x * 4` can only be written as a multiplication
+    reduce_strength(&mut expected);
+    super::ast::strip_lines(&mut expected);
+    super::ast::strip_lines(&mut ast);
+    assert_eq!(expected, ast);
+
+    let mut ast = tokens_to_ast(tokenize("a = x * (y * 2)\n")).unwrap();
+    reduce_strength(&mut ast);
+    super::ast::strip_lines(&mut ast);
+    let Ast::Block { statements } = ast else {
+        panic!("expected a block, found {:?}", ast)
+    };
+    let Ast::Assign { rhs, .. } = &statements[0] else {
+        panic!("expected an assignment, found {:?}", statements[0])
+    };
+    let Ast::Expression { right, .. } = &**rhs else {
+        panic!("expected an expression, found {:?}", rhs)
+    };
+    assert!(matches!(
+        **right,
+        Ast::Expression {
+            operation: Operation::ShiftLeft,
+            ..
+        }
+    ));
+}
@@ -0,0 +1,297 @@
+use crate::instructions::{Instructions, VariableRef};
+use alloc::vec::Vec;
+
+/// Shrinks the bytecode that `ir::emit` wrote to `bytecode`, returning the new length.
+///
+/// - Jumps to unconditional jumps go straight to the final target.
+/// - Jumps to the next instruction are removed.
+/// - `JumpIfTrue a; Jump b; a:` becomes `JumpIfFalse b`, and vice versa.
+/// - Code after an unconditional jump that is never jumped to is removed.
+/// - Moves of a variable to itself, and moves that are overwritten by the next instruction,
+///   are removed.
+/// - Compares that are not followed by a conditional jump are removed.
+///
+/// This relies on every conditional jump directly following the compare it depends on, which
+/// is how `ir::emit` lays them out.
+///
+/// The offsets in `lines` are moved along with the instructions. Lines of which every
+/// instruction is removed end up at the offset of the next instruction. `entries` are offsets
+/// that code is started at besides the start, like the `on_reload:` handler. They are kept like
+/// jump targets, and moved the same way as `lines`.
+pub fn optimize(bytecode: &mut [u8], lines: &mut [(usize, u32)], entries: &mut [usize]) -> usize {
+    let mut offsets = Vec::new();
+    let mut code = Vec::new();
+    let mut offset = 0;
+    while offset < bytecode.len() {
+        let instruction = Instructions::get(&bytecode[offset..]);
+        offsets.push(offset);
+        code.push(Some(instruction));
+        offset += instruction.size();
+    }
+
+    // While optimizing, jump targets are indices into `code`, where `code.len()` is the end
+    for instruction in code.iter_mut().flatten() {
+        if let Some(target) = jump_target(instruction) {
+            let index = offsets
+                .binary_search(&(*target as usize))
+                .unwrap_or(offsets.len());
+            *target = index as u16;
+        }
+    }
+
+    let entry_indices: Vec<usize> = entries
+        .iter()
+        .map(|entry| offsets.binary_search(entry).unwrap_or(offsets.len()))
+        .collect();
+    while optimize_pass(&mut code, &entry_indices) {}
+
+    let mut len = 0;
+    let mut new_offsets = Vec::with_capacity(code.len() + 1);
+    for instruction in &code {
+        new_offsets.push(len);
+        len += instruction.as_ref().map_or(0, Instructions::size);
+    }
+    new_offsets.push(len);
+    for offset in lines.iter_mut().map(|(offset, _)| offset).chain(entries) {
+        let index = offsets.binary_search(offset).unwrap_or(offsets.len());
+        *offset = new_offsets[index];
+    }
+    let mut offset = 0;
+    for mut instruction in code.into_iter().flatten() {
+        if let Some(target) = jump_target(&mut instruction) {
+            *target = new_offsets[*target as usize] as u16;
+        }
+        instruction.write(&mut bytecode[offset..]);
+        offset += instruction.size();
+    }
+    len
+}
+
+fn jump_target(instruction: &mut Instructions) -> Option<&mut u16> {
+    match instruction {
+        Instructions::Jump { target }
+        | Instructions::JumpIfTrue { target }
+        | Instructions::JumpIfFalse { target } => Some(target),
+        _ => None,
+    }
+}
+
+fn is_conditional_jump(instruction: &Option<Instructions>) -> bool {
+    matches!(
+        instruction,
+        Some(Instructions::JumpIfTrue { .. } | Instructions::JumpIfFalse { .. })
+    )
+}
+
+/// Index of the first instruction at or after `index` that has not been removed.
+fn live(code: &[Option<Instructions>], mut index: usize) -> usize {
+    while index < code.len() && code[index].is_none() {
+        index += 1;
+    }
+    index
+}
+
+/// The variable written by `instruction`, if it does not also read it.
+fn overwrites(instruction: &Instructions) -> Option<u8> {
+    let (target, reads) = match instruction {
+        Instructions::Move { target, value } => (*target, [*value, VariableRef::None]),
+        Instructions::Add {
+            target,
+            left,
+            right,
+        }
+        | Instructions::Subtract {
+            target,
+            left,
+            right,
+        }
+        | Instructions::Multiply {
+            target,
+            left,
+            right,
+        }
+        | Instructions::ShiftLeft {
+            target,
+            left,
+            right,
+        } => (*target, [*left, *right]),
+        _ => return None,
+    };
+    if reads.contains(&VariableRef::Idx(target)) {
+        None
+    } else {
+        Some(target)
+    }
+}
+
+fn optimize_pass(code: &mut [Option<Instructions>], entries: &[usize]) -> bool {
+    let mut changed = false;
+
+    // Point every jump at the first live instruction it ends up at
+    for index in 0..code.len() {
+        let mut instruction = match code[index] {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        if let Some(target) = jump_target(&mut instruction) {
+            let mut new_target = live(code, *target as usize);
+            for _ in 0..code.len() {
+                match code.get(new_target) {
+                    Some(Some(Instructions::Jump { target })) if *target as usize != new_target => {
+                        new_target = live(code, *target as usize)
+                    }
+                    _ => break,
+                }
+            }
+            if new_target != *target as usize {
+                *target = new_target as u16;
+                code[index] = Some(instruction);
+                changed = true;
+            }
+        }
+    }
+
+    let mut is_jump_target = alloc::vec![false; code.len() + 1];
+    for instruction in code.iter_mut().flatten() {
+        if let Some(target) = jump_target(instruction) {
+            is_jump_target[*target as usize] = true;
+        }
+    }
+    for entry in entries {
+        is_jump_target[live(code, *entry)] = true;
+    }
+
+    let mut index = live(code, 0);
+    while index < code.len() {
+        let next = live(code, index + 1);
+        let remove = match code[index].unwrap() {
+            Instructions::Jump { target }
+            | Instructions::JumpIfTrue { target }
+            | Instructions::JumpIfFalse { target }
+                if target as usize == next =>
+            {
+                true
+            }
+            instruction @ (Instructions::JumpIfTrue { target }
+            | Instructions::JumpIfFalse { target }) => {
+                match code.get(next) {
+                    Some(Some(Instructions::Jump { target: other }))
+                        if target as usize == live(code, next + 1) && !is_jump_target[next] =>
+                    {
+                        let target = *other;
+                        code[index] = Some(match instruction {
+                            Instructions::JumpIfTrue { .. } => Instructions::JumpIfFalse { target },
+                            _ => Instructions::JumpIfTrue { target },
+                        });
+                        code[next] = None;
+                        changed = true;
+                    }
+                    _ => {}
+                }
+                false
+            }
+            Instructions::Jump { .. } => {
+                // Nothing falls through into the code after a jump
+                let mut dead = next;
+                while dead < code.len() && !is_jump_target[dead] {
+                    if code[dead].take().is_some() {
+                        changed = true;
+                    }
+                    dead += 1;
+                }
+                false
+            }
+            Instructions::Move {
+                target,
+                value: VariableRef::Idx(value),
+            } if target == value => true,
+            Instructions::CompareEquals { .. }
+            | Instructions::CompareLessThan { .. }
+            | Instructions::CompareLessOrEqualTo { .. } => {
+                next >= code.len() || !is_conditional_jump(&code[next])
+            }
+            Instructions::Move { target, .. } => {
+                !is_jump_target[next]
+                    && code
+                        .get(next)
+                        .copied()
+                        .flatten()
+                        .and_then(|i| overwrites(&i))
+                        == Some(target)
+            }
+            _ => false,
+        };
+        if remove {
+            code[index] = None;
+            changed = true;
+        }
+        index = live(code, index + 1);
+    }
+
+    changed
+}
+
+#[test]
+fn test_peephole() {
+    let code = [
+        // 0
+        Instructions::Move {
+            target: 1,
+            value: VariableRef::Num(5),
+        },
+        // 3
+        Instructions::Move {
+            target: 1,
+            value: VariableRef::Num(3),
+        },
+        // 6
+        Instructions::CompareLessThan {
+            left: VariableRef::Idx(1),
+            right: VariableRef::Num(10),
+        },
+        // 10
+        Instructions::JumpIfTrue { target: 16 },
+        // 13
+        Instructions::Jump { target: 29 },
+        // 16
+        Instructions::Move {
+            target: 2,
+            value: VariableRef::Idx(2),
+        },
+        // 20
+        Instructions::Jump { target: 26 },
+        // 23 (unreachable)
+        Instructions::Jump { target: 0 },
+        // 26
+        Instructions::Jump { target: 6 },
+        // 29
+    ];
+    let mut bytecode = [0u8; 64];
+    let mut len = 0;
+    for instruction in &code {
+        instruction.write(&mut bytecode[len..]);
+        len += instruction.size();
+    }
+    assert_eq!(29, len);
+
+    let mut lines = [(6, 3), (16, 4), (23, 5)];
+    let len = optimize(&mut bytecode[..len], &mut lines, &mut []);
+    assert_eq!([(3, 3), (10, 4), (10, 5)], lines);
+    let expected = [
+        Instructions::Move {
+            target: 1,
+            value: VariableRef::Num(3),
+        },
+        Instructions::CompareLessThan {
+            left: VariableRef::Idx(1),
+            right: VariableRef::Num(10),
+        },
+        Instructions::JumpIfTrue { target: 3 },
+    ];
+    let mut offset = 0;
+    for instruction in &expected {
+        assert_eq!(*instruction, Instructions::get(&bytecode[offset..]));
+        offset += instruction.size();
+    }
+    assert_eq!(offset, len);
+}
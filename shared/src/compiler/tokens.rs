@@ -1,15 +1,19 @@
-use alloc::vec::Vec;
-
 #[derive(Debug, PartialEq, Eq)]
 pub enum Token<'a> {
     Number(i32),
     Word(&'a str),
+    /// The text between two `"` on one line. There are no escapes, so it can not contain a `"`.
+    String(&'a str),
     Ident(u8),
+    /// The line a statement starts on, counting from 1. Follows the `Ident` of the line.
+    Line(u32),
     BananaOpen,
     EndStatement,
     BananaClose,
     Comma,
+    Const,
     Loop,
+    OnReload,
     For,
     If,
     In,
@@ -18,6 +22,8 @@ pub enum Token<'a> {
     Not,
     Equals,
     Assign,
+    PlusAssign,
+    MinusAssign,
     Colon,
     Multiply,
     Plus,
@@ -28,146 +34,202 @@ pub enum Token<'a> {
     GreaterOrEqualTo,
 }
 
-pub fn tokenize(mut script: &str) -> Vec<Token> {
-    let mut result = Vec::new();
+const DELIMITERS: &[u8] = b"\t\n\r()=:*><+,-\" ";
 
-    'outer: while !script.is_empty() {
-        if script.starts_with("    ") {
-            let mut ident_count = 0;
-            while script.starts_with("    ") {
-                ident_count += 1;
-                script = &script[4..];
-            }
-            result.push(Token::Ident(ident_count));
+/// Streaming lexer over a script.
+///
+/// Works without allocating: blank lines are skipped, indentation is reported as a single
+/// `Ident` at the start of each non-empty line followed by the `Line` it is on, and every
+/// non-empty line is terminated by exactly one `EndStatement`.
+pub struct Tokenizer<'a> {
+    script: &'a str,
+    line: u32,
+    at_line_start: bool,
+    line_has_tokens: bool,
+    line_pending: bool,
+}
+
+pub fn tokenize(script: &str) -> Tokenizer<'_> {
+    Tokenizer {
+        script,
+        line: 1,
+        at_line_start: true,
+        line_has_tokens: false,
+        line_pending: false,
+    }
+}
+
+impl<'a> Tokenizer<'a> {
+    fn take(&mut self, len: usize) {
+        self.script = &self.script[len..];
+    }
+
+    fn end_line(&mut self) -> Option<Token<'a>> {
+        self.at_line_start = true;
+        if core::mem::replace(&mut self.line_has_tokens, false) {
+            Some(Token::EndStatement)
+        } else {
+            None
         }
-        if script.starts_with('\t') {
-            let mut ident_count = 0;
-            while script.starts_with('\t') {
-                ident_count += 1;
-                script = &script[1..];
+    }
+
+    /// Consumes the indentation of the current line. Returns the indentation level, or `None`
+    /// if the line turned out to be blank.
+    fn indentation(&mut self) -> Option<u8> {
+        let mut ident_count = 0u8;
+        loop {
+            if self.script.starts_with("    ") {
+                self.take(4);
+            } else if self.script.starts_with('\t') {
+                self.take(1);
+            } else {
+                break;
             }
-            result.push(Token::Ident(ident_count));
+            ident_count = ident_count.saturating_add(1);
         }
-        while let Some(index) = script.bytes().position(|c| {
-            [
-                b'\t', b'\n', b'\r', b'(', b')', b'=', b':', b'*', b'>', b'<', b'+', b',', b'-',
-                b' ',
-            ]
-            .contains(&c)
-        }) {
-            if index == 0 {
-                let c = script.bytes().next().unwrap() as char;
-                let mut chars_taken = 1;
-                match c {
-                    ' ' => {}
-                    '\n' => {
-                        result.push(Token::EndStatement);
-                        script = &script[1..];
-                        continue 'outer;
-                    }
-                    '=' => {
-                        if let Some(b'=') = script.bytes().nth(1) {
-                            result.push(Token::Equals);
-                            chars_taken += 1;
-                        } else {
-                            result.push(Token::Assign);
-                        }
-                    }
-                    '>' => {
-                        if let Some(b'=') = script.bytes().nth(1) {
-                            result.push(Token::GreaterOrEqualTo);
-                            chars_taken += 1;
-                        } else {
-                            result.push(Token::GreaterThan);
+        let rest = self.script.trim_start_matches([' ', '\r']);
+        if rest.is_empty() || rest.starts_with('\n') {
+            self.script = rest;
+            None
+        } else {
+            Some(ident_count)
+        }
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        loop {
+            if self.at_line_start {
+                match self.indentation() {
+                    Some(ident_count) => {
+                        self.at_line_start = false;
+                        self.line_has_tokens = true;
+                        self.line_pending = true;
+                        if ident_count > 0 {
+                            return Some(Token::Ident(ident_count));
                         }
                     }
-                    '<' => {
-                        if let Some(b'=') = script.bytes().nth(1) {
-                            result.push(Token::LessOrEqualTo);
-                            chars_taken += 1;
-                        } else {
-                            result.push(Token::LessThan);
-                        }
+                    None if self.script.is_empty() => return None,
+                    None => {
+                        self.take(1);
+                        self.line += 1;
+                        continue;
                     }
-                    '(' => result.push(Token::BananaOpen),
-                    ')' => result.push(Token::BananaClose),
-                    ',' => result.push(Token::Comma),
-                    ':' => result.push(Token::Colon),
-                    '*' => result.push(Token::Multiply),
-                    '+' => result.push(Token::Plus),
-                    '-' => result.push(Token::Minus),
-                    x => panic!("Unknown token: {:?}", x),
                 }
-                script = &script[chars_taken..];
-            } else {
-                let word = &script[..index].trim();
-                if let Ok(num) = word.parse() {
-                    result.push(Token::Number(num));
+            }
+            if core::mem::take(&mut self.line_pending) {
+                return Some(Token::Line(self.line));
+            }
+
+            let mut bytes = self.script.bytes();
+            let (c, next) = match bytes.next() {
+                Some(c) => (c, bytes.next()),
+                None => return self.end_line(),
+            };
+            let index = self
+                .script
+                .bytes()
+                .position(|c| DELIMITERS.contains(&c))
+                .unwrap_or(self.script.len());
+            if index > 0 {
+                let word = self.script[..index].trim();
+                self.take(index);
+                return Some(if let Ok(num) = word.parse() {
+                    Token::Number(num)
                 } else {
-                    match *word {
-                        "loop" => result.push(Token::Loop),
-                        "for" => result.push(Token::For),
-                        "in" => result.push(Token::In),
-                        "if" => result.push(Token::If),
-                        "and" => result.push(Token::And),
-                        "or" => result.push(Token::Or),
-                        "not" => result.push(Token::Not),
-                        word => result.push(Token::Word(word)),
+                    match word {
+                        "const" => Token::Const,
+                        "loop" => Token::Loop,
+                        "on_reload" => Token::OnReload,
+                        "for" => Token::For,
+                        "in" => Token::In,
+                        "if" => Token::If,
+                        "and" => Token::And,
+                        "or" => Token::Or,
+                        "not" => Token::Not,
+                        word => Token::Word(word),
                     }
-                }
-                script = &script[index..]
+                });
             }
-        }
-    }
 
-    result
-}
-
-pub fn optimize(tokens: &mut Vec<Token>) {
-    let mut idx = 0;
-    'optimize_loop: while idx < tokens.len() {
-        if idx > 0 && [Token::Multiply, Token::Minus, Token::Plus].contains(&tokens[idx]) {
-            if let (Some(Token::Number(left)), Some(Token::Number(right))) =
-                (tokens.get(idx - 1), tokens.get(idx + 1))
-            {
-                let result = match &tokens[idx] {
-                    Token::Multiply => left * right,
-                    Token::Minus => left - right,
-                    Token::Plus => left + right,
-                    token => panic!("Unknown math optimization: {:?}", token),
-                };
-                tokens[idx - 1] = Token::Number(result);
-                tokens.remove(idx);
-                tokens.remove(idx);
-                continue 'optimize_loop;
-            }
-        }
-        if tokens[idx] == Token::EndStatement {
-            while let Some(Token::EndStatement) = tokens.get(idx + 1) {
-                tokens.remove(idx + 1);
-            }
-        }
-        if let Token::Ident(num) = tokens[idx] {
-            match tokens.get(idx + 1) {
-                Some(Token::EndStatement) => {
-                    tokens.remove(idx);
-                    continue 'optimize_loop;
+            let (token, chars_taken) = match (c, next) {
+                (b' ', _) | (b'\t', _) | (b'\r', _) => {
+                    self.take(1);
+                    continue;
                 }
-                Some(Token::Ident(num2)) => {
-                    tokens[idx] = Token::Ident(num + num2);
-                    tokens.remove(idx + 1);
-                    continue 'optimize_loop;
+                (b'\n', _) => {
+                    self.take(1);
+                    self.line += 1;
+                    match self.end_line() {
+                        Some(token) => return Some(token),
+                        None => continue,
+                    }
                 }
-                _ => {}
-            }
+                (b'=', Some(b'=')) => (Token::Equals, 2),
+                (b'=', _) => (Token::Assign, 1),
+                (b'>', Some(b'=')) => (Token::GreaterOrEqualTo, 2),
+                (b'>', _) => (Token::GreaterThan, 1),
+                (b'<', Some(b'=')) => (Token::LessOrEqualTo, 2),
+                (b'<', _) => (Token::LessThan, 1),
+                (b'(', _) => (Token::BananaOpen, 1),
+                (b')', _) => (Token::BananaClose, 1),
+                (b',', _) => (Token::Comma, 1),
+                (b':', _) => (Token::Colon, 1),
+                (b'*', _) => (Token::Multiply, 1),
+                (b'+', Some(b'=')) => (Token::PlusAssign, 2),
+                (b'+', _) => (Token::Plus, 1),
+                (b'-', Some(b'=')) => (Token::MinusAssign, 2),
+                (b'-', _) => (Token::Minus, 1),
+                (b'"', _) => {
+                    let script = self.script;
+                    match script[1..].find(['"', '\n']) {
+                        Some(end) if script[1 + end..].starts_with('"') => {
+                            (Token::String(&script[1..1 + end]), end + 2)
+                        }
+                        // left for the parser to report
+                        _ => {
+                            let end = script.find('\n').unwrap_or(script.len());
+                            (Token::Word(&script[..end]), end)
+                        }
+                    }
+                }
+                (c, _) => unreachable!("{:?} is not a delimiter", c as char),
+            };
+            self.take(chars_taken);
+            return Some(token);
         }
-        idx += 1;
-    }
-    while let Some(Token::EndStatement) = tokens.first() {
-        tokens.remove(0);
     }
-    while let Some(Token::EndStatement) = tokens.last() {
-        tokens.pop();
+}
+
+#[test]
+fn test_tokenize_layout() {
+    let script = "\n\na = 1\r\n    \n\tb += 2*3\n        loop:";
+    let mut tokens = tokenize(script);
+    let expected = [
+        Token::Line(3),
+        Token::Word("a"),
+        Token::Assign,
+        Token::Number(1),
+        Token::EndStatement,
+        Token::Ident(1),
+        Token::Line(5),
+        Token::Word("b"),
+        Token::PlusAssign,
+        Token::Number(2),
+        Token::Multiply,
+        Token::Number(3),
+        Token::EndStatement,
+        Token::Ident(2),
+        Token::Line(6),
+        Token::Loop,
+        Token::Colon,
+        Token::EndStatement,
+    ];
+    for token in &expected {
+        assert_eq!(Some(token), tokens.next().as_ref());
     }
-    tokens.push(Token::EndStatement);
+    assert_eq!(None, tokens.next());
 }
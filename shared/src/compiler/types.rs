@@ -0,0 +1,126 @@
+use super::ast::Ast;
+use super::Error;
+use crate::natives::{self, Signature, Type};
+use alloc::vec::Vec;
+
+/// Checks that every argument of a call has the type the native expects, that a variable keeps
+/// the type of its first assignment, and that strings are only passed to natives.
+///
+/// Runs before the optimizer, so dead code is checked as well. Calls of unknown natives and with
+/// the wrong amount of arguments are left for `ir::lower` to report.
+///
+/// Returns the type of every variable, for the debug info.
+pub fn check<'a>(ast: &Ast<'a>, natives: &[Signature]) -> Result<Vec<(&'a str, Type)>, Error<'a>> {
+    let mut checker = Checker {
+        natives,
+        variables: Vec::new(),
+    };
+    checker.check(ast)?;
+    Ok(checker.variables)
+}
+
+struct Checker<'a, 'n> {
+    natives: &'n [Signature<'n>],
+    variables: Vec<(&'a str, Type)>,
+}
+
+impl<'a> Checker<'a, '_> {
+    fn check(&mut self, ast: &Ast<'a>) -> Result<(), Error<'a>> {
+        match ast {
+            Ast::Assign { var_name, rhs } => {
+                self.check(rhs)?;
+                self.assign(var_name, self.type_of(rhs))?;
+            }
+            Ast::Method { method_name, args } => {
+                for arg in args.iter().filter(|arg| !matches!(arg, Ast::String(_))) {
+                    self.check(arg)?;
+                }
+                if let Some((_, signature)) = natives::find(self.natives, method_name) {
+                    for (index, (arg, expected)) in args.iter().zip(signature.args).enumerate() {
+                        let found = self.type_of(arg);
+                        if found != *expected {
+                            return Err(Error::ArgumentType {
+                                method: method_name,
+                                index,
+                                expected: *expected,
+                                found,
+                            });
+                        }
+                    }
+                }
+            }
+            Ast::Expression { left, right, .. } => {
+                self.check(left)?;
+                self.check(right)?;
+            }
+            Ast::Not { value } | Ast::Const { value, .. } => self.check(value)?,
+            Ast::For {
+                var_name,
+                start,
+                end,
+                statements,
+            } => {
+                self.check(start)?;
+                self.check(end)?;
+                self.assign(var_name, Type::Number)?;
+                self.check_all(statements)?;
+            }
+            Ast::If {
+                condition,
+                statements,
+            } => {
+                self.check(condition)?;
+                self.check_all(statements)?;
+            }
+            Ast::Loop { statements } | Ast::OnReload { statements } | Ast::Block { statements } => {
+                self.check_all(statements)?
+            }
+            Ast::String(_) => return Err(Error::MisplacedString),
+            Ast::ConstantNum(_) | Ast::Variable { .. } | Ast::Line(_) => {}
+        }
+        Ok(())
+    }
+
+    fn check_all(&mut self, statements: &[Ast<'a>]) -> Result<(), Error<'a>> {
+        statements
+            .iter()
+            .try_for_each(|statement| self.check(statement))
+    }
+
+    /// Gives `name` the type `ty`, or checks that it already has it.
+    fn assign(&mut self, name: &'a str, ty: Type) -> Result<(), Error<'a>> {
+        if ty == Type::String {
+            return Err(Error::MisplacedString);
+        }
+        match self.variables.iter().find(|(other, _)| *other == name) {
+            Some((_, expected)) if *expected != ty => Err(Error::VariableType {
+                name,
+                expected: *expected,
+                found: ty,
+            }),
+            Some(_) => Ok(()),
+            None => {
+                self.variables.push((name, ty));
+                Ok(())
+            }
+        }
+    }
+
+    /// The type of the value of an expression. Only variables and calls can be buffers, the
+    /// result of an operation is always a number.
+    fn type_of(&self, ast: &Ast<'a>) -> Type {
+        let ty = match ast {
+            Ast::Variable { name } => self
+                .variables
+                .iter()
+                .find(|(other, _)| other == name)
+                .map(|(_, ty)| *ty),
+            Ast::Method { method_name, .. } => {
+                natives::find(self.natives, method_name).and_then(|(_, signature)| signature.result)
+            }
+            Ast::String(_) => Some(Type::String),
+            _ => None,
+        };
+        ty.unwrap_or(Type::Number)
+    }
+}
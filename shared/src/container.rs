@@ -0,0 +1,209 @@
+//! The format that bytecode is stored and uploaded in.
+//!
+//! ```text
+//! offset  size  field
+//!      0     4  magic, `ESLB`
+//!      4     1  format version
+//!      5     1  reserved, 0
+//!      6     2  required features, see `features`
+//!      8     2  amount of variable slots used
+//!     10     2  entry point, offset into the code
+//!     12     2  length of the code
+//!     14     4  hash of the natives the code was compiled against, see `natives::manifest`
+//!     18     4  CRC32 of the header up to here, followed by the rest of the container
+//!     22        code
+//! ```
+//!
+//! With `features::CONSTANT_POOL`, the code is followed by the length of the constant pool as
+//! 2 bytes and the pool itself, see `pool`.
+//!
+//! All numbers are big endian, like the rest of the bytecode.
+//!
+//! The container can be followed by debug info, see `debug_info`.
+
+use byteorder::{ByteOrder, NetworkEndian};
+
+pub const MAGIC: [u8; 4] = *b"ESLB";
+pub const VERSION: u8 = 6;
+pub const HEADER_SIZE: usize = 22;
+const CHECKSUM_OFFSET: usize = 18;
+
+/// Flags for `Header::required_features`. A runtime refuses to run bytecode that requires a
+/// feature it does not know.
+pub mod features {
+    /// The code is followed by a constant pool.
+    pub const CONSTANT_POOL: u16 = 1 << 0;
+
+    /// Every feature this version of the runtime supports.
+    pub const SUPPORTED: u16 = CONSTANT_POOL;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub required_features: u16,
+    pub variable_count: u16,
+    pub entry_point: u16,
+    pub code_len: u16,
+    /// The `manifest::hash` of the natives the code calls. The runtime refuses to load the code
+    /// if its own natives have another hash.
+    pub natives_hash: u32,
+    /// Length of the constant pool, only stored with `features::CONSTANT_POOL`.
+    pub pool_len: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The buffer is shorter than the header says it should be.
+    Truncated,
+    /// The buffer does not start with `MAGIC`.
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The bytecode needs these features, which this runtime does not support.
+    UnsupportedFeatures(u16),
+    /// The bytecode uses more variable slots than the runtime has.
+    TooManyVariables(u16),
+    EntryPointOutOfRange(u16),
+    /// The bytecode got corrupted, or was not completely uploaded.
+    ChecksumMismatch {
+        expected: u32,
+        found: u32,
+    },
+}
+
+impl Header {
+    /// Writes the header, including the checksum, to the start of `buffer`. The code has to be
+    /// written to `buffer[HEADER_SIZE..]` before calling this, and the constant pool to
+    /// `buffer[self.pool_range()]`.
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[..4].copy_from_slice(&MAGIC);
+        buffer[4] = VERSION;
+        buffer[5] = 0;
+        NetworkEndian::write_u16(&mut buffer[6..], self.required_features);
+        NetworkEndian::write_u16(&mut buffer[8..], self.variable_count);
+        NetworkEndian::write_u16(&mut buffer[10..], self.entry_point);
+        NetworkEndian::write_u16(&mut buffer[12..], self.code_len);
+        NetworkEndian::write_u32(&mut buffer[14..], self.natives_hash);
+        if self.has_pool() {
+            NetworkEndian::write_u16(&mut buffer[self.code_range().end..], self.pool_len);
+        }
+        let checksum = checksum(&buffer[..self.len()]);
+        NetworkEndian::write_u32(&mut buffer[CHECKSUM_OFFSET..], checksum);
+    }
+
+    /// Reads and validates the header at the start of `buffer`.
+    pub fn read(buffer: &[u8]) -> Result<Self, Error> {
+        if buffer.len() < HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+        if buffer[..4] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if buffer[4] != VERSION {
+            return Err(Error::UnsupportedVersion(buffer[4]));
+        }
+        let mut header = Header {
+            required_features: NetworkEndian::read_u16(&buffer[6..]),
+            variable_count: NetworkEndian::read_u16(&buffer[8..]),
+            entry_point: NetworkEndian::read_u16(&buffer[10..]),
+            code_len: NetworkEndian::read_u16(&buffer[12..]),
+            natives_hash: NetworkEndian::read_u32(&buffer[14..]),
+            pool_len: 0,
+        };
+        if header.has_pool() {
+            let pool_len = buffer
+                .get(header.code_range().end..header.code_range().end + 2)
+                .ok_or(Error::Truncated)?;
+            header.pool_len = NetworkEndian::read_u16(pool_len);
+        }
+        if buffer.len() < header.len() {
+            return Err(Error::Truncated);
+        }
+        let expected = NetworkEndian::read_u32(&buffer[CHECKSUM_OFFSET..]);
+        let found = checksum(&buffer[..header.len()]);
+        if expected != found {
+            return Err(Error::ChecksumMismatch { expected, found });
+        }
+        if header.required_features & !features::SUPPORTED != 0 {
+            return Err(Error::UnsupportedFeatures(
+                header.required_features & !features::SUPPORTED,
+            ));
+        }
+        if header.variable_count as usize > crate::runtime::VARIABLE_COUNT {
+            return Err(Error::TooManyVariables(header.variable_count));
+        }
+        if header.entry_point > header.code_len {
+            return Err(Error::EntryPointOutOfRange(header.entry_point));
+        }
+        Ok(header)
+    }
+
+    /// The range of the container that holds the code.
+    pub fn code_range(&self) -> core::ops::Range<usize> {
+        HEADER_SIZE..HEADER_SIZE + self.code_len as usize
+    }
+
+    pub fn has_pool(&self) -> bool {
+        self.required_features & features::CONSTANT_POOL != 0
+    }
+
+    /// The range of the container that holds the constant pool, empty if there is none.
+    pub fn pool_range(&self) -> core::ops::Range<usize> {
+        let start = self.code_range().end + if self.has_pool() { 2 } else { 0 };
+        start..start + self.pool_len as usize
+    }
+
+    /// The length of the whole container, which is where debug info starts.
+    pub fn len(&self) -> usize {
+        self.pool_range().end
+    }
+}
+
+/// The checksum stored in the header of a container that `Header::read` accepted. It covers
+/// everything up to the debug info, so it identifies the bytecode.
+pub fn stored_checksum(container: &[u8]) -> u32 {
+    NetworkEndian::read_u32(&container[CHECKSUM_OFFSET..])
+}
+
+/// Checksum of a whole container, skipping the checksum itself.
+fn checksum(container: &[u8]) -> u32 {
+    let crc = crc32_update(!0, &container[..CHECKSUM_OFFSET]);
+    !crc32_update(crc, &container[HEADER_SIZE..])
+}
+
+/// Bitwise CRC-32 (IEEE), slow but without a lookup table taking up flash.
+pub(crate) fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    crc
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(0xCBF4_3926, !crc32_update(!0, b"123456789"));
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_validate_container() {
+    let mut bytecode = [0u8; 256];
+    let len = crate::compiler::compile(
+        "buffer = get_bit_buffer(5)\nset_frame_buffer(buffer)",
+        &mut bytecode,
+    )
+    .unwrap();
+    let header = Header::read(&bytecode[..len]).unwrap();
+    assert_eq!(len, header.len());
+
+    assert_eq!(Err(Error::Truncated), Header::read(&bytecode[..len - 1]));
+    bytecode[len - 1] ^= 0x10;
+    assert!(matches!(
+        Header::read(&bytecode[..len]),
+        Err(Error::ChecksumMismatch { .. })
+    ));
+    bytecode[0] = b'X';
+    assert_eq!(Err(Error::BadMagic), Header::read(&bytecode[..len]));
+}
@@ -0,0 +1,321 @@
+//! Optional section after the end of a container, mapping the bytecode back to the script.
+//!
+//! ```text
+//! offset  size  field
+//!      0     4  magic, `ESLD`
+//!      4     2  length of the line table
+//!      6     2  length of the variable table
+//!      8     4  CRC32 of the rest of the section
+//!     12     2  offset of the `on_reload:` handler, 0xFFFF if there is none
+//!     14        line table, followed by the variable table
+//! ```
+//!
+//! The line table has an entry for the first instruction of every script line, in order of
+//! offset. An entry is the offset and the line, each as the difference with the previous entry
+//! in LEB128. The line is zigzag encoded first, as it goes back at the end of a loop.
+//!
+//! The variable table has the slot, the `Type::index` of the type, the length of the name and the
+//! name of every variable.
+//!
+//! `Runtime::reload` uses the names and types to carry variables over to new bytecode, and
+//! continues at the `on_reload:` handler. Both are only known from the debug info.
+//!
+//! The checksum in the container header does not cover this section, so it can be stripped for
+//! release by cutting the container off at `Header::len`, see `strip`.
+
+use crate::container::{self, crc32_update, Header};
+use crate::natives::Type;
+use byteorder::{ByteOrder, NetworkEndian};
+
+pub const MAGIC: [u8; 4] = *b"ESLD";
+const HEADER_SIZE: usize = 14;
+/// Stored as the handler offset when the script has no `on_reload:` handler.
+const NO_RELOAD_ENTRY: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The section is shorter than its header says it should be.
+    Truncated,
+    ChecksumMismatch {
+        expected: u32,
+        found: u32,
+    },
+    /// One of the tables can not be decoded.
+    Malformed,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DebugInfo<'a> {
+    reload_entry: u16,
+    lines: &'a [u8],
+    variables: &'a [u8],
+}
+
+impl<'a> DebugInfo<'a> {
+    /// Reads the debug info from `section`, the bytes following a container.
+    ///
+    /// Returns `None` if there is no debug info. Anything after the container that does not start
+    /// with `MAGIC` is not debug info, so a container can be stored in a larger buffer.
+    pub fn read(section: &'a [u8]) -> Result<Option<Self>, Error> {
+        if !section.starts_with(&MAGIC) {
+            return Ok(None);
+        }
+        if section.len() < HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+        let lines_len = NetworkEndian::read_u16(&section[4..]) as usize;
+        let variables_len = NetworkEndian::read_u16(&section[6..]) as usize;
+        let tables = section
+            .get(HEADER_SIZE..HEADER_SIZE + lines_len + variables_len)
+            .ok_or(Error::Truncated)?;
+        let expected = NetworkEndian::read_u32(&section[8..]);
+        let found = !crc32_update(!0, &section[12..HEADER_SIZE + tables.len()]);
+        if expected != found {
+            return Err(Error::ChecksumMismatch { expected, found });
+        }
+
+        let (lines, variables) = tables.split_at(lines_len);
+        let debug_info = DebugInfo {
+            reload_entry: NetworkEndian::read_u16(&section[12..]),
+            lines,
+            variables,
+        };
+        // Check the tables once, so iterating over them can not fail
+        let mut lines = debug_info.lines();
+        while !lines.data.is_empty() {
+            lines.decode().ok_or(Error::Malformed)?;
+        }
+        let mut variables = debug_info.variables();
+        while !variables.data.is_empty() {
+            variables.decode().ok_or(Error::Malformed)?;
+        }
+        Ok(Some(debug_info))
+    }
+
+    /// The offset of the code of the `on_reload:` handler, if the script has one.
+    pub fn reload_entry(&self) -> Option<u16> {
+        Some(self.reload_entry).filter(|entry| *entry != NO_RELOAD_ENTRY)
+    }
+
+    /// The offset and script line of the first instruction of every line, in order of offset.
+    pub fn lines(&self) -> Lines<'a> {
+        Lines {
+            data: self.lines,
+            offset: 0,
+            line: 0,
+        }
+    }
+
+    /// The script line the instruction at `offset` comes from.
+    pub fn line(&self, offset: usize) -> Option<u32> {
+        self.lines()
+            .take_while(|(start, _)| *start as usize <= offset)
+            .last()
+            .map(|(_, line)| line)
+    }
+
+    /// The slot, type and name of every variable in the script.
+    pub fn variables(&self) -> Variables<'a> {
+        Variables {
+            data: self.variables,
+        }
+    }
+
+    pub fn variable_name(&self, slot: u8) -> Option<&'a str> {
+        self.variables()
+            .find(|(variable, ..)| *variable == slot)
+            .map(|(.., name)| name)
+    }
+}
+
+pub struct Lines<'a> {
+    data: &'a [u8],
+    offset: u16,
+    line: u32,
+}
+
+impl Lines<'_> {
+    fn decode(&mut self) -> Option<(u16, u32)> {
+        let offset = read_leb128(&mut self.data)?;
+        let line = read_leb128(&mut self.data)?;
+        self.offset = self.offset.wrapping_add(offset as u16);
+        self.line = self
+            .line
+            .wrapping_add((((line >> 1) as i32) ^ -((line & 1) as i32)) as u32);
+        Some((self.offset, self.line))
+    }
+}
+
+impl Iterator for Lines<'_> {
+    type Item = (u16, u32);
+
+    fn next(&mut self) -> Option<(u16, u32)> {
+        self.decode()
+    }
+}
+
+pub struct Variables<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Variables<'a> {
+    fn decode(&mut self) -> Option<(u8, Type, &'a str)> {
+        let (&slot, rest) = self.data.split_first()?;
+        let (&ty, rest) = rest.split_first()?;
+        let (&len, rest) = rest.split_first()?;
+        let name = rest.get(..len as usize)?;
+        self.data = &rest[len as usize..];
+        Some((
+            slot,
+            Type::from_index(ty)?,
+            core::str::from_utf8(name).ok()?,
+        ))
+    }
+}
+
+impl<'a> Iterator for Variables<'a> {
+    type Item = (u8, Type, &'a str);
+
+    fn next(&mut self) -> Option<(u8, Type, &'a str)> {
+        self.decode()
+    }
+}
+
+/// Writes debug info to the start of `buffer`, which should directly follow the container.
+/// Returns the amount of bytes written, or `None` if they do not fit.
+///
+/// Names are cut off at 255 bytes.
+pub fn write<'n>(
+    buffer: &mut [u8],
+    reload_entry: Option<u16>,
+    lines: impl IntoIterator<Item = (u16, u32)>,
+    variables: impl IntoIterator<Item = (u8, Type, &'n str)>,
+) -> Option<usize> {
+    if buffer.len() < HEADER_SIZE {
+        return None;
+    }
+    let mut len = HEADER_SIZE;
+    let (mut previous_offset, mut previous_line) = (0u16, 0u32);
+    for (offset, line) in lines {
+        let line_delta = line.wrapping_sub(previous_line) as i32;
+        len += write_leb128(
+            buffer.get_mut(len..)?,
+            offset.wrapping_sub(previous_offset) as u32,
+        )?;
+        len += write_leb128(
+            buffer.get_mut(len..)?,
+            ((line_delta << 1) ^ (line_delta >> 31)) as u32,
+        )?;
+        previous_offset = offset;
+        previous_line = line;
+    }
+    let lines_len = len - HEADER_SIZE;
+
+    for (slot, ty, mut name) in variables {
+        if name.len() > u8::MAX as usize {
+            let mut end = u8::MAX as usize;
+            while !name.is_char_boundary(end) {
+                end -= 1;
+            }
+            name = &name[..end];
+        }
+        let entry = buffer.get_mut(len..len + 3 + name.len())?;
+        entry[0] = slot;
+        entry[1] = ty.index();
+        entry[2] = name.len() as u8;
+        entry[3..].copy_from_slice(name.as_bytes());
+        len += entry.len();
+    }
+    let variables_len = len - HEADER_SIZE - lines_len;
+    if lines_len > u16::MAX as usize || variables_len > u16::MAX as usize {
+        return None;
+    }
+
+    buffer[..4].copy_from_slice(&MAGIC);
+    NetworkEndian::write_u16(&mut buffer[4..], lines_len as u16);
+    NetworkEndian::write_u16(&mut buffer[6..], variables_len as u16);
+    NetworkEndian::write_u16(&mut buffer[12..], reload_entry.unwrap_or(NO_RELOAD_ENTRY));
+    let checksum = !crc32_update(!0, &buffer[12..len]);
+    NetworkEndian::write_u32(&mut buffer[8..], checksum);
+    Some(len)
+}
+
+/// The length of the container without its debug info.
+pub fn strip(bytecode: &[u8]) -> Result<usize, container::Error> {
+    Header::read(bytecode).map(|header| header.len())
+}
+
+fn write_leb128(buffer: &mut [u8], mut value: u32) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let byte = buffer.get_mut(len)?;
+        *byte = (value & 0x7F) as u8;
+        value >>= 7;
+        len += 1;
+        if value == 0 {
+            return Some(len);
+        }
+        *byte |= 0x80;
+    }
+}
+
+fn read_leb128(data: &mut &[u8]) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..32).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= ((byte & 0x7F) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_debug_info() {
+    let script = r#"
+buffer = get_bit_buffer(10)
+
+for x in 0, 10:
+    set_bit_buffer_index(buffer, x)
+set_frame_buffer(buffer)
+"#;
+    let mut bytecode = [0u8; 256];
+    let options = crate::compiler::Options {
+        optimize: true,
+        debug_info: true,
+        ..Default::default()
+    };
+    let len = crate::compiler::compile_with_options(script, &mut bytecode, &options).unwrap();
+    let header = Header::read(&bytecode[..len]).unwrap();
+    let section = &bytecode[header.len()..len];
+    let debug_info = DebugInfo::read(section).unwrap().unwrap();
+
+    // get_bit_buffer, the start of the loop, its body, the increment and set_frame_buffer
+    let lines: std::vec::Vec<_> = debug_info.lines().collect();
+    assert_eq!(
+        std::vec![(0x00, 2), (0x05, 4), (0x0F, 5), (0x16, 4), (0x1E, 6)],
+        lines
+    );
+    assert_eq!(Some(5), debug_info.line(0x10));
+    assert_eq!(Some("buffer"), debug_info.variable_name(0));
+    assert_eq!(Some("x"), debug_info.variable_name(1));
+    assert_eq!(
+        Some((0, Type::Buffer, "buffer")),
+        debug_info.variables().next()
+    );
+
+    let stripped = strip(&bytecode[..len]).unwrap();
+    assert!(stripped < len);
+    assert!(DebugInfo::read(&bytecode[stripped..stripped])
+        .unwrap()
+        .is_none());
+
+    bytecode[len - 1] ^= 1;
+    assert!(matches!(
+        DebugInfo::read(&bytecode[stripped..len]),
+        Err(Error::ChecksumMismatch { .. })
+    ));
+}
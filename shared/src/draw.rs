@@ -0,0 +1,328 @@
+//! Lines, rectangles, circles and copies on the pixel buffers of a script.
+//!
+//! A buffer is drawn on as rows of pixels, with pixel `(x, y)` at index `y * width + x`, the
+//! layout `xy_to_buffer_index` uses. The drawing natives take the width of the screen, `blit`
+//! takes the width of its source. The last row can be shorter than the others.
+//!
+//! Shapes are clipped to the buffer, so they can be partly or completely off it. A native costs
+//! the same fuel however many pixels it draws, so coordinates and sizes are limited to
+//! `MAX_COORDINATE` to bound the work a call does.
+
+use crate::font::Font;
+use crate::pixel::PixelFormat;
+use crate::runtime::TrapKind;
+use core::convert::TryFrom;
+
+/// The furthest from 0 a coordinate or size can be.
+pub const MAX_COORDINATE: i32 = i16::MAX as i32;
+
+/// A buffer to draw on.
+pub struct Canvas<'b> {
+    format: PixelFormat,
+    width: usize,
+    pixels: usize,
+    data: &'b mut [u8],
+}
+
+impl<'b> Canvas<'b> {
+    /// Draws on the `pixels` pixels in `data` in rows of `width`. Traps with `DivisionByZero` if
+    /// the width is 0, like `xy_to_buffer_index`.
+    pub fn new(
+        format: PixelFormat,
+        width: usize,
+        pixels: usize,
+        data: &'b mut [u8],
+    ) -> Result<Self, TrapKind> {
+        if width == 0 {
+            return Err(TrapKind::DivisionByZero);
+        }
+        Ok(Self {
+            format,
+            width,
+            pixels,
+            data,
+        })
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// The amount of rows, counting a last row that is not full.
+    pub fn height(&self) -> usize {
+        self.pixels.div_ceil(self.width)
+    }
+
+    /// The index of pixel `(x, y)`, if it is in the buffer.
+    fn index(&self, x: i32, y: i32) -> Option<i32> {
+        let (x, y) = (usize::try_from(x).ok()?, usize::try_from(y).ok()?);
+        if x >= self.width {
+            return None;
+        }
+        let index = y.checked_mul(self.width)?.checked_add(x)?;
+        if index >= self.pixels {
+            return None;
+        }
+        i32::try_from(index).ok()
+    }
+
+    /// The colour of pixel `(x, y)`, or black outside of the buffer.
+    pub fn get(&self, x: i32, y: i32) -> u32 {
+        self.index(x, y)
+            .and_then(|index| self.format.get(self.data, self.pixels, index).ok())
+            .unwrap_or(0)
+    }
+
+    /// Sets pixel `(x, y)`, if it is in the buffer.
+    pub fn plot(&mut self, x: i32, y: i32, colour: i32) -> Result<(), TrapKind> {
+        match self.index(x, y) {
+            Some(index) => self.format.set(self.data, self.pixels, index, colour),
+            None => self.format.colour(colour).map(|_| ()),
+        }
+    }
+
+    /// Sets every pixel to black.
+    pub fn clear(&mut self) {
+        self.data.fill(0);
+    }
+
+    /// Swaps black and white, and every colour with its opposite.
+    pub fn invert(&mut self) {
+        let max = self.format.max();
+        for index in 0..self.pixels as i32 {
+            if let Ok(value) = self.format.get(self.data, self.pixels, index) {
+                let _ = self
+                    .format
+                    .set(self.data, self.pixels, index, (value ^ max) as i32);
+            }
+        }
+    }
+
+    /// Draws a line from `(x0, y0)` to `(x1, y1)`, both ends included.
+    pub fn line(
+        &mut self,
+        (mut x0, mut y0): (i32, i32),
+        (x1, y1): (i32, i32),
+        colour: i32,
+    ) -> Result<(), TrapKind> {
+        check_coordinates(&[x0, y0, x1, y1])?;
+        self.format.colour(colour)?;
+        // Bresenham's algorithm, which steps along both axes with the error in integers
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (step_x, step_y) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let mut error = dx + dy;
+        loop {
+            self.plot(x0, y0, colour)?;
+            if x0 == x1 && y0 == y1 {
+                return Ok(());
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x0 += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y0 += step_y;
+            }
+        }
+    }
+
+    /// Draws the outline of the `width` by `height` rectangle with its top left at `(x, y)`.
+    /// Nothing is drawn if the width or height is not positive.
+    pub fn rect(
+        &mut self,
+        (x, y): (i32, i32),
+        (width, height): (i32, i32),
+        colour: i32,
+    ) -> Result<(), TrapKind> {
+        check_coordinates(&[x, y, width, height])?;
+        if width <= 0 || height <= 0 {
+            return self.format.colour(colour).map(|_| ());
+        }
+        self.fill_rect((x, y), (width, 1), colour)?;
+        self.fill_rect((x, y + height - 1), (width, 1), colour)?;
+        self.fill_rect((x, y), (1, height), colour)?;
+        self.fill_rect((x + width - 1, y), (1, height), colour)
+    }
+
+    /// Fills the `width` by `height` rectangle with its top left at `(x, y)`.
+    pub fn fill_rect(
+        &mut self,
+        (x, y): (i32, i32),
+        (width, height): (i32, i32),
+        colour: i32,
+    ) -> Result<(), TrapKind> {
+        check_coordinates(&[x, y, width, height])?;
+        self.format.colour(colour)?;
+        // only the part of the rectangle in the buffer is visited
+        let (start_x, end_x) = (x.max(0), (x + width).min(self.width as i32));
+        let (start_y, end_y) = (y.max(0), (y + height).min(self.height() as i32));
+        for y in start_y..end_y {
+            for x in start_x..end_x {
+                self.plot(x, y, colour)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws the outline of the circle around `(x, y)`. A radius of 0 draws the centre, a
+    /// negative radius nothing.
+    pub fn circle(&mut self, (x, y): (i32, i32), radius: i32, colour: i32) -> Result<(), TrapKind> {
+        check_coordinates(&[x, y, radius])?;
+        self.format.colour(colour)?;
+        // the midpoint algorithm, which draws an eighth of the circle and mirrors it
+        let (mut dx, mut dy) = (radius, 0);
+        let mut error = 1 - radius;
+        while dx >= dy {
+            for (px, py) in [(dx, dy), (dy, dx)] {
+                self.plot(x + px, y + py, colour)?;
+                self.plot(x - px, y + py, colour)?;
+                self.plot(x + px, y - py, colour)?;
+                self.plot(x - px, y - py, colour)?;
+            }
+            dy += 1;
+            if error < 0 {
+                error += 2 * dy + 1;
+            } else {
+                dx -= 1;
+                error += 2 * (dy - dx) + 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies every pixel of `source` onto this buffer, with the top left of the source at
+    /// `(x, y)`. The colours are copied as they are, so the source should have the format of
+    /// this buffer.
+    pub fn blit(&mut self, source: &Canvas, (x, y): (i32, i32)) -> Result<(), TrapKind> {
+        check_coordinates(&[x, y])?;
+        for index in 0..source.pixels {
+            let (source_x, source_y) = (index % source.width, index / source.width);
+            let (target_x, target_y) = (x + source_x as i32, y + source_y as i32);
+            if self.index(target_x, target_y).is_some() {
+                let value = source.get(source_x as i32, source_y as i32);
+                self.plot(target_x, target_y, value as i32)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws `text` with its top left at `(x, y)`, leaving the pixels around the glyphs as they
+    /// are. Returns the width of the text, see `Font::text_width`.
+    pub fn text(
+        &mut self,
+        font: Font,
+        (x, y): (i32, i32),
+        text: &str,
+        colour: i32,
+    ) -> Result<i32, TrapKind> {
+        check_coordinates(&[x, y])?;
+        self.format.colour(colour)?;
+        for (idx, c) in text.chars().enumerate() {
+            let left = x + idx as i32 * (font.width() + 1);
+            for (column, bits) in font.glyph(c).iter().enumerate() {
+                for row in 0..font.height() {
+                    if bits >> row & 1 != 0 {
+                        self.plot(left + column as i32, y + row, colour)?;
+                    }
+                }
+            }
+        }
+        Ok(font.text_width(text))
+    }
+
+    /// Moves every pixel `dx` to the right and `dy` down. Pixels moved off the buffer are lost
+    /// and the pixels left behind are black.
+    pub fn scroll(&mut self, dx: i32, dy: i32) -> Result<(), TrapKind> {
+        check_coordinates(&[dx, dy])?;
+        // every pixel is read from `offset` pixels before it, so the pixels are visited in the
+        // direction that reads each one before it is written. A pixel read from outside of the
+        // buffer, including from another row, is black.
+        let offset = dy as i64 * self.width as i64 + dx as i64;
+        for step in 0..self.pixels {
+            let index = if offset > 0 {
+                self.pixels - 1 - step
+            } else {
+                step
+            };
+            let (x, y) = ((index % self.width) as i32, (index / self.width) as i32);
+            let value = self.get(x - dx, y - dy);
+            self.plot(x, y, value as i32)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_coordinates(values: &[i32]) -> Result<(), TrapKind> {
+    match values
+        .iter()
+        .find(|value| value.unsigned_abs() > MAX_COORDINATE as u32)
+    {
+        Some(value) => Err(TrapKind::InvalidCoordinate(*value)),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn test_canvas() {
+    use PixelFormat::{Grey8, Mono};
+
+    // 5 by 4 pixels, one bit each
+    let mut data = [0u8; 3];
+    let mut canvas = Canvas::new(Mono, 5, 20, &mut data).unwrap();
+    let rows = |canvas: &Canvas| {
+        let mut rows = [0u8; 4];
+        for (y, row) in rows.iter_mut().enumerate() {
+            for x in 0..5 {
+                *row = *row << 1 | canvas.get(x, y as i32) as u8;
+            }
+        }
+        rows
+    };
+
+    canvas.line((0, 0), (4, 3), 1).unwrap();
+    assert_eq!([0b10000, 0b01000, 0b00110, 0b00001], rows(&canvas));
+    canvas.clear();
+    canvas.rect((1, 0), (3, 3), 1).unwrap();
+    assert_eq!([0b01110, 0b01010, 0b01110, 0], rows(&canvas));
+    canvas.invert();
+    assert_eq!([0b10001, 0b10101, 0b10001, 0b11111], rows(&canvas));
+    canvas.clear();
+    canvas.fill_rect((-2, 2), (4, 9), 1).unwrap();
+    assert_eq!([0, 0, 0b11000, 0b11000], rows(&canvas));
+    canvas.scroll(2, -1).unwrap();
+    assert_eq!([0, 0b00110, 0b00110, 0], rows(&canvas));
+    canvas.clear();
+    canvas.circle((2, 1), 1, 1).unwrap();
+    assert_eq!([0b00100, 0b01010, 0b00100, 0], rows(&canvas));
+
+    assert_eq!(
+        Err(TrapKind::InvalidCoordinate(-40000)),
+        canvas.line((0, 0), (-40000, 0), 1)
+    );
+    assert_eq!(
+        Err(TrapKind::InvalidColour(2)),
+        canvas.fill_rect((9, 9), (1, 1), 2)
+    );
+    assert_eq!(
+        Err(TrapKind::DivisionByZero),
+        Canvas::new(Mono, 0, 20, &mut [0; 3]).map(|_| ())
+    );
+
+    canvas.clear();
+    assert_eq!(Ok(7), canvas.text(Font::Small, (-2, 0), "-1", 1));
+    assert_eq!([0b00010, 0b00110, 0b10010, 0b00010], rows(&canvas));
+
+    // a 2 by 2 sprite, copied partly off the bottom right
+    let mut sprite_data = [0xFF, 0x80, 0x80, 0xFF];
+    let sprite = Canvas::new(Grey8, 2, 4, &mut sprite_data).unwrap();
+    let mut data = [0u8; 9];
+    let mut target = Canvas::new(Grey8, 3, 9, &mut data).unwrap();
+    target.blit(&sprite, (1, 2)).unwrap();
+    assert_eq!([0, 0, 0, 0, 0, 0, 0, 0xFF, 0x80], data);
+}
@@ -1,27 +1,229 @@
-use crate::runtime::Runtime;
+use crate::natives::{MAX_ARGS, MAX_NATIVES};
+use crate::runtime::{Runtime, TrapKind};
 use crate::traits::State;
 use byteorder::{ByteOrder, NetworkEndian};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instructions {
     CallMethod {
         // 0x01
         result_variable: VariableRef,
         method: MethodRef,
-        args: [VariableRef; 3],
+        args: [VariableRef; MAX_ARGS],
     },
     CompareEquals {
         // 0x02
         left: VariableRef,
         right: VariableRef,
     },
+    CompareLessThan {
+        // 0x03
+        left: VariableRef,
+        right: VariableRef,
+    },
+    CompareLessOrEqualTo {
+        // 0x04
+        left: VariableRef,
+        right: VariableRef,
+    },
+    Move {
+        // 0x05
+        target: u8,
+        value: VariableRef,
+    },
+    Add {
+        // 0x06
+        target: u8,
+        left: VariableRef,
+        right: VariableRef,
+    },
+    Subtract {
+        // 0x07
+        target: u8,
+        left: VariableRef,
+        right: VariableRef,
+    },
+    Multiply {
+        // 0x08
+        target: u8,
+        left: VariableRef,
+        right: VariableRef,
+    },
+    ShiftLeft {
+        // 0x09
+        target: u8,
+        left: VariableRef,
+        right: VariableRef,
+    },
+    Jump {
+        // 0x0A
+        target: u16,
+    },
+    /// Jumps if the last compare instruction was true
+    JumpIfTrue {
+        // 0x0B
+        target: u16,
+    },
+    /// Jumps if the last compare instruction was false
+    JumpIfFalse {
+        // 0x0C
+        target: u16,
+    },
 }
 
 impl Instructions {
+    /// Decodes the instruction at the start of `buffer`.
+    ///
+    /// Panics on invalid bytecode, so this should only be used on bytecode that went through
+    /// `verifier::verify`.
     pub fn get(buffer: &[u8]) -> Self {
-        unimplemented!()
+        match Self::decode(buffer) {
+            Ok(instruction) => instruction,
+            Err(e) => panic!("Invalid instruction: {:?}", e),
+        }
     }
 
-    pub fn write(buffer: &mut [u8]) {}
+    /// Decodes the instruction at the start of `buffer`, checking that it is valid.
+    pub fn decode(buffer: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { buffer, offset: 0 };
+        Ok(match reader.u8()? {
+            0x01 => {
+                let result_variable = reader.variable()?;
+                let method = MethodRef::decode(&mut reader)?;
+                let mut args = [VariableRef::None; MAX_ARGS];
+                for arg in args.iter_mut().take(method.arg_len()) {
+                    *arg = reader.variable()?;
+                }
+                Instructions::CallMethod {
+                    result_variable,
+                    method,
+                    args,
+                }
+            }
+            0x02 => Instructions::CompareEquals {
+                left: reader.variable()?,
+                right: reader.variable()?,
+            },
+            0x03 => Instructions::CompareLessThan {
+                left: reader.variable()?,
+                right: reader.variable()?,
+            },
+            0x04 => Instructions::CompareLessOrEqualTo {
+                left: reader.variable()?,
+                right: reader.variable()?,
+            },
+            0x05 => Instructions::Move {
+                target: reader.u8()?,
+                value: reader.variable()?,
+            },
+            0x06 => Instructions::Add {
+                target: reader.u8()?,
+                left: reader.variable()?,
+                right: reader.variable()?,
+            },
+            0x07 => Instructions::Subtract {
+                target: reader.u8()?,
+                left: reader.variable()?,
+                right: reader.variable()?,
+            },
+            0x08 => Instructions::Multiply {
+                target: reader.u8()?,
+                left: reader.variable()?,
+                right: reader.variable()?,
+            },
+            0x09 => Instructions::ShiftLeft {
+                target: reader.u8()?,
+                left: reader.variable()?,
+                right: reader.variable()?,
+            },
+            0x0A => Instructions::Jump {
+                target: reader.u16()?,
+            },
+            0x0B => Instructions::JumpIfTrue {
+                target: reader.u16()?,
+            },
+            0x0C => Instructions::JumpIfFalse {
+                target: reader.u16()?,
+            },
+            x => return Err(DecodeError::InvalidOpcode(x)),
+        })
+    }
+
+    pub const fn opcode(&self) -> u8 {
+        match self {
+            Instructions::CallMethod { .. } => 0x01,
+            Instructions::CompareEquals { .. } => 0x02,
+            Instructions::CompareLessThan { .. } => 0x03,
+            Instructions::CompareLessOrEqualTo { .. } => 0x04,
+            Instructions::Move { .. } => 0x05,
+            Instructions::Add { .. } => 0x06,
+            Instructions::Subtract { .. } => 0x07,
+            Instructions::Multiply { .. } => 0x08,
+            Instructions::ShiftLeft { .. } => 0x09,
+            Instructions::Jump { .. } => 0x0A,
+            Instructions::JumpIfTrue { .. } => 0x0B,
+            Instructions::JumpIfFalse { .. } => 0x0C,
+        }
+    }
+
+    pub fn write(&self, buffer: &mut [u8]) {
+        buffer[0] = self.opcode();
+        match self {
+            Instructions::CallMethod {
+                result_variable,
+                method,
+                args,
+            } => {
+                result_variable.write(&mut buffer[1..]);
+                let mut offset = 1 + result_variable.size();
+                method.write(&mut buffer[offset..]);
+                offset += method.size();
+                for arg in args.iter().take(method.arg_len()) {
+                    arg.write(&mut buffer[offset..]);
+                    offset += arg.size();
+                }
+            }
+            Instructions::CompareEquals { left, right }
+            | Instructions::CompareLessThan { left, right }
+            | Instructions::CompareLessOrEqualTo { left, right } => {
+                left.write(&mut buffer[1..]);
+                right.write(&mut buffer[1 + left.size()..]);
+            }
+            Instructions::Move { target, value } => {
+                buffer[1] = *target;
+                value.write(&mut buffer[2..]);
+            }
+            Instructions::Add {
+                target,
+                left,
+                right,
+            }
+            | Instructions::Subtract {
+                target,
+                left,
+                right,
+            }
+            | Instructions::Multiply {
+                target,
+                left,
+                right,
+            }
+            | Instructions::ShiftLeft {
+                target,
+                left,
+                right,
+            } => {
+                buffer[1] = *target;
+                left.write(&mut buffer[2..]);
+                right.write(&mut buffer[2 + left.size()..]);
+            }
+            Instructions::Jump { target }
+            | Instructions::JumpIfTrue { target }
+            | Instructions::JumpIfFalse { target } => {
+                NetworkEndian::write_u16(&mut buffer[1..], *target);
+            }
+        }
+    }
 
     pub fn size(&self) -> usize {
         match self {
@@ -31,114 +233,423 @@ impl Instructions {
                 args,
             } => {
                 let mut result = 1 + result_variable.size() + method.size();
-                for idx in 0..method.arg_len() {
-                    result += args[idx].size()
+                for arg in args.iter().take(method.arg_len()) {
+                    result += arg.size()
                 }
                 result
             }
-            Instructions::CompareEquals { left, right } => 1 + left.size() + right.size(),
+            Instructions::CompareEquals { left, right }
+            | Instructions::CompareLessThan { left, right }
+            | Instructions::CompareLessOrEqualTo { left, right } => 1 + left.size() + right.size(),
+            Instructions::Move { value, .. } => 2 + value.size(),
+            Instructions::Add { left, right, .. }
+            | Instructions::Subtract { left, right, .. }
+            | Instructions::Multiply { left, right, .. }
+            | Instructions::ShiftLeft { left, right, .. } => 2 + left.size() + right.size(),
+            Instructions::Jump { .. }
+            | Instructions::JumpIfTrue { .. }
+            | Instructions::JumpIfFalse { .. } => 3,
+        }
+    }
+
+    /// The highest `cost` of any instruction.
+    pub const MAX_COST: u32 = 4;
+
+    /// How much fuel `Runtime::run` spends on this instruction. A method call costs more, as it
+    /// does more work and calls into the host.
+    pub const fn cost(&self) -> u32 {
+        match self {
+            Instructions::CallMethod { .. } => Self::MAX_COST,
+            _ => 1,
+        }
+    }
+
+    pub fn execute<S: State>(&self, runtime: &mut Runtime<S>) -> Result<(), TrapKind> {
+        match self {
+            Instructions::CallMethod {
+                result_variable,
+                method,
+                args,
+            } => {
+                let mut values = [0; MAX_ARGS];
+                for (value, arg) in values.iter_mut().zip(args) {
+                    *value = match arg {
+                        // strings are passed by the index of their constant
+                        VariableRef::Const(idx) if runtime.string(*idx as i32).is_ok() => {
+                            *idx as i32
+                        }
+                        arg => runtime.get_value(arg)?,
+                    };
+                }
+                let native = runtime
+                    .natives
+                    .get(method.index() as usize)
+                    .ok_or(TrapKind::UnknownNative(method.index()))?;
+                let result = (native.handler)(runtime, values)?;
+                if let VariableRef::Idx(idx) = result_variable {
+                    runtime.set_value(*idx, result)?;
+                }
+            }
+            Instructions::CompareEquals { left, right } => {
+                runtime.compare_flag = runtime.get_value(left)? == runtime.get_value(right)?;
+            }
+            Instructions::CompareLessThan { left, right } => {
+                runtime.compare_flag = runtime.get_value(left)? < runtime.get_value(right)?;
+            }
+            Instructions::CompareLessOrEqualTo { left, right } => {
+                runtime.compare_flag = runtime.get_value(left)? <= runtime.get_value(right)?;
+            }
+            Instructions::Move { target, value } => {
+                runtime.set_value(*target, runtime.get_value(value)?)?;
+            }
+            Instructions::Add {
+                target,
+                left,
+                right,
+            } => {
+                let value = runtime
+                    .get_value(left)?
+                    .wrapping_add(runtime.get_value(right)?);
+                runtime.set_value(*target, value)?;
+            }
+            Instructions::Subtract {
+                target,
+                left,
+                right,
+            } => {
+                let value = runtime
+                    .get_value(left)?
+                    .wrapping_sub(runtime.get_value(right)?);
+                runtime.set_value(*target, value)?;
+            }
+            Instructions::Multiply {
+                target,
+                left,
+                right,
+            } => {
+                let value = runtime
+                    .get_value(left)?
+                    .wrapping_mul(runtime.get_value(right)?);
+                runtime.set_value(*target, value)?;
+            }
+            Instructions::ShiftLeft {
+                target,
+                left,
+                right,
+            } => {
+                let value = runtime
+                    .get_value(left)?
+                    .wrapping_shl(runtime.get_value(right)? as u32);
+                runtime.set_value(*target, value)?;
+            }
+            Instructions::Jump { target } => runtime.program_counter = *target as usize,
+            Instructions::JumpIfTrue { target } => {
+                if runtime.compare_flag {
+                    runtime.program_counter = *target as usize;
+                }
+            }
+            Instructions::JumpIfFalse { target } => {
+                if !runtime.compare_flag {
+                    runtime.program_counter = *target as usize;
+                }
+            }
         }
+        Ok(())
     }
+}
 
-    pub fn execute<S: State>(&self, runtime: &mut Runtime<S>) {}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytecode ends in the middle of an instruction.
+    Truncated,
+    InvalidOpcode(u8),
+    InvalidVariableRef(u8),
+    /// A call with more than `MAX_ARGS` arguments, or with a count byte it did not need.
+    InvalidMethodRef(u8),
 }
 
+struct Reader<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], DecodeError> {
+        let bytes = self
+            .buffer
+            .get(self.offset..self.offset + len)
+            .ok_or(DecodeError::Truncated)?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(NetworkEndian::read_u16(self.bytes(2)?))
+    }
+
+    fn variable(&mut self) -> Result<VariableRef, DecodeError> {
+        let start = self.offset;
+        let tag = self.u8()?;
+        let variable = match tag {
+            0x00 => VariableRef::None,
+            0x01 => VariableRef::Idx(self.u8()?),
+            0x02 => VariableRef::Num(NetworkEndian::read_i32(self.bytes(4)?)),
+            0x03 => VariableRef::Float(NetworkEndian::read_f32(self.bytes(4)?)),
+            0x04 => VariableRef::Const(self.u8()?),
+            0x05 => VariableRef::Num(self.leb128()?),
+            SMALL_NUM_TAG..=0xFF => VariableRef::Num((tag - SMALL_NUM_TAG) as i32 + SMALL_NUM_MIN),
+            x => return Err(DecodeError::InvalidVariableRef(x)),
+        };
+        // Every number has a single encoding, so `size` always matches the bytes that were read
+        if variable.size() != self.offset - start {
+            return Err(DecodeError::InvalidVariableRef(tag));
+        }
+        Ok(variable)
+    }
+
+    /// A zigzag encoded LEB128 number. Numbers that need more than 3 bytes use the fixed size
+    /// encoding instead.
+    fn leb128(&mut self) -> Result<i32, DecodeError> {
+        let mut value = 0u32;
+        for shift in [0, 7, 14] {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok((value >> 1) as i32 ^ -((value & 1) as i32));
+            }
+        }
+        Err(DecodeError::InvalidVariableRef(0x05))
+    }
+}
+
+/// The tag of the first number that is encoded in the tag itself, `SMALL_NUM_MIN`. Every tag
+/// from here up to `0xFF` is a number, up to `SMALL_NUM_MAX`.
+const SMALL_NUM_TAG: u8 = 0x80;
+const SMALL_NUM_MIN: i32 = -16;
+const SMALL_NUM_MAX: i32 = 111;
+
+/// A value an instruction reads.
+///
+/// Numbers are written in the smallest of three encodings: in the tag itself for
+/// `SMALL_NUM_MIN..=SMALL_NUM_MAX`, as zigzag encoded LEB128 if that takes at most 3 bytes, or
+/// as 4 bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum VariableRef {
     None,
     Idx(u8),
     Num(i32),
     Float(f32),
+    /// Index of a number in the constant pool.
+    Const(u8),
 }
 
 impl VariableRef {
-    const fn size(&self) -> usize {
+    pub const fn size(&self) -> usize {
         match self {
             VariableRef::None => 1,
             VariableRef::Idx(_) => 2,
-            VariableRef::Num(_) => 5,
+            VariableRef::Num(num) => {
+                if *num >= SMALL_NUM_MIN && *num <= SMALL_NUM_MAX {
+                    1
+                } else if leb128_len(zigzag(*num)) <= 3 {
+                    1 + leb128_len(zigzag(*num))
+                } else {
+                    5
+                }
+            }
             VariableRef::Float(_) => 5,
+            VariableRef::Const(_) => 2,
         }
     }
 
-    fn write(&self, buffer: &mut [u8]) {
+    pub fn write(&self, buffer: &mut [u8]) {
         match self {
             VariableRef::None => buffer[0] = 0x00,
             VariableRef::Idx(idx) => {
                 buffer[0] = 0x01;
                 buffer[1] = *idx;
             }
-            VariableRef::Num(num) => {
-                buffer[0] = 0x02;
-                NetworkEndian::write_i32(&mut buffer[1..], *num);
-            }
+            VariableRef::Num(num) => match self.size() {
+                1 => buffer[0] = (*num - SMALL_NUM_MIN) as u8 + SMALL_NUM_TAG,
+                5 => {
+                    buffer[0] = 0x02;
+                    NetworkEndian::write_i32(&mut buffer[1..], *num);
+                }
+                size => {
+                    buffer[0] = 0x05;
+                    let mut value = zigzag(*num);
+                    for byte in &mut buffer[1..size] {
+                        *byte = (value & 0x7F) as u8 | 0x80;
+                        value >>= 7;
+                    }
+                    buffer[size - 1] &= 0x7F;
+                }
+            },
             VariableRef::Float(num) => {
                 buffer[0] = 0x03;
                 NetworkEndian::write_f32(&mut buffer[1..], *num);
             }
+            VariableRef::Const(idx) => {
+                buffer[0] = 0x04;
+                buffer[1] = *idx;
+            }
         }
     }
 }
 
-#[repr(C)]
-pub enum MethodRef {
-    /// get_bit_buffer(buffer_size) -> ref
-    GetBitBuffer = 0x01,
-
-    /// fill_random_bit_buffer(buffer_ref)
-    FillRandomBitBuffer = 0x02,
-
-    /// set_bit_buffer_index(buffer_ref, index_ref)
-    SetBitBufferIndex = 0x03,
+const fn zigzag(num: i32) -> u32 {
+    ((num << 1) ^ (num >> 31)) as u32
+}
 
-    /// clear_bit_buffer_index(buffer_ref, index_ref)
-    ClearBitBufferIndex = 0x04,
+/// The amount of bytes `value` takes in LEB128.
+const fn leb128_len(value: u32) -> usize {
+    let bits = 32 - value.leading_zeros() as usize;
+    if bits == 0 {
+        1
+    } else {
+        bits.div_ceil(7)
+    }
+}
 
-    /// get_bit_buffer_index(buffer_ref, index_ref) -> value_ref
-    GetBitBufferIndex = 0x05,
+/// A call of a native, by its index in the natives of the host, see `natives`.
+///
+/// Stored in a single byte, with the index in the low 6 bits and the amount of arguments in the
+/// high 2, so a call can be decoded without knowing which natives the host has. Calls with 3 or
+/// more arguments have 3 in the high bits and the amount of arguments in a second byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MethodRef {
+    index: u8,
+    arg_len: u8,
+}
 
-    /// xy_to_buffer_index(x, y) -> index_ref
-    XYToBufferIndex = 0x06,
+impl MethodRef {
+    /// Returns `None` if the index is `MAX_NATIVES` or more, or there are more than `MAX_ARGS`
+    /// arguments.
+    pub const fn new(index: u8, arg_len: usize) -> Option<Self> {
+        if index as usize >= MAX_NATIVES || arg_len > MAX_ARGS {
+            return None;
+        }
+        Some(MethodRef {
+            index,
+            arg_len: arg_len as u8,
+        })
+    }
 
-    /// wait_for_clock_high()
-    WaitForClockHigh = 0x07,
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let byte = reader.u8()?;
+        let arg_len = match byte >> 6 {
+            3 => match reader.u8()? {
+                arg_len @ 3.. if arg_len as usize <= MAX_ARGS => arg_len,
+                arg_len => return Err(DecodeError::InvalidMethodRef(arg_len)),
+            },
+            arg_len => arg_len,
+        };
+        Ok(MethodRef {
+            index: byte & 0x3F,
+            arg_len,
+        })
+    }
 
-    /// wait_for_clock_low()
-    WaitForClockLow = 0x08,
+    fn write(&self, buffer: &mut [u8]) {
+        if self.arg_len < 3 {
+            buffer[0] = self.index | self.arg_len << 6;
+        } else {
+            buffer[0] = self.index | 3 << 6;
+            buffer[1] = self.arg_len;
+        }
+    }
 
-    /// set_frame_buffer(buffer)
-    SetFrameBuffer = 0x09,
-}
+    pub const fn index(&self) -> u8 {
+        self.index
+    }
 
-impl MethodRef {
     pub const fn size(&self) -> usize {
-        1
+        if self.arg_len < 3 {
+            1
+        } else {
+            2
+        }
     }
 
     pub const fn arg_len(&self) -> usize {
-        match self {
-            MethodRef::GetBitBuffer => 1,
-            MethodRef::FillRandomBitBuffer => 1,
-            MethodRef::SetBitBufferIndex => 2,
-            MethodRef::ClearBitBufferIndex => 2,
-            MethodRef::GetBitBufferIndex => 2,
-            MethodRef::XYToBufferIndex => 2,
-            MethodRef::WaitForClockHigh => 0,
-            MethodRef::WaitForClockLow => 0,
-            MethodRef::SetFrameBuffer => 1,
-        }
+        self.arg_len as usize
     }
+}
 
-    pub const fn has_result(&self) -> bool {
-        match self {
-            MethodRef::GetBitBuffer => true,
-            MethodRef::FillRandomBitBuffer => false,
-            MethodRef::SetBitBufferIndex => false,
-            MethodRef::ClearBitBufferIndex => false,
-            MethodRef::GetBitBufferIndex => true,
-            MethodRef::XYToBufferIndex => true,
-            MethodRef::WaitForClockHigh => false,
-            MethodRef::WaitForClockLow => false,
-            MethodRef::SetFrameBuffer => false,
+#[test]
+fn test_number_encoding() {
+    let decode = |bytes: &[u8]| {
+        let mut reader = Reader {
+            buffer: bytes,
+            offset: 0,
+        };
+        reader.variable()
+    };
+    for (num, size) in [
+        (0, 1),
+        (-16, 1),
+        (111, 1),
+        (112, 3),
+        (-17, 2),
+        (-64, 2),
+        (1000, 3),
+        (-1_048_576, 4),
+        (1_048_576, 5),
+        (i32::MIN, 5),
+        (i32::MAX, 5),
+    ] {
+        let variable = VariableRef::Num(num);
+        assert_eq!(size, variable.size(), "{}", num);
+        let mut buffer = [0u8; 8];
+        variable.write(&mut buffer);
+        assert_eq!(Ok(variable), decode(&buffer[..size]), "{}", num);
+    }
+
+    // Numbers written in a larger encoding than needed are rejected
+    assert_eq!(
+        Err(DecodeError::InvalidVariableRef(0x02)),
+        decode(&[0x02, 0, 0, 0, 1])
+    );
+    assert_eq!(
+        Err(DecodeError::InvalidVariableRef(0x05)),
+        decode(&[0x05, 0x80, 0x00])
+    );
+    assert_eq!(
+        Err(DecodeError::InvalidVariableRef(0x05)),
+        decode(&[0x05, 0xFF, 0xFF, 0xFF, 0x0F])
+    );
+}
+
+#[test]
+fn test_method_encoding() {
+    for (arg_len, size) in [(0, 3), (2, 7), (3, 10), (MAX_ARGS, 16)] {
+        let mut args = [VariableRef::None; MAX_ARGS];
+        for (idx, arg) in args.iter_mut().take(arg_len).enumerate() {
+            *arg = VariableRef::Idx(idx as u8);
         }
+        let instruction = Instructions::CallMethod {
+            result_variable: VariableRef::None,
+            method: MethodRef::new(5, arg_len).unwrap(),
+            args,
+        };
+        assert_eq!(size, instruction.size(), "{}", arg_len);
+        let mut buffer = [0u8; 16];
+        instruction.write(&mut buffer);
+        assert_eq!(Ok(instruction), Instructions::decode(&buffer[..size]));
     }
+    assert_eq!(None, MethodRef::new(5, MAX_ARGS + 1));
+
+    // the amount of arguments only gets its own byte when it does not fit in the first
+    assert_eq!(
+        Err(DecodeError::InvalidMethodRef(2)),
+        Instructions::decode(&[0x01, 0x00, 0xC5, 0x02, 0x00, 0x00])
+    );
+    assert_eq!(
+        Err(DecodeError::InvalidMethodRef(7)),
+        Instructions::decode(&[0x01, 0x00, 0xC5, 0x07])
+    );
 }
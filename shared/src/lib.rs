@@ -9,10 +9,33 @@ extern crate std;
 #[cfg(feature = "compiler")]
 mod compiler;
 
+pub mod asm;
+mod container;
+mod debug_info;
+mod draw;
 mod evaluator;
+mod font;
 mod instructions;
+mod memory;
+mod natives;
+mod pixel;
+mod pool;
 mod runtime;
+mod snapshot;
 mod traits;
+mod verifier;
+
+pub use crate::draw::Canvas;
+pub use crate::font::Font;
+pub use crate::memory::Memory;
+pub use crate::natives::{manifest, Native, Signature, Type, DEFAULT_SIGNATURES, MAX_ARGS};
+pub use crate::pixel::PixelFormat;
+pub use crate::runtime::{LoadError, Recovery, Runtime, Trap, TrapKind, TrapPolicy, Yield};
+pub use crate::traits::{AsyncState, State};
+pub use macros::script_api;
+
+// Lets the code generated by `script_api` refer to this crate by name in its own tests
+extern crate self as shared;
 
 #[test]
 #[cfg(feature = "compiler")]
@@ -28,21 +51,21 @@ loop:
     for x in 0,10:
         for y in 0,10:
             neighbour_count = 0
-            if get_bit_buffer_index(xy_to_buffer_index(x - 1, y - 1)):
+            if get_bit_buffer_index(buffer, xy_to_buffer_index(x - 1, y - 1)):
                 neighbour_count += 1
-            if get_bit_buffer_index(xy_to_buffer_index(x, y - 1)):
+            if get_bit_buffer_index(buffer, xy_to_buffer_index(x, y - 1)):
                 neighbour_count += 1
-            if get_bit_buffer_index(xy_to_buffer_index(x + 1, y - 1)):
+            if get_bit_buffer_index(buffer, xy_to_buffer_index(x + 1, y - 1)):
                 neighbour_count += 1
-            if get_bit_buffer_index(xy_to_buffer_index(x - 1, y)):
+            if get_bit_buffer_index(buffer, xy_to_buffer_index(x - 1, y)):
                 neighbour_count += 1
-            if get_bit_buffer_index(xy_to_buffer_index(x + 1, y)):
+            if get_bit_buffer_index(buffer, xy_to_buffer_index(x + 1, y)):
                 neighbour_count += 1
-            if get_bit_buffer_index(xy_to_buffer_index(x - 1, y + 1)):
+            if get_bit_buffer_index(buffer, xy_to_buffer_index(x - 1, y + 1)):
                 neighbour_count += 1
-            if get_bit_buffer_index(xy_to_buffer_index(x, y + 1)):
+            if get_bit_buffer_index(buffer, xy_to_buffer_index(x, y + 1)):
                 neighbour_count += 1
-            if get_bit_buffer_index(xy_to_buffer_index(x + 1, y + 1)):
+            if get_bit_buffer_index(buffer, xy_to_buffer_index(x + 1, y + 1)):
                 neighbour_count += 1
 
             idx = xy_to_buffer_index(x, y)
@@ -61,39 +84,755 @@ loop:
 "#;
     let mut bytecode = [0u8; 10 * 1024];
     let len = compiler::compile(script, &mut bytecode).unwrap();
+    // 521 bytes when every number took 5 bytes
+    assert!(len <= 445, "{} bytes", len);
     let bytecode = &mut bytecode[..len];
 
-    let mut runtime = runtime::Runtime::new(bytecode, test_state::TestState::default());
+    let mut runtime = runtime::Runtime::new(bytecode, test_state::TestState::default()).unwrap();
 
     while runtime.state.screens.is_empty() {
-        runtime.step();
+        runtime.step().unwrap();
+    }
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_control_flow() {
+    let script = r#"
+buffer = get_bit_buffer(100)
+total = 0
+for x in 0, 10:
+    if x > 2 and not x == 5:
+        total += x
+    if x * 4 < 13 or x >= 9:
+        set_bit_buffer_index(buffer, x)
+is_large = total > 30
+set_bit_buffer_index(buffer, 50 + total - is_large)
+set_frame_buffer(buffer)
+"#;
+    for optimize in [true, false] {
+        let mut bytecode = [0u8; 1024];
+        let options = compiler::Options {
+            optimize,
+            debug_info: true,
+            ..Default::default()
+        };
+        let len = compiler::compile_with_options(script, &mut bytecode, &options).unwrap();
+        let mut runtime =
+            runtime::Runtime::new(&mut bytecode[..len], test_state::TestState::default()).unwrap();
+        assert!(runtime.debug_info.is_some());
+        while runtime.program_counter < runtime.bytecode.len() {
+            runtime.step().unwrap();
+        }
+        assert_eq!(
+            vec![0b1111 | 1 << 9 | 1 << 86],
+            runtime.state.screens,
+            "optimize: {}",
+            optimize
+        );
     }
 }
 
+#[test]
+#[cfg(feature = "compiler")]
+fn test_traps() {
+    use runtime::{Trap, TrapKind, TrapPolicy};
+
+    let script = "buffer = get_bit_buffer(10)\nset_bit_buffer_index(buffer, 200)";
+    let mut bytecode = [0u8; 256];
+    let len = compiler::compile(script, &mut bytecode).unwrap();
+    let mut runtime =
+        runtime::Runtime::new(&mut bytecode[..len], test_state::TestState::default()).unwrap();
+    runtime.step().unwrap();
+    let trap = Trap {
+        program_counter: runtime.program_counter,
+        kind: TrapKind::BitIndexOutOfRange(200),
+    };
+    assert_eq!(Err(trap), runtime.step());
+    // halted, so it does not run again
+    assert_eq!(Err(trap), runtime.step());
+    assert_eq!(trap.program_counter, runtime.program_counter);
+
+    runtime.reset();
+    runtime.trap_policy = TrapPolicy::Restart;
+    runtime.step().unwrap();
+    assert_eq!(Err(trap), runtime.step());
+    assert_eq!(0, runtime.program_counter);
+    assert!(runtime.memory.is_empty());
+
+    runtime.trap_policy = TrapPolicy::Handler;
+    runtime.step().unwrap();
+    assert_eq!(Err(trap), runtime.step());
+    assert_eq!(vec![trap], runtime.state.traps);
+    // the test state restarts
+    assert_eq!(None, runtime.trap);
+    assert_eq!(0, runtime.program_counter);
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_run_with_fuel() {
+    use runtime::Yield;
+
+    let script = r#"
+buffer = get_bit_buffer(10)
+loop:
+    fill_random_bit_buffer(buffer)
+    set_frame_buffer(buffer)
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = compiler::compile(script, &mut bytecode).unwrap();
+    let mut runtime =
+        runtime::Runtime::new(&mut bytecode[..len], test_state::TestState::default()).unwrap();
+    // get_bit_buffer and fill_random_bit_buffer, with too little left for set_frame_buffer
+    assert_eq!(Yield::OutOfFuel, runtime.run(4 + 4 + 3));
+    assert!(runtime.state.screens.is_empty());
+    // set_frame_buffer, the jump back and fill_random_bit_buffer
+    assert_eq!(Yield::OutOfFuel, runtime.run(4 + 1 + 4));
+    assert_eq!(1, runtime.state.screens.len());
+    // fuel below the cost of a call still runs the call
+    assert_eq!(Yield::OutOfFuel, runtime.run(1));
+    assert_eq!(2, runtime.state.screens.len());
+
+    let mut bytecode = [0u8; 256];
+    let len = compiler::compile("buffer = get_bit_buffer(10)", &mut bytecode).unwrap();
+    let mut runtime =
+        runtime::Runtime::new(&mut bytecode[..len], test_state::TestState::default()).unwrap();
+    assert_eq!(Yield::Finished, runtime.run(100));
+    assert_eq!(Yield::Finished, runtime.run(100));
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_wait_for_clock() {
+    use runtime::Yield;
+
+    let script = r#"
+buffer = get_bit_buffer(10)
+loop:
+    wait_for_clock_high()
+    set_frame_buffer(buffer)
+    wait_for_clock_low()
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = compiler::compile(script, &mut bytecode).unwrap();
+    let mut runtime =
+        runtime::Runtime::new(&mut bytecode[..len], test_state::TestState::default()).unwrap();
+    assert_eq!(Yield::WaitClockHigh, runtime.run(100));
+    assert!(runtime.state.screens.is_empty());
+    assert_eq!(Yield::WaitClockLow, runtime.run(100));
+    assert_eq!(1, runtime.state.screens.len());
+    assert_eq!(Yield::WaitClockHigh, runtime.run(100));
+    assert_eq!(Yield::WaitClockLow, runtime.run(100));
+    assert_eq!(2, runtime.state.screens.len());
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_reload() {
+    use runtime::Yield;
+
+    let options = compiler::Options {
+        optimize: true,
+        debug_info: true,
+        ..Default::default()
+    };
+    let script = r#"
+buffer = get_bit_buffer(10)
+frame = 0
+loop:
+    wait_for_clock_high()
+    set_bit_buffer_index(buffer, frame)
+    set_frame_buffer(buffer)
+    frame += 1
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = compiler::compile_with_options(script, &mut bytecode, &options).unwrap();
+    // the variables are declared in a different order, so they end up in other slots
+    let edited = r#"
+speed = 2
+frame = 0
+buffer = get_bit_buffer(10)
+on_reload:
+    speed = 3
+    clear_bit_buffer_index(buffer, 0)
+loop:
+    wait_for_clock_high()
+    set_bit_buffer_index(buffer, frame)
+    set_frame_buffer(buffer)
+    frame += speed
+"#;
+    let mut new_bytecode = [0u8; 256];
+    let new_len = compiler::compile_with_options(edited, &mut new_bytecode, &options).unwrap();
+
+    let mut listing = std::string::String::new();
+    asm::disassemble(&new_bytecode[..new_len], None, &mut listing).unwrap();
+    assert!(listing.contains(".on_reload L"));
+    let mut assembled = [0u8; 256];
+    assert_eq!(Ok(new_len), asm::assemble(&listing, &mut assembled));
+    assert_eq!(new_bytecode[..new_len], assembled[..new_len]);
+
+    let mut runtime =
+        runtime::Runtime::new(&mut bytecode[..len], test_state::TestState::default()).unwrap();
+    for _ in 0..3 {
+        assert_eq!(Yield::WaitClockHigh, runtime.run(100));
+    }
+    assert_eq!(std::vec![0b1, 0b11], runtime.state.screens);
+
+    runtime.reload(&mut new_bytecode[..new_len]).unwrap();
+    assert_eq!(Yield::WaitClockHigh, runtime.run(100));
+    assert_eq!(Yield::WaitClockHigh, runtime.run(100));
+    assert_eq!(Some(&0b110), runtime.state.screens.last());
+    let debug_info = runtime.debug_info.unwrap();
+    let value = |name| {
+        let (slot, ..) = debug_info
+            .variables()
+            .find(|(.., other)| *other == name)
+            .unwrap();
+        runtime.variables[slot as usize]
+    };
+    assert_eq!(5, value("frame"));
+    assert_eq!(3, value("speed"));
+
+    assert_eq!(
+        Err(compiler::Error::NestedOnReload),
+        compiler::compile("loop:\n    on_reload:\n        a = 1\n", &mut [0; 64])
+    );
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_reload_without_handler() {
+    use runtime::Yield;
+
+    let options = compiler::Options {
+        debug_info: true,
+        ..Default::default()
+    };
+    let script = r#"
+count += 1
+buffer = get_bit_buffer(64)
+level = get_bit_buffer(8)
+loop:
+    wait_for_clock_high()
+    set_frame_buffer(level)
+"#;
+    let mut bytecode = [0u8; 128];
+    let len = compiler::compile_with_options(script, &mut bytecode, &options).unwrap();
+    // `level` is a number now, so it does not keep the index of its buffer
+    let edited = r#"
+count += 1
+level += 1
+loop:
+    wait_for_clock_high()
+"#;
+    let mut new_bytecode = [0u8; 128];
+    let new_len = compiler::compile_with_options(edited, &mut new_bytecode, &options).unwrap();
+
+    let mut copies = [bytecode; 10];
+    let (first, copies) = copies.split_first_mut().unwrap();
+    let mut runtime =
+        runtime::Runtime::new(&mut first[..len], test_state::TestState::default()).unwrap();
+    assert_eq!(Yield::WaitClockHigh, runtime.run(100));
+    // the script starts over, allocating its buffers again after the old ones are freed
+    for copy in copies {
+        runtime.reload(&mut copy[..len]).unwrap();
+        assert_eq!(Yield::WaitClockHigh, runtime.run(100));
+    }
+    assert_eq!(2, runtime.memory.len());
+
+    runtime.reload(&mut new_bytecode[..new_len]).unwrap();
+    assert_eq!(Yield::WaitClockHigh, runtime.run(100));
+    assert_eq!(0, runtime.memory.len());
+    let debug_info = runtime.debug_info.unwrap();
+    let value = |name| {
+        let (slot, ..) = debug_info
+            .variables()
+            .find(|(.., other)| *other == name)
+            .unwrap();
+        runtime.variables[slot as usize]
+    };
+    assert_eq!(11, value("count"));
+    assert_eq!(1, value("level"));
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_reload_new_buffer() {
+    use runtime::{Trap, TrapKind, Yield};
+
+    let options = compiler::Options {
+        debug_info: true,
+        ..Default::default()
+    };
+    let script = r#"
+buffer = get_bit_buffer(8)
+loop:
+    wait_for_clock_high()
+    set_frame_buffer(buffer)
+"#;
+    let mut bytecode = [0u8; 128];
+    let len = compiler::compile_with_options(script, &mut bytecode, &options).unwrap();
+    // the handler uses `sprite` before it is assigned, as it skips the code before the loop
+    let edited = r#"
+buffer = get_bit_buffer(8)
+sprite = get_bit_buffer(8)
+on_reload:
+    set_frame_buffer(sprite)
+loop:
+    wait_for_clock_high()
+    set_frame_buffer(buffer)
+"#;
+    let mut new_bytecode = [0u8; 128];
+    let new_len = compiler::compile_with_options(edited, &mut new_bytecode, &options).unwrap();
+
+    let mut runtime =
+        runtime::Runtime::new(&mut bytecode[..len], test_state::TestState::default()).unwrap();
+    assert_eq!(Yield::WaitClockHigh, runtime.run(100));
+    runtime.reload(&mut new_bytecode[..new_len]).unwrap();
+    assert!(matches!(
+        runtime.run(100),
+        Yield::Trapped(Trap {
+            kind: TrapKind::InvalidBuffer(-1),
+            ..
+        })
+    ));
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_natives() {
+    use natives::{Native, Signature, Type, MAX_ARGS};
+    use runtime::{LoadError, Runtime, TrapKind};
+    use test_state::TestState;
+
+    fn double(_: &mut Runtime<TestState>, args: [i32; MAX_ARGS]) -> Result<i32, TrapKind> {
+        Ok(args[0] * 2)
+    }
+    const DEFAULT_COUNT: usize = Native::<TestState>::DEFAULTS.len();
+    let mut natives = [Native::DEFAULTS[0]; DEFAULT_COUNT + 1];
+    natives[..DEFAULT_COUNT].copy_from_slice(&Native::DEFAULTS);
+    natives[DEFAULT_COUNT] = Native {
+        signature: Signature {
+            name: "double",
+            args: &[Type::Number],
+            result: Some(Type::Number),
+        },
+        handler: double,
+    };
+    // compile against the manifest of the host, like a compiler on another machine would
+    let mut text = std::string::String::new();
+    natives::manifest::write(&natives, &mut text).unwrap();
+    let manifest = natives::manifest::Manifest::parse(&text).unwrap();
+    let signatures = manifest.signatures();
+
+    let script = r#"
+buffer = get_bit_buffer(10)
+set_bit_buffer_index(buffer, double(3))
+set_frame_buffer(buffer)
+"#;
+    let mut bytecode = [0u8; 256];
+    assert_eq!(
+        Err(compiler::Error::UnknownMethod("double")),
+        compiler::compile(script, &mut bytecode)
+    );
+    let options = compiler::Options {
+        natives: &signatures,
+        ..Default::default()
+    };
+    let len = compiler::compile_with_options(script, &mut bytecode, &options).unwrap();
+
+    // the manifest has the types of the arguments too
+    assert_eq!(
+        Err(compiler::Error::ArgumentType {
+            method: "set_bit_buffer_index",
+            index: 0,
+            expected: Type::Buffer,
+            found: Type::Number,
+        }),
+        compiler::compile_with_options("set_bit_buffer_index(3, 3)", &mut [0; 64], &options)
+    );
+    assert_eq!(
+        Err(compiler::Error::VariableType {
+            name: "x",
+            expected: Type::Number,
+            found: Type::Buffer,
+        }),
+        compiler::compile_with_options("x = 1\nx = get_bit_buffer(1)", &mut [0; 64], &options)
+    );
+
+    let mut listing = std::string::String::new();
+    asm::disassemble_with_natives(&bytecode[..len], None, &signatures, &mut listing).unwrap();
+    assert!(listing.contains("call double(#3) -> %"));
+
+    assert!(matches!(
+        Runtime::new(&mut bytecode[..len], TestState::default()),
+        Err(LoadError::NativesMismatch { .. })
+    ));
+    let mut runtime =
+        Runtime::with_natives(&mut bytecode[..len], TestState::default(), &natives).unwrap();
+    while !runtime.is_finished() {
+        runtime.step().unwrap();
+    }
+    assert_eq!(std::vec![1 << 6], runtime.state.screens);
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_run_async() {
+    use core::future::Future;
+    use core::task::{Context, Poll, Waker};
+    use runtime::Yield;
+
+    /// Polls `future` until it is ready, returning its output and how often it was polled.
+    fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+        let mut future = core::pin::pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut polls = 1;
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return (output, polls);
+            }
+            polls += 1;
+        }
+    }
+
+    let script = r#"
+buffer = get_bit_buffer(10)
+for x in 0, 3:
+    wait_for_clock_high()
+    set_bit_buffer_index(buffer, x)
+    set_frame_buffer(buffer)
+    wait_for_clock_low()
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = compiler::compile(script, &mut bytecode).unwrap();
+    let mut runtime =
+        runtime::Runtime::new(&mut bytecode[..len], test_state::TestState::default()).unwrap();
+    let (stop, polls) = block_on(runtime.run_async(1000));
+    assert_eq!(Yield::Finished, stop);
+    assert_eq!(vec![0b1, 0b11, 0b111], runtime.state.screens);
+    assert_eq!(6, runtime.state.clock_waits);
+    assert_eq!(1, polls);
+
+    // Running a few instructions at a time gives the executor a turn in between
+    runtime.reset();
+    let (stop, polls) = block_on(runtime.run_async(5));
+    assert_eq!(Yield::Finished, stop);
+    assert!(polls > 3);
+
+    // Fuel below the cost of a call still runs the calls
+    runtime.reset();
+    runtime.state.screens.clear();
+    let (stop, _) = block_on(runtime.run_async(2));
+    assert_eq!(Yield::Finished, stop);
+    assert_eq!(vec![0b1, 0b11, 0b111], runtime.state.screens);
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_large_buffers() {
+    use runtime::{Runtime, TrapKind};
+    use test_state::TestState;
+
+    let script = r#"
+buffer = get_bit_buffer(64 * 32)
+set_bit_buffer_index(buffer, 64 * 32 - 1)
+other = get_bit_buffer(64 * 32)
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = compiler::compile(script, &mut bytecode).unwrap();
+    let mut copy = bytecode;
+    let mut region = [0u8; 300];
+    let mut runtime = Runtime::new(&mut bytecode[..len], TestState::default()).unwrap();
+    runtime.memory = Memory::new(&mut region);
+    let trap = loop {
+        if let Err(trap) = runtime.step() {
+            break trap;
+        }
+    };
+    assert_eq!(TrapKind::BufferTooLarge(64 * 32), trap.kind);
+    assert_eq!(Ok(0x80), runtime.memory.buffer(0).map(|buffer| buffer[255]));
+
+    // the buffer does not fit in the default memory
+    let mut snapshot = [0u8; 512];
+    let snapshot_len = runtime.snapshot(&mut snapshot).unwrap();
+    let mut restored = Runtime::new(&mut copy[..len], TestState::default()).unwrap();
+    assert_eq!(
+        Err(snapshot::Error::Invalid),
+        restored.restore(&snapshot[..snapshot_len])
+    );
+    let mut region = [0u8; 256];
+    restored.memory = Memory::new(&mut region);
+    restored.restore(&snapshot[..snapshot_len]).unwrap();
+    assert_eq!(runtime.memory.buffer(0), restored.memory.buffer(0));
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_pixel_buffers() {
+    use runtime::{Runtime, TrapKind};
+    use test_state::TestState;
+
+    let script = r#"
+const RGB565 = 2
+frame = get_pixel_buffer(RGB565, 4)
+red = colour(frame, 16711680)
+set_pixel(frame, 1, red)
+set_bit_buffer_index(frame, 3)
+if get_pixel(frame, 1) == red:
+    set_frame_buffer(frame)
+set_pixel(frame, 0, 65536)
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = compiler::compile(script, &mut bytecode).unwrap();
+    let mut runtime = Runtime::new(&mut bytecode[..len], TestState::default()).unwrap();
+    let trap = loop {
+        if let Err(trap) = runtime.step() {
+            break trap;
+        }
+    };
+    assert_eq!(TrapKind::InvalidColour(65536), trap.kind);
+    assert_eq!(
+        std::vec![(
+            PixelFormat::Rgb565,
+            std::vec![0, 0, 0xF8, 0, 0, 0, 0xFF, 0xFF]
+        )],
+        runtime.state.frames
+    );
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_drawing() {
+    use runtime::{Runtime, TrapKind};
+    use test_state::TestState;
+
+    // the test screen is 10 by 10 pixels
+    let script = r#"
+screen = get_bit_buffer(100)
+draw_line(screen, 0, 0, 9, 0, 1)
+fill_rect(screen, 0, 2, 2, 2, 1)
+draw_circle(screen, 5, 5, 2, 1)
+sprite = get_bit_buffer(4)
+fill_rect(sprite, 0, 0, 4, 1, 1)
+blit(screen, sprite, 8, 8, 2)
+scroll_buffer(screen, 0, 1)
+set_frame_buffer(screen)
+grey = get_pixel_buffer(1, 4)
+blit(screen, grey, 0, 0, 2)
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = compiler::compile(script, &mut bytecode).unwrap();
+    let mut runtime = Runtime::new(&mut bytecode[..len], TestState::default()).unwrap();
+    let trap = loop {
+        if let Err(trap) = runtime.step() {
+            break trap;
+        }
+    };
+    assert_eq!(TrapKind::FormatMismatch(2), trap.kind);
+
+    let mut pixels = std::vec![(0, 3), (1, 3), (0, 4), (1, 4), (8, 9), (9, 9)];
+    pixels.extend((0..10).map(|x| (x, 1)));
+    // the circle around (5, 6) after scrolling
+    for (dx, dy) in [(2, 0), (0, 2), (2, 1), (1, 2)] {
+        for (sx, sy) in [(1, 1), (-1, 1), (1, -1), (-1, -1)] {
+            pixels.push((5 + dx * sx, 6 + dy * sy));
+        }
+    }
+    let screen = pixels
+        .into_iter()
+        .fold(0u128, |screen, (x, y)| screen | 1 << (y * 10 + x));
+    assert_eq!(std::vec![screen], runtime.state.screens);
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_text() {
+    use runtime::Runtime;
+    use test_state::TestState;
+
+    let script = r#"
+screen = get_bit_buffer(100)
+width = draw_small_text(screen, 0, 0, "HI")
+draw_small_number(screen, width + 1, 5, 7)
+set_frame_buffer(screen)
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = compiler::compile(script, &mut bytecode).unwrap();
+    let mut runtime = Runtime::new(&mut bytecode[..len], TestState::default()).unwrap();
+    assert_eq!(runtime::Yield::Finished, runtime.run(1000));
+
+    // the 7 is cut off by the right edge of the screen
+    let rows = [
+        "#.#.###...",
+        "#.#..#....",
+        "###..#....",
+        "#.#..#....",
+        "#.#.###...",
+        "........##",
+        "..........",
+        "..........",
+        ".........#",
+        ".........#",
+    ];
+    let screen = rows
+        .iter()
+        .flat_map(|row| row.chars())
+        .enumerate()
+        .filter(|(_, c)| *c == '#')
+        .fold(0u128, |screen, (index, _)| screen | 1 << index);
+    assert_eq!(std::vec![screen], runtime.state.screens);
+
+    let error = |script| compiler::compile(script, &mut [0; 64]).err();
+    assert_eq!(
+        Some(compiler::Error::MisplacedString),
+        error("x = \"text\"")
+    );
+    assert_eq!(
+        Some(compiler::Error::MisplacedString),
+        error("set_bit_buffer_index(get_bit_buffer(1), \"text\" + 1)")
+    );
+    assert_eq!(
+        Some(compiler::Error::UnterminatedString),
+        error("draw_text(get_bit_buffer(1), 0, 0, \"text)")
+    );
+    assert_eq!(
+        Some(compiler::Error::ArgumentType {
+            method: "draw_text",
+            index: 3,
+            expected: natives::Type::String,
+            found: natives::Type::Number,
+        }),
+        error("draw_text(get_bit_buffer(1), 0, 0, 1)")
+    );
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_script_api() {
+    use natives::{manifest, Native, Type, DEFAULT_SIGNATURES};
+    use runtime::{Runtime, TrapKind};
+    use test_state::TestState;
+
+    assert_eq!(
+        "screen_count() -> number\ninvert(buffer)\npixel(buffer, number) -> number\n\
+         text_len(string) -> number\n",
+        TestState::MANIFEST
+    );
+    assert_eq!(&[Type::Buffer, Type::Number], TestState::SIGNATURES[2].args);
+
+    const DEFAULT_COUNT: usize = Native::<TestState>::DEFAULTS.len();
+    let mut natives = [Native::DEFAULTS[0]; DEFAULT_COUNT + 4];
+    natives[..DEFAULT_COUNT].copy_from_slice(&Native::DEFAULTS);
+    natives[DEFAULT_COUNT..].copy_from_slice(&TestState::NATIVES);
+
+    let mut text = std::string::String::new();
+    manifest::write(&DEFAULT_SIGNATURES, &mut text).unwrap();
+    text.push_str(TestState::MANIFEST);
+    let manifest = manifest::Manifest::parse(&text).unwrap();
+    assert_eq!(
+        manifest::hash(&natives),
+        manifest::hash(&manifest.signatures())
+    );
+
+    let script = r#"
+buffer = get_bit_buffer(8)
+invert(buffer)
+set_frame_buffer(buffer)
+if pixel(buffer, 3) == screen_count():
+    set_frame_buffer(buffer)
+if text_len("abc") == 3:
+    set_frame_buffer(buffer)
+x = pixel(buffer, 200)
+"#;
+    let options = compiler::Options {
+        natives: &manifest.signatures(),
+        ..Default::default()
+    };
+    let mut bytecode = [0u8; 256];
+    let len = compiler::compile_with_options(script, &mut bytecode, &options).unwrap();
+    let mut runtime =
+        Runtime::with_natives(&mut bytecode[..len], TestState::default(), &natives).unwrap();
+    let trap = loop {
+        if let Err(trap) = runtime.step() {
+            break trap;
+        }
+    };
+    assert_eq!(TrapKind::BitIndexOutOfRange(200), trap.kind);
+    assert_eq!(std::vec![0xFF, 0xFF, 0xFF], runtime.state.screens);
+}
+
 #[cfg(test)]
 mod test_state {
     #[derive(Default)]
     pub struct TestState {
         pub screens: Vec<u128>,
-        pub wait_clock_high_count: usize,
-        pub wait_clock_low_count: usize,
+        pub traps: Vec<crate::runtime::Trap>,
+        pub clock_waits: usize,
+        /// The frames drawn from buffers that are not 1-bit
+        pub frames: Vec<(crate::pixel::PixelFormat, Vec<u8>)>,
     }
 
     impl crate::traits::State for TestState {
-        fn fill_random_bit_buffer(&mut self, buffer: &mut u128) {
-            *buffer = 0;
-            *buffer |= 1 << 2;
-            *buffer |= 1 << 12;
-            *buffer |= 1 << 22;
+        fn fill_random_bit_buffer(&mut self, buffer: &mut [u8]) {
+            buffer.fill(0);
+            for bit in [2, 12, 22] {
+                if let Some(byte) = buffer.get_mut(bit / 8) {
+                    *byte |= 1 << (bit % 8);
+                }
+            }
+        }
+        /// Keeps the first 128 bits of the screen, which is enough for the tests.
+        fn draw_screen(&mut self, screen: &[u8]) {
+            let mut bytes = [0; 16];
+            let len = screen.len().min(16);
+            bytes[..len].copy_from_slice(&screen[..len]);
+            self.screens.push(u128::from_le_bytes(bytes));
+        }
+        fn draw_frame(&mut self, format: crate::pixel::PixelFormat, data: &[u8]) {
+            match format {
+                crate::pixel::PixelFormat::Mono => self.draw_screen(data),
+                format => self.frames.push((format, data.to_vec())),
+            }
+        }
+        fn screen_size(&self) -> (u32, u32) {
+            (10, 10)
         }
-        fn draw_screen(&mut self, screen: u128) {
-            self.screens.push(screen);
+        fn on_trap(&mut self, trap: crate::runtime::Trap) -> crate::runtime::Recovery {
+            self.traps.push(trap);
+            crate::runtime::Recovery::Restart
         }
-        fn wait_for_clock_high(&mut self) {
-            self.wait_clock_high_count += 1;
+    }
+
+    #[crate::script_api]
+    impl TestState {
+        fn screen_count(&self) -> i32 {
+            self.screens.len() as i32
+        }
+
+        fn invert(&mut self, buffer: &mut [u8]) {
+            for byte in buffer {
+                *byte = !*byte;
+            }
+        }
+
+        fn pixel(&self, buffer: &[u8], index: i32) -> Result<bool, crate::runtime::TrapKind> {
+            let pixel = crate::pixel::PixelFormat::Mono.get(buffer, buffer.len() * 8, index)?;
+            Ok(pixel != 0)
+        }
+
+        fn text_len(&self, text: &str) -> i32 {
+            text.len() as i32
+        }
+    }
+
+    impl crate::traits::AsyncState for TestState {
+        async fn wait_for_clock_high(&mut self) {
+            self.clock_waits += 1;
         }
-        fn wait_for_clock_low(&mut self) {
-            self.wait_clock_low_count += 1;
+        async fn wait_for_clock_low(&mut self) {
+            self.clock_waits += 1;
         }
     }
 }
@@ -0,0 +1,271 @@
+//! The memory that the pixel buffers of a script are allocated in.
+//!
+//! Every buffer has a `PixelFormat`, which says how many bytes it takes and how its pixels are
+//! laid out. The bits after the last pixel in the last byte of a buffer are unused. Buffers are
+//! only freed all at once, when the script starts over, so allocating one is a matter of taking
+//! the next bytes of the region.
+
+use crate::pixel::PixelFormat;
+use crate::runtime::{TrapKind, BUFFER_COUNT};
+use arrayvec::ArrayVec;
+use core::convert::TryFrom;
+
+/// The size of the memory a runtime has without `Memory::new`, enough for 8 buffers of 128 1-bit
+/// pixels.
+pub const DEFAULT_MEMORY_SIZE: usize = 128;
+
+/// The buffers of a script, in a region of memory from the host or in a small region of its
+/// own.
+pub struct Memory<'a> {
+    region: Region<'a>,
+    /// The start of every buffer in the region, with its format and amount of pixels
+    buffers: ArrayVec<[(usize, PixelFormat, usize); BUFFER_COUNT]>,
+    /// The amount of bytes of the region that buffers take up
+    used: usize,
+}
+
+enum Region<'a> {
+    Inline([u8; DEFAULT_MEMORY_SIZE]),
+    Borrowed(&'a mut [u8]),
+}
+
+impl Default for Memory<'_> {
+    fn default() -> Self {
+        Self::with_region(Region::Inline([0; DEFAULT_MEMORY_SIZE]))
+    }
+}
+
+impl<'a> Memory<'a> {
+    /// Allocates buffers in `region`, for screens that do not fit in `DEFAULT_MEMORY_SIZE`.
+    pub fn new(region: &'a mut [u8]) -> Self {
+        Self::with_region(Region::Borrowed(region))
+    }
+
+    fn with_region(region: Region<'a>) -> Self {
+        Self {
+            region,
+            buffers: ArrayVec::new(),
+            used: 0,
+        }
+    }
+
+    fn region(&self) -> &[u8] {
+        match &self.region {
+            Region::Inline(region) => region,
+            Region::Borrowed(region) => region,
+        }
+    }
+
+    fn region_mut(&mut self) -> &mut [u8] {
+        match &mut self.region {
+            Region::Inline(region) => region,
+            Region::Borrowed(region) => region,
+        }
+    }
+
+    /// The size of the region in bytes.
+    pub fn capacity(&self) -> usize {
+        self.region().len()
+    }
+
+    /// The amount of buffers allocated.
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    /// Frees every buffer.
+    pub fn clear(&mut self) {
+        self.buffers.clear();
+        self.used = 0;
+    }
+
+    /// Whether buffers of these formats and amounts of pixels can all be allocated once every
+    /// buffer is freed.
+    pub fn fits(&self, buffers: impl IntoIterator<Item = (PixelFormat, usize)>) -> bool {
+        let mut count = 0;
+        let mut used = 0usize;
+        for (format, pixels) in buffers {
+            count += 1;
+            let len = match format.checked_byte_len(pixels) {
+                Some(len) if i32::try_from(pixels).is_ok() => len,
+                _ => return false,
+            };
+            used = match used.checked_add(len) {
+                Some(used) => used,
+                None => return false,
+            };
+        }
+        count <= BUFFER_COUNT && used <= self.capacity()
+    }
+
+    /// Replaces every buffer with a copy of `buffers`, as the format, amount of pixels and bytes
+    /// of each, which `fits` accepted. Used for restoring a snapshot once it has been checked.
+    pub(crate) fn load<'b>(
+        &mut self,
+        buffers: impl IntoIterator<Item = (PixelFormat, usize, &'b [u8])>,
+    ) {
+        self.clear();
+        for (format, pixels, data) in buffers {
+            let start = self.used;
+            self.region_mut()[start..start + data.len()].copy_from_slice(data);
+            self.used += data.len();
+            self.buffers.push((start, format, pixels));
+        }
+    }
+
+    /// Allocates a buffer of `pixels` pixels with every pixel black, returning the index scripts
+    /// refer to it by.
+    pub fn allocate(&mut self, format: PixelFormat, pixels: i32) -> Result<i32, TrapKind> {
+        let len = usize::try_from(pixels)
+            .ok()
+            .and_then(|pixels| format.checked_byte_len(pixels))
+            .ok_or(TrapKind::BufferTooLarge(pixels))?;
+        if self.buffers.is_full() {
+            return Err(TrapKind::OutOfBuffers);
+        }
+        let start = self.used;
+        self.region_mut()
+            .get_mut(start..start + len)
+            .ok_or(TrapKind::BufferTooLarge(pixels))?
+            .fill(0);
+        self.used += len;
+        self.buffers.push((start, format, pixels as usize));
+        Ok(self.buffers.len() as i32 - 1)
+    }
+
+    fn range(&self, idx: i32) -> Result<core::ops::Range<usize>, TrapKind> {
+        let (start, format, pixels) = self.get(idx)?;
+        Ok(start..start + format.byte_len(pixels))
+    }
+
+    fn get(&self, idx: i32) -> Result<(usize, PixelFormat, usize), TrapKind> {
+        usize::try_from(idx)
+            .ok()
+            .and_then(|idx| self.buffers.get(idx))
+            .copied()
+            .ok_or(TrapKind::InvalidBuffer(idx))
+    }
+
+    /// The format and the amount of pixels of the buffer a script refers to by `idx`.
+    pub fn pixels(&self, idx: i32) -> Result<(PixelFormat, usize), TrapKind> {
+        let (_, format, pixels) = self.get(idx)?;
+        Ok((format, pixels))
+    }
+
+    /// Reads pixel `index` of a buffer.
+    pub fn get_pixel(&self, idx: i32, index: i32) -> Result<u32, TrapKind> {
+        let (format, pixels) = self.pixels(idx)?;
+        format.get(self.buffer(idx)?, pixels, index)
+    }
+
+    /// Sets pixel `index` of a buffer to `value`, a colour in the format of the buffer.
+    pub fn set_pixel(&mut self, idx: i32, index: i32, value: i32) -> Result<(), TrapKind> {
+        let (format, pixels) = self.pixels(idx)?;
+        format.set(self.buffer_mut(idx)?, pixels, index, value)
+    }
+
+    /// The bytes of the buffer a script refers to by `idx`.
+    pub fn buffer(&self, idx: i32) -> Result<&[u8], TrapKind> {
+        let range = self.range(idx)?;
+        Ok(&self.region()[range])
+    }
+
+    pub fn buffer_mut(&mut self, idx: i32) -> Result<&mut [u8], TrapKind> {
+        let range = self.range(idx)?;
+        Ok(&mut self.region_mut()[range])
+    }
+
+    /// Several buffers at once, for natives that take more than one. Traps with
+    /// `TrapKind::SameBuffer` if a buffer is asked for twice.
+    pub fn buffers_mut<const N: usize>(
+        &mut self,
+        indices: [i32; N],
+    ) -> Result<[&mut [u8]; N], TrapKind> {
+        let mut ranges = [(); N].map(|_| 0..0);
+        for (idx, range) in indices.iter().zip(&mut ranges) {
+            *range = self.range(*idx)?;
+        }
+        // the region is split up front to back, so the ranges have to be in order
+        let mut order = [0; N];
+        for (position, slot) in order.iter_mut().enumerate() {
+            *slot = position;
+        }
+        // empty buffers can start where another one starts, and come first
+        order.sort_unstable_by_key(|position| {
+            let range = &ranges[*position];
+            (range.start, range.end, indices[*position])
+        });
+        for pair in order.windows(2) {
+            if indices[pair[0]] == indices[pair[1]] {
+                return Err(TrapKind::SameBuffer(indices[pair[0]]));
+            }
+        }
+
+        let mut buffers = [(); N].map(|_| None);
+        let mut rest = self.region_mut();
+        let mut offset = 0;
+        for position in order {
+            let range = &ranges[position];
+            let (_, tail) = core::mem::take(&mut rest).split_at_mut(range.start - offset);
+            let (buffer, tail) = tail.split_at_mut(range.len());
+            buffers[position] = Some(buffer);
+            rest = tail;
+            offset = range.end;
+        }
+        Ok(buffers.map(|buffer| buffer.unwrap_or_default()))
+    }
+
+    /// The format, amount of pixels and bytes of every buffer, in order of their index.
+    pub fn iter(&self) -> impl Iterator<Item = (PixelFormat, usize, &[u8])> {
+        let region = self.region();
+        self.buffers.iter().map(move |(start, format, pixels)| {
+            let bytes = &region[*start..start + format.byte_len(*pixels)];
+            (*format, *pixels, bytes)
+        })
+    }
+}
+
+#[test]
+fn test_memory() {
+    use PixelFormat::{Mono, Rgb565};
+
+    let mut region = [0xAA; 10];
+    let mut memory = Memory::new(&mut region);
+    assert_eq!(Ok(0), memory.allocate(Mono, 12));
+    assert_eq!(Ok(1), memory.allocate(Rgb565, 4));
+    assert_eq!(Err(TrapKind::BufferTooLarge(9)), memory.allocate(Mono, 9));
+    assert_eq!(Err(TrapKind::BufferTooLarge(-1)), memory.allocate(Mono, -1));
+    assert_eq!(Ok(&[0, 0][..]), memory.buffer(0));
+    assert_eq!(Ok((Rgb565, 4)), memory.pixels(1));
+    assert_eq!(Err(TrapKind::InvalidBuffer(2)), memory.buffer(2));
+
+    memory.set_pixel(0, 11, 1).unwrap();
+    memory.set_pixel(1, 3, 0xFFFF).unwrap();
+    assert_eq!(
+        Err(TrapKind::BitIndexOutOfRange(12)),
+        memory.set_pixel(0, 12, 1)
+    );
+    assert_eq!(Ok(1), memory.get_pixel(0, 11));
+    let [rgb, mono] = memory.buffers_mut([1, 0]).unwrap();
+    assert_eq!((&[0, 0x08][..], 8), (&*mono, rgb.len()));
+    assert_eq!(
+        Some((Rgb565, 4, &[0, 0, 0, 0, 0, 0, 0xFF, 0xFF][..])),
+        memory.iter().nth(1)
+    );
+    assert_eq!(Err(TrapKind::SameBuffer(0)), memory.buffers_mut([0, 1, 0]));
+
+    // the memory is reused after clearing it
+    memory.clear();
+    assert_eq!(Ok(0), memory.allocate(Mono, 80));
+    assert_eq!(Ok(&[0; 10][..]), memory.buffer(0));
+    assert_eq!(Err(TrapKind::OutOfBuffers), {
+        for _ in 0..BUFFER_COUNT - 1 {
+            memory.allocate(Mono, 0).unwrap();
+        }
+        memory.allocate(Mono, 0)
+    });
+}
@@ -0,0 +1,331 @@
+//! The buffer, drawing, screen and clock functions, which every host gets with
+//! `Native::DEFAULTS`.
+
+use super::draw::{
+    blit, clear_buffer, draw_circle, draw_line, draw_number, draw_rect, draw_small_number,
+    draw_small_text, draw_text, fill_rect, invert_buffer, scroll_buffer,
+};
+use super::{Native, Signature, Type, MAX_ARGS};
+use crate::pixel::PixelFormat;
+use crate::runtime::{Runtime, TrapKind, Yield};
+use crate::traits::State;
+
+/// The amount of default natives. Natives a host adds after them start at this index.
+const DEFAULT_COUNT: usize = 25;
+
+/// The signatures of `Native::DEFAULTS`, for compiling scripts without a runtime.
+pub const DEFAULT_SIGNATURES: [Signature<'static>; DEFAULT_COUNT] = [
+    Signature {
+        name: "get_bit_buffer",
+        args: &[Type::Number],
+        result: Some(Type::Buffer),
+    },
+    Signature {
+        name: "fill_random_bit_buffer",
+        args: &[Type::Buffer],
+        result: None,
+    },
+    Signature {
+        name: "set_bit_buffer_index",
+        args: &[Type::Buffer, Type::Number],
+        result: None,
+    },
+    Signature {
+        name: "clear_bit_buffer_index",
+        args: &[Type::Buffer, Type::Number],
+        result: None,
+    },
+    Signature {
+        name: "get_bit_buffer_index",
+        args: &[Type::Buffer, Type::Number],
+        result: Some(Type::Number),
+    },
+    Signature {
+        name: "xy_to_buffer_index",
+        args: &[Type::Number, Type::Number],
+        result: Some(Type::Number),
+    },
+    Signature {
+        name: "wait_for_clock_high",
+        args: &[],
+        result: None,
+    },
+    Signature {
+        name: "wait_for_clock_low",
+        args: &[],
+        result: None,
+    },
+    Signature {
+        name: "set_frame_buffer",
+        args: &[Type::Buffer],
+        result: None,
+    },
+    Signature {
+        name: "get_pixel_buffer",
+        args: &[Type::Number, Type::Number],
+        result: Some(Type::Buffer),
+    },
+    Signature {
+        name: "set_pixel",
+        args: &[Type::Buffer, Type::Number, Type::Number],
+        result: None,
+    },
+    Signature {
+        name: "get_pixel",
+        args: &[Type::Buffer, Type::Number],
+        result: Some(Type::Number),
+    },
+    Signature {
+        name: "colour",
+        args: &[Type::Buffer, Type::Number],
+        result: Some(Type::Number),
+    },
+    Signature {
+        name: "clear_buffer",
+        args: &[Type::Buffer],
+        result: None,
+    },
+    Signature {
+        name: "invert_buffer",
+        args: &[Type::Buffer],
+        result: None,
+    },
+    Signature {
+        name: "draw_line",
+        args: &[
+            Type::Buffer,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+        ],
+        result: None,
+    },
+    Signature {
+        name: "draw_rect",
+        args: &[
+            Type::Buffer,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+        ],
+        result: None,
+    },
+    Signature {
+        name: "fill_rect",
+        args: &[
+            Type::Buffer,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+        ],
+        result: None,
+    },
+    Signature {
+        name: "draw_circle",
+        args: &[
+            Type::Buffer,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+        ],
+        result: None,
+    },
+    Signature {
+        name: "blit",
+        args: &[
+            Type::Buffer,
+            Type::Buffer,
+            Type::Number,
+            Type::Number,
+            Type::Number,
+        ],
+        result: None,
+    },
+    Signature {
+        name: "scroll_buffer",
+        args: &[Type::Buffer, Type::Number, Type::Number],
+        result: None,
+    },
+    Signature {
+        name: "draw_text",
+        args: &[Type::Buffer, Type::Number, Type::Number, Type::String],
+        result: Some(Type::Number),
+    },
+    Signature {
+        name: "draw_small_text",
+        args: &[Type::Buffer, Type::Number, Type::Number, Type::String],
+        result: Some(Type::Number),
+    },
+    Signature {
+        name: "draw_number",
+        args: &[Type::Buffer, Type::Number, Type::Number, Type::Number],
+        result: Some(Type::Number),
+    },
+    Signature {
+        name: "draw_small_number",
+        args: &[Type::Buffer, Type::Number, Type::Number, Type::Number],
+        result: Some(Type::Number),
+    },
+];
+
+impl<S: State> Native<S> {
+    pub const DEFAULTS: [Native<S>; DEFAULT_COUNT] = [
+        native(0, get_bit_buffer),
+        native(1, fill_random_bit_buffer),
+        native(2, set_bit_buffer_index),
+        native(3, clear_bit_buffer_index),
+        native(4, get_bit_buffer_index),
+        native(5, xy_to_buffer_index),
+        native(6, wait_for_clock_high),
+        native(7, wait_for_clock_low),
+        native(8, set_frame_buffer),
+        native(9, get_pixel_buffer),
+        native(10, set_pixel),
+        native(11, get_pixel),
+        native(12, colour),
+        native(13, clear_buffer),
+        native(14, invert_buffer),
+        native(15, draw_line),
+        native(16, draw_rect),
+        native(17, fill_rect),
+        native(18, draw_circle),
+        native(19, blit),
+        native(20, scroll_buffer),
+        native(21, draw_text),
+        native(22, draw_small_text),
+        native(23, draw_number),
+        native(24, draw_small_number),
+    ];
+}
+
+const fn native<S: State>(
+    index: usize,
+    handler: fn(&mut Runtime<'_, S>, [i32; MAX_ARGS]) -> Result<i32, TrapKind>,
+) -> Native<S> {
+    Native {
+        signature: DEFAULT_SIGNATURES[index],
+        handler,
+    }
+}
+
+/// get_bit_buffer(buffer_size) -> buffer
+fn get_bit_buffer<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    runtime.memory.allocate(PixelFormat::Mono, args[0])
+}
+
+/// fill_random_bit_buffer(buffer)
+fn fill_random_bit_buffer<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    let buffer = runtime.memory.buffer_mut(args[0])?;
+    runtime.state.fill_random_bit_buffer(buffer);
+    Ok(0)
+}
+
+/// set_bit_buffer_index(buffer, index), which sets the pixel to white in a colour buffer
+fn set_bit_buffer_index<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    let (format, _) = runtime.memory.pixels(args[0])?;
+    runtime
+        .memory
+        .set_pixel(args[0], args[1], format.max() as i32)?;
+    Ok(0)
+}
+
+/// clear_bit_buffer_index(buffer, index)
+fn clear_bit_buffer_index<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    runtime.memory.set_pixel(args[0], args[1], 0)?;
+    Ok(0)
+}
+
+/// get_bit_buffer_index(buffer, index) -> value, which is 1 for any colour but black
+fn get_bit_buffer_index<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    Ok((runtime.memory.get_pixel(args[0], args[1])? != 0) as i32)
+}
+
+/// xy_to_buffer_index(x, y) -> index
+fn xy_to_buffer_index<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    let (width, height) = runtime.state.screen_size();
+    let (width, height) = (width as i32, height as i32);
+    if width == 0 || height == 0 {
+        return Err(TrapKind::DivisionByZero);
+    }
+    Ok(args[1].rem_euclid(height) * width + args[0].rem_euclid(width))
+}
+
+/// wait_for_clock_high()
+fn wait_for_clock_high<S: State>(
+    runtime: &mut Runtime<S>,
+    _: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    runtime.wait = Some(Yield::WaitClockHigh);
+    Ok(0)
+}
+
+/// wait_for_clock_low()
+fn wait_for_clock_low<S: State>(
+    runtime: &mut Runtime<S>,
+    _: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    runtime.wait = Some(Yield::WaitClockLow);
+    Ok(0)
+}
+
+/// set_frame_buffer(buffer)
+fn set_frame_buffer<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    let (format, _) = runtime.memory.pixels(args[0])?;
+    let buffer = runtime.memory.buffer(args[0])?;
+    runtime.state.draw_frame(format, buffer);
+    Ok(0)
+}
+
+/// get_pixel_buffer(format, pixels) -> buffer, with the format as a `PixelFormat::index`
+fn get_pixel_buffer<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    let format = PixelFormat::from_index(args[0]).ok_or(TrapKind::InvalidPixelFormat(args[0]))?;
+    runtime.memory.allocate(format, args[1])
+}
+
+/// set_pixel(buffer, index, colour)
+fn set_pixel<S: State>(runtime: &mut Runtime<S>, args: [i32; MAX_ARGS]) -> Result<i32, TrapKind> {
+    runtime.memory.set_pixel(args[0], args[1], args[2])?;
+    Ok(0)
+}
+
+/// get_pixel(buffer, index) -> colour
+fn get_pixel<S: State>(runtime: &mut Runtime<S>, args: [i32; MAX_ARGS]) -> Result<i32, TrapKind> {
+    Ok(runtime.memory.get_pixel(args[0], args[1])? as i32)
+}
+
+/// colour(buffer, rgb) -> colour, converting `0xRRGGBB` into the format of the buffer
+fn colour<S: State>(runtime: &mut Runtime<S>, args: [i32; MAX_ARGS]) -> Result<i32, TrapKind> {
+    let (format, _) = runtime.memory.pixels(args[0])?;
+    Ok(format.from_rgb(args[1] as u32) as i32)
+}
@@ -0,0 +1,165 @@
+//! The drawing functions of `Native::DEFAULTS`, see `draw`.
+
+use super::MAX_ARGS;
+use crate::draw::Canvas;
+use crate::font::Font;
+use crate::runtime::{Runtime, TrapKind};
+use crate::traits::State;
+use arrayvec::ArrayString;
+use core::fmt::Write;
+
+/// A buffer to draw on in rows as wide as the screen.
+fn canvas<'r, S: State>(
+    runtime: &'r mut Runtime<'_, S>,
+    buffer: i32,
+) -> Result<Canvas<'r>, TrapKind> {
+    let (width, _) = runtime.state.screen_size();
+    let (format, pixels) = runtime.memory.pixels(buffer)?;
+    Canvas::new(
+        format,
+        width as usize,
+        pixels,
+        runtime.memory.buffer_mut(buffer)?,
+    )
+}
+
+/// clear_buffer(buffer)
+pub(super) fn clear_buffer<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    runtime.memory.buffer_mut(args[0])?.fill(0);
+    Ok(0)
+}
+
+/// invert_buffer(buffer)
+pub(super) fn invert_buffer<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    canvas(runtime, args[0])?.invert();
+    Ok(0)
+}
+
+/// draw_line(buffer, x0, y0, x1, y1, colour)
+pub(super) fn draw_line<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    canvas(runtime, args[0])?.line((args[1], args[2]), (args[3], args[4]), args[5])?;
+    Ok(0)
+}
+
+/// draw_rect(buffer, x, y, width, height, colour)
+pub(super) fn draw_rect<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    canvas(runtime, args[0])?.rect((args[1], args[2]), (args[3], args[4]), args[5])?;
+    Ok(0)
+}
+
+/// fill_rect(buffer, x, y, width, height, colour)
+pub(super) fn fill_rect<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    canvas(runtime, args[0])?.fill_rect((args[1], args[2]), (args[3], args[4]), args[5])?;
+    Ok(0)
+}
+
+/// draw_circle(buffer, x, y, radius, colour)
+pub(super) fn draw_circle<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    canvas(runtime, args[0])?.circle((args[1], args[2]), args[3], args[4])?;
+    Ok(0)
+}
+
+/// blit(target, source, x, y, source_width), copying `source` in rows of `source_width` onto
+/// `target` with its top left at `(x, y)`
+pub(super) fn blit<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    let [target, source, x, y, source_width, _] = args;
+    if source_width <= 0 {
+        return Err(TrapKind::InvalidCoordinate(source_width));
+    }
+    let (width, _) = runtime.state.screen_size();
+    let (target_format, target_pixels) = runtime.memory.pixels(target)?;
+    let (source_format, source_pixels) = runtime.memory.pixels(source)?;
+    if source_format != target_format {
+        return Err(TrapKind::FormatMismatch(source));
+    }
+    let [target_data, source_data] = runtime.memory.buffers_mut([target, source])?;
+    let mut target = Canvas::new(target_format, width as usize, target_pixels, target_data)?;
+    let source = Canvas::new(
+        source_format,
+        source_width as usize,
+        source_pixels,
+        source_data,
+    )?;
+    target.blit(&source, (x, y))?;
+    Ok(0)
+}
+
+/// scroll_buffer(buffer, dx, dy), moving every pixel `dx` to the right and `dy` down
+pub(super) fn scroll_buffer<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    canvas(runtime, args[0])?.scroll(args[1], args[2])?;
+    Ok(0)
+}
+
+/// Draws text in white with its top left at `(x, y)`, returning its width.
+fn text<S: State>(
+    runtime: &mut Runtime<S>,
+    font: Font,
+    [buffer, x, y]: [i32; 3],
+    text: &str,
+) -> Result<i32, TrapKind> {
+    let mut canvas = canvas(runtime, buffer)?;
+    let white = canvas.format().max() as i32;
+    canvas.text(font, (x, y), text, white)
+}
+
+/// draw_text(buffer, x, y, text) -> width, in the 5 by 7 font
+pub(super) fn draw_text<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    let string = runtime.string(args[3])?;
+    text(runtime, Font::Normal, [args[0], args[1], args[2]], string)
+}
+
+/// draw_small_text(buffer, x, y, text) -> width, in the 3 by 5 font
+pub(super) fn draw_small_text<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    let string = runtime.string(args[3])?;
+    text(runtime, Font::Small, [args[0], args[1], args[2]], string)
+}
+
+/// draw_number(buffer, x, y, number) -> width, in the 5 by 7 font
+pub(super) fn draw_number<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    let mut digits = ArrayString::<[u8; 11]>::new();
+    let _ = write!(digits, "{}", args[3]);
+    text(runtime, Font::Normal, [args[0], args[1], args[2]], &digits)
+}
+
+/// draw_small_number(buffer, x, y, number) -> width, in the 3 by 5 font
+pub(super) fn draw_small_number<S: State>(
+    runtime: &mut Runtime<S>,
+    args: [i32; MAX_ARGS],
+) -> Result<i32, TrapKind> {
+    let mut digits = ArrayString::<[u8; 11]>::new();
+    let _ = write!(digits, "{}", args[3]);
+    text(runtime, Font::Small, [args[0], args[1], args[2]], &digits)
+}
@@ -0,0 +1,216 @@
+//! A text form of the signatures of the natives of a host, for compiling scripts on another
+//! machine than the one running them.
+//!
+//! ```text
+//! ; the natives of the badge
+//! get_bit_buffer(number) -> buffer
+//! set_frame_buffer(buffer)
+//! read_button() -> number
+//! ```
+//!
+//! Every line has the signature of a native, in the order the host registers them. Empty lines
+//! and everything after a `;` are ignored. `write` creates a manifest from the natives of a host.
+//!
+//! The `hash` of the natives is stored in the container, so a runtime refuses bytecode that was
+//! compiled against other natives. It is the CRC32 of the manifest as `write` writes it, so it
+//! does not change with comments or whitespace.
+
+use super::Signature;
+#[cfg(feature = "alloc")]
+use super::{Type, MAX_ARGS, MAX_NATIVES};
+use crate::container::crc32_update;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/// Writes the manifest of `natives` to `out`, one signature per line.
+pub fn write<'n>(natives: &[impl AsRef<Signature<'n>>], out: &mut impl Write) -> fmt::Result {
+    for native in natives {
+        let signature = native.as_ref();
+        write!(out, "{}(", signature.name)?;
+        for (idx, arg) in signature.args.iter().enumerate() {
+            if idx > 0 {
+                write!(out, ", ")?;
+            }
+            write!(out, "{}", arg.name())?;
+        }
+        write!(out, ")")?;
+        if let Some(result) = signature.result {
+            write!(out, " -> {}", result.name())?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// The hash of the manifest of `natives`, without writing it out.
+pub fn hash<'n>(natives: &[impl AsRef<Signature<'n>>]) -> u32 {
+    struct Crc32(u32);
+
+    impl Write for Crc32 {
+        fn write_str(&mut self, text: &str) -> fmt::Result {
+            self.0 = crc32_update(self.0, text.as_bytes());
+            Ok(())
+        }
+    }
+
+    let mut crc = Crc32(!0);
+    // `Crc32` never fails
+    let _ = write(natives, &mut crc);
+    !crc.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error<'t> {
+    /// The line the error is on, starting at 1
+    pub line: usize,
+    pub kind: ErrorKind<'t>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind<'t> {
+    /// The line is not a signature.
+    InvalidSignature(&'t str),
+    UnknownType(&'t str),
+    /// The native takes more than `MAX_ARGS` arguments.
+    TooManyArguments,
+    /// There are more than `MAX_NATIVES` natives.
+    TooManyNatives,
+    DuplicateName(&'t str),
+}
+
+/// A manifest read from its text, see `signatures` for the natives in it.
+#[cfg(feature = "alloc")]
+pub struct Manifest<'t> {
+    /// The name, the range of `types` with the arguments, and the result of every native
+    natives: Vec<(&'t str, core::ops::Range<usize>, Option<Type>)>,
+    types: Vec<Type>,
+}
+
+#[cfg(feature = "alloc")]
+impl<'t> Manifest<'t> {
+    /// Reads the manifest in `text`, like a compiler for another machine does.
+    ///
+    /// ```
+    /// use shared::manifest::{self, Manifest};
+    /// use shared::DEFAULT_SIGNATURES;
+    ///
+    /// let mut text = String::new();
+    /// manifest::write(&DEFAULT_SIGNATURES, &mut text).unwrap();
+    /// text.push_str("read_button() -> number\n");
+    /// let manifest = Manifest::parse(&text).unwrap();
+    /// let signatures = manifest.signatures();
+    /// assert_eq!(DEFAULT_SIGNATURES.len() + 1, signatures.len());
+    /// assert_ne!(manifest::hash(&DEFAULT_SIGNATURES), manifest::hash(&signatures));
+    /// ```
+    pub fn parse(text: &'t str) -> Result<Self, Error<'t>> {
+        let mut manifest = Manifest {
+            natives: Vec::new(),
+            types: Vec::new(),
+        };
+        for (idx, line) in text.lines().enumerate() {
+            let error = |kind| Error {
+                line: idx + 1,
+                kind,
+            };
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || error(ErrorKind::InvalidSignature(line));
+            let (name, rest) = line.split_once('(').ok_or_else(invalid)?;
+            let (args, result) = rest.split_once(')').ok_or_else(invalid)?;
+            let name = name.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(invalid());
+            }
+            if manifest.natives.iter().any(|(other, ..)| *other == name) {
+                return Err(error(ErrorKind::DuplicateName(name)));
+            }
+            if manifest.natives.len() == MAX_NATIVES {
+                return Err(error(ErrorKind::TooManyNatives));
+            }
+
+            let start = manifest.types.len();
+            if !args.trim().is_empty() {
+                for arg in args.split(',') {
+                    let arg = arg.trim();
+                    let ty = Type::from_name(arg).ok_or(error(ErrorKind::UnknownType(arg)))?;
+                    manifest.types.push(ty);
+                }
+            }
+            if manifest.types.len() - start > MAX_ARGS {
+                return Err(error(ErrorKind::TooManyArguments));
+            }
+            let result = match result.trim() {
+                "" => None,
+                result => {
+                    let result = result.strip_prefix("->").ok_or_else(invalid)?.trim();
+                    Some(Type::from_name(result).ok_or(error(ErrorKind::UnknownType(result)))?)
+                }
+            };
+            let end = manifest.types.len();
+            manifest.natives.push((name, start..end, result));
+        }
+        Ok(manifest)
+    }
+
+    /// The signatures in the manifest, in order of their index.
+    pub fn signatures(&self) -> Vec<Signature<'_>> {
+        self.natives
+            .iter()
+            .map(|(name, args, result)| Signature {
+                name,
+                args: &self.types[args.clone()],
+                result: *result,
+            })
+            .collect()
+    }
+}
+
+#[test]
+#[cfg(feature = "alloc")]
+fn test_manifest() {
+    use super::DEFAULT_SIGNATURES;
+
+    let mut text = std::string::String::new();
+    write(&DEFAULT_SIGNATURES, &mut text).unwrap();
+    assert!(text.starts_with("get_bit_buffer(number) -> buffer\n"));
+    assert!(text.contains("\nset_bit_buffer_index(buffer, number)\n"));
+
+    let manifest = Manifest::parse(&text).unwrap();
+    assert_eq!(&DEFAULT_SIGNATURES[..], &manifest.signatures()[..]);
+    assert_eq!(hash(&DEFAULT_SIGNATURES), hash(&manifest.signatures()));
+
+    // comments and layout do not change the hash, but the order of the natives does
+    let text =
+        "; only a few\nget_bit_buffer( number )->buffer\n\nset_frame_buffer(buffer) ; draws\n";
+    let manifest = Manifest::parse(text).unwrap();
+    assert_eq!(
+        hash(&DEFAULT_SIGNATURES[..1]),
+        hash(&manifest.signatures()[..1])
+    );
+    assert_ne!(
+        hash(&manifest.signatures()),
+        hash(&[DEFAULT_SIGNATURES[8], DEFAULT_SIGNATURES[0]])
+    );
+
+    let error = |text| {
+        Manifest::parse(text)
+            .err()
+            .map(|error| (error.line, error.kind))
+    };
+    assert_eq!(
+        Some((2, ErrorKind::UnknownType("colour"))),
+        error("a()\nb(colour)")
+    );
+    assert_eq!(Some((2, ErrorKind::DuplicateName("a"))), error("a()\na()"));
+    assert_eq!(
+        Some((1, ErrorKind::TooManyArguments)),
+        error("a(number, number, number, number, number, number, number)")
+    );
+    assert_eq!(
+        Some((1, ErrorKind::InvalidSignature("a -> number"))),
+        error("a -> number")
+    );
+}
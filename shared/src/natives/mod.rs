@@ -0,0 +1,156 @@
+//! Functions that scripts call, provided by the host.
+//!
+//! A host registers its natives as a slice of `Native`, usually `Native::DEFAULTS` followed by
+//! its own. The compiler and assembler only need their `Signature`, to resolve a call by name
+//! into the index of the native in the slice. The runtime calls the handler at that index.
+//!
+//! ```ignore
+//! fn read_button(runtime: &mut Runtime<Board>, _: [i32; MAX_ARGS]) -> Result<i32, TrapKind> {
+//!     Ok(runtime.state.button_pressed() as i32)
+//! }
+//!
+//! const DEFAULT_COUNT: usize = Native::<Board>::DEFAULTS.len();
+//! let mut natives = [Native::DEFAULTS[0]; DEFAULT_COUNT + 1];
+//! natives[..DEFAULT_COUNT].copy_from_slice(&Native::DEFAULTS);
+//! natives[DEFAULT_COUNT] = Native {
+//!     signature: Signature {
+//!         name: "read_button",
+//!         args: &[],
+//!         result: Some(Type::Number),
+//!     },
+//!     handler: read_button,
+//! };
+//! let runtime = Runtime::with_natives(&mut bytecode, Board::new(), &natives)?;
+//! ```
+//!
+//! `#[script_api]` on an `impl` block of the state writes the handlers and signatures for its
+//! methods instead.
+//!
+//! To compile scripts for the host elsewhere, write its natives to a manifest with
+//! `manifest::write`. Bytecode records the hash of the natives it was compiled against, and the
+//! runtime refuses bytecode compiled against other natives.
+
+mod defaults;
+mod draw;
+pub mod manifest;
+
+pub use self::defaults::DEFAULT_SIGNATURES;
+
+use crate::runtime::{Runtime, TrapKind};
+use crate::traits::State;
+
+/// The most natives a script can call, as the index of a native is stored in 6 bits.
+pub const MAX_NATIVES: usize = 64;
+
+/// The most arguments a native can take, enough for a line between two points in a colour.
+pub const MAX_ARGS: usize = 6;
+
+/// The type of an argument or result. Every value is an `i32` at runtime, the type says what
+/// the native does with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Number,
+    /// The index of a bit buffer from `get_bit_buffer`
+    Buffer,
+    /// A string literal, passed as the index of its constant, see `Runtime::string`. Only
+    /// arguments can be strings.
+    String,
+}
+
+impl Type {
+    /// The name of the type in a manifest, the inverse of `from_name`.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Type::Number => "number",
+            Type::Buffer => "buffer",
+            Type::String => "string",
+        }
+    }
+
+    /// The type stored in the debug info by `index`, the order of the variants.
+    pub fn from_index(index: u8) -> Option<Self> {
+        Some(match index {
+            0 => Type::Number,
+            1 => Type::Buffer,
+            2 => Type::String,
+            _ => return None,
+        })
+    }
+
+    pub const fn index(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "number" => Type::Number,
+            "buffer" => Type::Buffer,
+            "string" => Type::String,
+            _ => return None,
+        })
+    }
+}
+
+/// How a script calls a native. Natives registered by the host have `'static` signatures, a
+/// `Manifest` borrows its signatures from the text they are read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature<'n> {
+    /// The name scripts call the native by
+    pub name: &'n str,
+    /// At most `MAX_ARGS`
+    pub args: &'n [Type],
+    /// `None` if the native does not return anything
+    pub result: Option<Type>,
+}
+
+impl<'n> AsRef<Signature<'n>> for Signature<'n> {
+    fn as_ref(&self) -> &Signature<'n> {
+        self
+    }
+}
+
+/// Runs a native with the values of its arguments, unused arguments are 0. Returns the result,
+/// or the trap to stop the script with.
+pub type Handler<S> = fn(&mut Runtime<'_, S>, [i32; MAX_ARGS]) -> Result<i32, TrapKind>;
+
+pub struct Native<S: State> {
+    pub signature: Signature<'static>,
+    pub handler: Handler<S>,
+}
+
+// Not derived, as that would require `S` to be `Clone` as well
+impl<S: State> Clone for Native<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: State> Copy for Native<S> {}
+
+impl<S: State> AsRef<Signature<'static>> for Native<S> {
+    fn as_ref(&self) -> &Signature<'static> {
+        &self.signature
+    }
+}
+
+/// The index of the native called `name`, and its signature. Natives after the first
+/// `MAX_NATIVES`, or that take more than `MAX_ARGS` arguments, can not be called.
+pub fn find<'s, 'n, N: AsRef<Signature<'n>>>(
+    natives: &'s [N],
+    name: &str,
+) -> Option<(u8, &'s Signature<'n>)> {
+    natives
+        .iter()
+        .take(MAX_NATIVES)
+        .enumerate()
+        .map(|(idx, native)| (idx as u8, native.as_ref()))
+        .find(|(_, signature)| signature.name == name && signature.args.len() <= MAX_ARGS)
+}
+
+/// The signature of the native at `index`.
+pub fn get<'s, 'n, N: AsRef<Signature<'n>>>(
+    natives: &'s [N],
+    index: u8,
+) -> Option<&'s Signature<'n>> {
+    natives.get(index as usize).map(AsRef::as_ref)
+}
@@ -0,0 +1,170 @@
+//! The formats of the pixels in a buffer.
+//!
+//! A buffer of `n` pixels takes `n * bits_per_pixel / 8` bytes, rounded up. 1-bit pixels are
+//! packed 8 to a byte, with pixel `i` in bit `i % 8` of byte `i / 8`. Larger pixels take whole
+//! bytes, in big endian like the rest of the bytecode.
+//!
+//! Scripts pick a format by its number in `get_pixel_buffer`, and pass colours as the value of a
+//! pixel in that format. `colour` converts a `0xRRGGBB` colour into a format.
+
+use crate::runtime::TrapKind;
+use core::convert::TryFrom;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 1 bit per pixel, the format of `get_bit_buffer`
+    Mono,
+    /// 8 bits of brightness per pixel
+    Grey8,
+    /// 16 bits per pixel, 5 red, 6 green and 5 blue
+    Rgb565,
+    /// 24 bits per pixel, a byte each for red, green and blue
+    Rgb888,
+}
+
+impl PixelFormat {
+    /// The format scripts refer to by `index`, the order of the variants.
+    pub fn from_index(index: i32) -> Option<Self> {
+        Some(match index {
+            0 => PixelFormat::Mono,
+            1 => PixelFormat::Grey8,
+            2 => PixelFormat::Rgb565,
+            3 => PixelFormat::Rgb888,
+            _ => return None,
+        })
+    }
+
+    pub const fn index(self) -> i32 {
+        self as i32
+    }
+
+    pub const fn bits_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Mono => 1,
+            PixelFormat::Grey8 => 8,
+            PixelFormat::Rgb565 => 16,
+            PixelFormat::Rgb888 => 24,
+        }
+    }
+
+    /// The value of a pixel with every bit set, which is white.
+    pub const fn max(self) -> u32 {
+        (1 << self.bits_per_pixel()) - 1
+    }
+
+    /// The amount of bytes `pixels` pixels take.
+    pub const fn byte_len(self, pixels: usize) -> usize {
+        (pixels * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    /// Like `byte_len`, or `None` if the amount of bits does not fit in a `usize`.
+    pub fn checked_byte_len(self, pixels: usize) -> Option<usize> {
+        pixels
+            .checked_mul(self.bits_per_pixel())
+            .map(|bits| bits.div_ceil(8))
+    }
+
+    /// Reads pixel `index` of a buffer of `pixels` pixels.
+    pub fn get(self, data: &[u8], pixels: usize, index: i32) -> Result<u32, TrapKind> {
+        let index = pixel_index(pixels, index)?;
+        let bytes = self.bits_per_pixel() / 8;
+        Ok(match self {
+            PixelFormat::Mono => (data[index / 8] >> (index % 8)) as u32 & 1,
+            _ => data[index * bytes..(index + 1) * bytes]
+                .iter()
+                .fold(0, |value, byte| value << 8 | *byte as u32),
+        })
+    }
+
+    /// Sets pixel `index` of a buffer of `pixels` pixels to `value`, trapping if the value is
+    /// not a colour in this format.
+    pub fn set(
+        self,
+        data: &mut [u8],
+        pixels: usize,
+        index: i32,
+        value: i32,
+    ) -> Result<(), TrapKind> {
+        let index = pixel_index(pixels, index)?;
+        let value = self.colour(value)?;
+        let bytes = self.bits_per_pixel() / 8;
+        match self {
+            PixelFormat::Mono => {
+                let mask = 1 << (index % 8);
+                if value != 0 {
+                    data[index / 8] |= mask;
+                } else {
+                    data[index / 8] &= !mask;
+                }
+            }
+            _ => {
+                let pixel = &mut data[index * bytes..(index + 1) * bytes];
+                for (idx, byte) in pixel.iter_mut().rev().enumerate() {
+                    *byte = (value >> (idx * 8)) as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `value` is a colour in this format.
+    pub fn colour(self, value: i32) -> Result<u32, TrapKind> {
+        u32::try_from(value)
+            .ok()
+            .filter(|value| *value <= self.max())
+            .ok_or(TrapKind::InvalidColour(value))
+    }
+
+    /// Converts a `0xRRGGBB` colour into the closest colour in this format.
+    pub fn from_rgb(self, rgb: u32) -> u32 {
+        let (r, g, b) = ((rgb >> 16) & 0xFF, (rgb >> 8) & 0xFF, rgb & 0xFF);
+        let grey = (r * 299 + g * 587 + b * 114) / 1000;
+        match self {
+            PixelFormat::Mono => (grey >= 0x80) as u32,
+            PixelFormat::Grey8 => grey,
+            PixelFormat::Rgb565 => (r >> 3) << 11 | (g >> 2) << 5 | b >> 3,
+            PixelFormat::Rgb888 => rgb & 0xFF_FFFF,
+        }
+    }
+}
+
+fn pixel_index(pixels: usize, index: i32) -> Result<usize, TrapKind> {
+    match usize::try_from(index) {
+        Ok(index) if index < pixels => Ok(index),
+        _ => Err(TrapKind::BitIndexOutOfRange(index)),
+    }
+}
+
+#[test]
+fn test_pixel_formats() {
+    let mut data = [0u8; 6];
+    PixelFormat::Mono.set(&mut data, 12, 9, 1).unwrap();
+    assert_eq!([0, 0b10], data[..2]);
+    assert_eq!(Ok(1), PixelFormat::Mono.get(&data, 12, 9));
+    assert_eq!(
+        Err(TrapKind::InvalidColour(2)),
+        PixelFormat::Mono.set(&mut data, 12, 9, 2)
+    );
+
+    PixelFormat::Rgb888.set(&mut data, 2, 1, 0x12_3456).unwrap();
+    assert_eq!([0x12, 0x34, 0x56], data[3..]);
+    assert_eq!(Ok(0x12_3456), PixelFormat::Rgb888.get(&data, 2, 1));
+    assert_eq!(
+        Err(TrapKind::BitIndexOutOfRange(2)),
+        PixelFormat::Rgb888.get(&data, 2, 2)
+    );
+    PixelFormat::Rgb565.set(&mut data, 3, 2, 0xF800).unwrap();
+    assert_eq!([0xF8, 0x00], data[4..]);
+    assert_eq!(
+        Err(TrapKind::InvalidColour(-1)),
+        PixelFormat::Grey8.set(&mut data, 6, 0, -1)
+    );
+
+    assert_eq!(6, PixelFormat::Rgb565.byte_len(3));
+    assert_eq!(2, PixelFormat::Mono.byte_len(9));
+    assert_eq!(0xF800, PixelFormat::Rgb565.from_rgb(0xFF_0000));
+    assert_eq!(0xFF, PixelFormat::Grey8.from_rgb(0xFF_FFFF));
+    assert_eq!(0, PixelFormat::Mono.from_rgb(0x00_00FF));
+    assert_eq!(Some(PixelFormat::Rgb565), PixelFormat::from_index(2));
+    assert_eq!(None, PixelFormat::from_index(4));
+}
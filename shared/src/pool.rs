@@ -0,0 +1,208 @@
+//! Constants that are stored once in the container, instead of at every instruction using them.
+//!
+//! ```text
+//! offset  size  field
+//!      0     1  amount of constants, n
+//!      1    2n  offset of every constant from the start of the pool
+//!   1+2n        the constants, each a kind followed by the value
+//! ```
+//!
+//! | kind | value                              |
+//! |------|------------------------------------|
+//! | 0x01 | number, 4 bytes                    |
+//! | 0x02 | bits, 16 bytes                     |
+//! | 0x03 | string, a length byte and UTF-8    |
+//!
+//! Instructions refer to a constant with `VariableRef::Const` and its index.
+
+use byteorder::{ByteOrder, NetworkEndian};
+use core::convert::TryFrom;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Constant<'a> {
+    Number(i32),
+    Bits(u128),
+    String(&'a str),
+}
+
+impl Constant<'_> {
+    /// The amount of bytes this constant takes up in the pool, not counting its offset.
+    pub fn size(&self) -> usize {
+        match self {
+            Constant::Number(_) => 5,
+            Constant::Bits(_) => 17,
+            Constant::String(text) => 2 + text.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConstantPool<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ConstantPool<'a> {
+    /// Reads a constant pool, returning `None` if any of its constants can not be decoded. An
+    /// empty `data` is an empty pool.
+    pub fn read(data: &'a [u8]) -> Option<Self> {
+        let pool = ConstantPool { data };
+        if data.is_empty() {
+            return Some(pool);
+        }
+        (0..pool.len()).try_for_each(|idx| pool.decode(idx).map(|_| ()))?;
+        Some(pool)
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.first().copied().unwrap_or(0) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, idx: u8) -> Option<Constant<'a>> {
+        if idx as usize >= self.len() {
+            return None;
+        }
+        self.decode(idx as usize)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Constant<'a>> + '_ {
+        (0..self.len()).filter_map(move |idx| self.decode(idx))
+    }
+
+    fn decode(&self, idx: usize) -> Option<Constant<'a>> {
+        let offset = self.data.get(1 + idx * 2..3 + idx * 2)?;
+        let entry = self.data.get(NetworkEndian::read_u16(offset) as usize..)?;
+        let (kind, value) = entry.split_first()?;
+        Some(match kind {
+            0x01 => Constant::Number(NetworkEndian::read_i32(value.get(..4)?)),
+            0x02 => Constant::Bits(NetworkEndian::read_u128(value.get(..16)?)),
+            0x03 => {
+                let (len, text) = value.split_first()?;
+                Constant::String(core::str::from_utf8(text.get(..*len as usize)?).ok()?)
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// Writes a constant pool into a buffer, one constant at a time.
+pub struct Writer<'b> {
+    buffer: &'b mut [u8],
+    count: usize,
+    written: usize,
+    len: usize,
+}
+
+impl<'b> Writer<'b> {
+    /// Starts a pool of `count` constants, returning `None` if the offsets do not fit.
+    pub fn new(buffer: &'b mut [u8], count: u8) -> Option<Self> {
+        let len = 1 + count as usize * 2;
+        *buffer.first_mut()? = count;
+        buffer.get(..len)?;
+        Some(Writer {
+            buffer,
+            count: count as usize,
+            written: 0,
+            len,
+        })
+    }
+
+    /// Adds a constant, returning `None` if it does not fit. Strings can be at most 255 bytes.
+    pub fn push(&mut self, constant: Constant) -> Option<()> {
+        assert!(self.written < self.count, "More constants than announced");
+        if self.len > u16::MAX as usize {
+            return None;
+        }
+        let entry = self.buffer.get_mut(self.len..self.len + constant.size())?;
+        match constant {
+            Constant::Number(num) => {
+                entry[0] = 0x01;
+                NetworkEndian::write_i32(&mut entry[1..], num);
+            }
+            Constant::Bits(bits) => {
+                entry[0] = 0x02;
+                NetworkEndian::write_u128(&mut entry[1..], bits);
+            }
+            Constant::String(text) => {
+                entry[0] = 0x03;
+                entry[1] = u8::try_from(text.len()).ok()?;
+                entry[2..].copy_from_slice(text.as_bytes());
+            }
+        }
+        NetworkEndian::write_u16(&mut self.buffer[1 + self.written * 2..], self.len as u16);
+        self.written += 1;
+        self.len += constant.size();
+        Some(())
+    }
+
+    /// The length of the pool, once every constant has been written.
+    pub fn finish(self) -> usize {
+        assert_eq!(self.written, self.count, "Fewer constants than announced");
+        self.len
+    }
+}
+
+#[test]
+fn test_pool() {
+    let mut buffer = [0u8; 64];
+    let mut writer = Writer::new(&mut buffer, 3).unwrap();
+    writer.push(Constant::Number(-5)).unwrap();
+    writer.push(Constant::Bits(1 << 100)).unwrap();
+    writer.push(Constant::String("héllo")).unwrap();
+    let len = writer.finish();
+    assert_eq!(1 + 3 * 2 + 5 + 17 + 8, len);
+
+    let pool = ConstantPool::read(&buffer[..len]).unwrap();
+    assert_eq!(Some(Constant::Bits(1 << 100)), pool.get(1));
+    assert_eq!(None, pool.get(3));
+    assert_eq!(
+        std::vec![
+            Constant::Number(-5),
+            Constant::Bits(1 << 100),
+            Constant::String("héllo")
+        ],
+        pool.iter().collect::<std::vec::Vec<_>>()
+    );
+    assert!(ConstantPool::read(&buffer[..len - 1]).is_none());
+    assert!(Writer::new(&mut buffer[..8], 4).is_none());
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_compiled_pool() {
+    use crate::container::Header;
+
+    let script = r#"
+buffer = get_bit_buffer(100)
+for x in 0, 10:
+    big = x * 10000000 + 10000000
+    small = big - 10000000
+    if small > 40000000 and not small == 10000000:
+        set_bit_buffer_index(buffer, x)
+set_frame_buffer(buffer)
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = crate::compiler::compile(script, &mut bytecode).unwrap();
+    let header = Header::read(&bytecode[..len]).unwrap();
+    assert!(header.has_pool());
+    let pool = ConstantPool::read(&bytecode[header.pool_range()]).unwrap();
+    assert_eq!(Some(Constant::Number(10000000)), pool.get(0));
+    assert_eq!(1, pool.len());
+    // every use takes 2 bytes instead of 5
+    let inline = [0x02, 0x00, 0x98, 0x96, 0x80];
+    let code = &bytecode[header.code_range()];
+    assert!(!code.windows(inline.len()).any(|window| window == inline));
+
+    let mut runtime = crate::runtime::Runtime::new(
+        &mut bytecode[..len],
+        crate::test_state::TestState::default(),
+    )
+    .unwrap();
+    while runtime.program_counter < runtime.bytecode.len() {
+        runtime.step().unwrap();
+    }
+    assert_eq!(std::vec![0b11111 << 5], runtime.state.screens);
+}
@@ -1,23 +1,457 @@
-use crate::traits::State;
+use crate::container::{self, Header};
+use crate::debug_info::{self, DebugInfo};
+use crate::instructions::{DecodeError, Instructions, VariableRef};
+use crate::memory::Memory;
+use crate::natives::{manifest, Native, Type};
+use crate::pool::{Constant, ConstantPool};
+use crate::traits::{AsyncState, State};
+use crate::verifier;
+use core::convert::TryFrom;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// The amount of variable slots a script can use, one for every value of `VariableRef::Idx`.
+pub const VARIABLE_COUNT: usize = 256;
+
+/// The amount of bit buffers a script can allocate with `get_bit_buffer`.
+pub const BUFFER_COUNT: usize = 8;
+
+/// Why bytecode could not be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Container(container::Error),
+    /// The constant pool has a constant that can not be decoded.
+    ConstantPool,
+    Verifier(verifier::Error),
+    DebugInfo(debug_info::Error),
+    /// The bytecode was compiled against other natives than the ones of the runtime, with the
+    /// `manifest::hash` of the natives of the runtime as `expected`.
+    NativesMismatch {
+        expected: u32,
+        found: u32,
+    },
+}
+
+impl From<container::Error> for LoadError {
+    fn from(error: container::Error) -> Self {
+        LoadError::Container(error)
+    }
+}
+
+impl From<debug_info::Error> for LoadError {
+    fn from(error: debug_info::Error) -> Self {
+        LoadError::DebugInfo(error)
+    }
+}
+
+impl From<verifier::Error> for LoadError {
+    fn from(error: verifier::Error) -> Self {
+        LoadError::Verifier(error)
+    }
+}
+
+/// Something that went wrong while running a script, at the instruction at `program_counter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trap {
+    pub program_counter: usize,
+    pub kind: TrapKind,
+}
+
+/// Why a script trapped. Verified bytecode can still trap on values that are only known while
+/// running, like a bit index. There is no call stack, so it can not overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapKind {
+    /// The instruction does not decode, which means the bytecode was changed after loading it.
+    InvalidInstruction(DecodeError),
+    /// A variable slot is used that is not counted in the header.
+    VariableOutOfRange(u8),
+    /// A constant is used that is not a number in the constant pool.
+    InvalidConstant(u8),
+    /// A native is called that the host did not register.
+    UnknownNative(u8),
+    /// A value is used as a bit buffer, but no buffer was allocated with that index.
+    InvalidBuffer(i32),
+    /// `get_bit_buffer` is called while every one of the `BUFFER_COUNT` buffers is in use.
+    OutOfBuffers,
+    /// A buffer is asked for with a negative amount of pixels, or with more than fit in the
+    /// memory that is left.
+    BufferTooLarge(i32),
+    /// `get_pixel_buffer` is asked for a format that does not exist.
+    InvalidPixelFormat(i32),
+    /// A pixel is set to a value that is not a colour in the format of the buffer.
+    InvalidColour(i32),
+    /// A native that takes several buffers is passed the same buffer twice.
+    SameBuffer(i32),
+    /// A pixel is set, cleared or read outside of a buffer.
+    BitIndexOutOfRange(i32),
+    /// A drawing native is passed a coordinate or size further than `draw::MAX_COORDINATE`
+    /// from 0, or a sprite width that is not positive.
+    InvalidCoordinate(i32),
+    /// `blit` is passed a source buffer in another format than the target.
+    FormatMismatch(i32),
+    /// `xy_to_buffer_index` or a drawing native is called while the screen has no width or
+    /// height.
+    DivisionByZero,
+}
+
+/// Why `Runtime::run` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Yield {
+    /// The end of the bytecode was reached.
+    Finished,
+    /// The fuel ran out before the script ended. Running again continues where it left off.
+    OutOfFuel,
+    /// The script called `wait_for_clock_high`. Run again once the clock is high.
+    WaitClockHigh,
+    /// The script called `wait_for_clock_low`. Run again once the clock is low.
+    WaitClockLow,
+    /// The script trapped, and was halted or restarted according to `Runtime::trap_policy`.
+    Trapped(Trap),
+}
+
+/// What the runtime does after a trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapPolicy {
+    /// Stop running the script. Every following `step` returns the same trap.
+    Halt,
+    /// Start the script over from its entry point, as if it was just loaded.
+    Restart,
+    /// Let `State::on_trap` decide between halting and restarting.
+    Handler,
+}
+
+/// The decision of `State::on_trap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    Halt,
+    Restart,
+}
+
+/// The sections of a bytecode container that passed validation.
+struct Loaded<'a> {
+    header: Header,
+    bytecode_hash: u32,
+    bytecode: &'a mut [u8],
+    constants: ConstantPool<'a>,
+    debug_info: Option<DebugInfo<'a>>,
+}
+
+impl<'a> Loaded<'a> {
+    fn read<S: State>(bytecode: &'a mut [u8], natives: &[Native<S>]) -> Result<Self, LoadError> {
+        let header = Header::read(bytecode)?;
+        let expected = manifest::hash(natives);
+        if header.natives_hash != expected {
+            return Err(LoadError::NativesMismatch {
+                expected,
+                found: header.natives_hash,
+            });
+        }
+        let bytecode_hash = container::stored_checksum(bytecode);
+        let (container, debug_section) = bytecode.split_at_mut(header.len());
+        let debug_info = DebugInfo::read(debug_section)?;
+        let (container, pool) = container.split_at_mut(header.code_range().end);
+        let constants = ConstantPool::read(&pool[pool.len() - header.pool_len as usize..])
+            .ok_or(LoadError::ConstantPool)?;
+        let bytecode = &mut container[header.code_range().start..];
+        verifier::verify(&header, bytecode, &constants, natives)?;
+        if let Some(entry) = debug_info.and_then(|debug_info| debug_info.reload_entry()) {
+            verifier::check_target(bytecode, entry).map_err(|kind| verifier::Error {
+                offset: entry as usize,
+                kind,
+            })?;
+        }
+        Ok(Loaded {
+            header,
+            bytecode_hash,
+            bytecode,
+            constants,
+            debug_info,
+        })
+    }
+}
 
 pub struct Runtime<'a, S: State> {
+    pub header: Header,
+    /// The checksum of the container, which identifies the bytecode in a snapshot.
+    pub bytecode_hash: u32,
+    /// The code section of the bytecode container
     pub bytecode: &'a mut [u8],
+    pub constants: ConstantPool<'a>,
+    /// The debug info after the code, if the container has any
+    pub debug_info: Option<DebugInfo<'a>>,
+    /// The functions the script can call, by their index
+    pub natives: &'a [Native<S>],
     pub state: S,
     pub program_counter: usize,
+    pub variables: [i32; VARIABLE_COUNT],
+    /// Result of the last compare instruction, used by the conditional jumps.
+    pub compare_flag: bool,
+    /// The buffers of the script, in an inline region of `DEFAULT_MEMORY_SIZE` bytes unless
+    /// the host replaces it with `Memory::new` before running the script.
+    pub memory: Memory<'a>,
+    /// What to do when the script traps, `TrapPolicy::Halt` by default.
+    pub trap_policy: TrapPolicy,
+    /// The trap the script halted on.
+    pub trap: Option<Trap>,
+    /// Set by the clock methods, for `run` to return.
+    pub(crate) wait: Option<Yield>,
 }
 
 impl<'a, S: State> Runtime<'a, S> {
-    pub fn new(bytecode: &'a mut [u8], state: S) -> Self {
-        Self {
+    /// Validates the bytecode container, verifies the code in it, and prepares to run it from
+    /// its entry point, with `Native::DEFAULTS` as the natives.
+    pub fn new(bytecode: &'a mut [u8], state: S) -> Result<Self, LoadError> {
+        Self::with_natives(bytecode, state, &Native::DEFAULTS)
+    }
+
+    /// Like `new`, for bytecode that was compiled against `natives`.
+    pub fn with_natives(
+        bytecode: &'a mut [u8],
+        state: S,
+        natives: &'a [Native<S>],
+    ) -> Result<Self, LoadError> {
+        let Loaded {
+            header,
+            bytecode_hash,
+            bytecode,
+            constants,
+            debug_info,
+        } = Loaded::read(bytecode, natives)?;
+        Ok(Self {
+            header,
+            bytecode_hash,
             bytecode,
+            constants,
+            debug_info,
+            natives,
             state,
-            program_counter: 0,
+            program_counter: header.entry_point as usize,
+            variables: [0; VARIABLE_COUNT],
+            compare_flag: false,
+            memory: Memory::default(),
+            trap_policy: TrapPolicy::Halt,
+            trap: None,
+            wait: None,
+        })
+    }
+
+    /// Replaces the bytecode of a running script, for trying out changes to it without starting
+    /// over.
+    ///
+    /// Variables are matched up by their name and type in the debug info, and keep their value if
+    /// the new bytecode has a variable with the same name and type. Other numbers start at 0,
+    /// which is all variables if the new bytecode has no debug info. Other buffer variables start
+    /// at -1, which is not the index of any buffer, so using them traps until they are assigned.
+    ///
+    /// The script continues at its `on_reload:` handler if it has one. The bit buffers are kept
+    /// then, so a variable that refers to a buffer still does after the reload. Otherwise the
+    /// script starts over at its entry point with its buffers freed, like `reset`, and only keeps
+    /// its numbers. If the new bytecode can not be loaded, the old one keeps running.
+    pub fn reload(&mut self, bytecode: &'a mut [u8]) -> Result<(), LoadError> {
+        let loaded = Loaded::read(bytecode, self.natives)?;
+        let reload_entry = loaded
+            .debug_info
+            .and_then(|debug_info| debug_info.reload_entry());
+        let keeps_buffers = reload_entry.is_some();
+        let mut variables = [0; VARIABLE_COUNT];
+        for (slot, ty, name) in loaded.debug_info.iter().flat_map(DebugInfo::variables) {
+            let old_slot = self
+                .debug_info
+                .filter(|_| ty != Type::Buffer || keeps_buffers)
+                .and_then(|old| {
+                    old.variables()
+                        .find(|(_, old_ty, old_name)| *old_ty == ty && *old_name == name)
+                });
+            variables[slot as usize] = match old_slot {
+                Some((old_slot, ..)) => self.variables[old_slot as usize],
+                None if ty == Type::Buffer => -1,
+                None => 0,
+            };
+        }
+
+        match reload_entry {
+            Some(entry) => self.program_counter = entry as usize,
+            None => {
+                self.program_counter = loaded.header.entry_point as usize;
+                self.memory.clear();
+            }
+        }
+        self.header = loaded.header;
+        self.bytecode_hash = loaded.bytecode_hash;
+        self.bytecode = loaded.bytecode;
+        self.constants = loaded.constants;
+        self.debug_info = loaded.debug_info;
+        self.variables = variables;
+        self.compare_flag = false;
+        self.trap = None;
+        self.wait = None;
+        Ok(())
+    }
+
+    /// Executes a single instruction. Does nothing once the end of the bytecode is reached.
+    ///
+    /// If the instruction traps, the trap is handled according to `trap_policy` and returned, so
+    /// the host can report it. A halted runtime stays on the instruction that trapped, and keeps
+    /// returning the trap.
+    ///
+    /// Waiting for the clock is left to the host, see `run`.
+    pub fn step(&mut self) -> Result<(), Trap> {
+        let result = self.step_within(u32::MAX).map(|_| ());
+        self.wait = None;
+        result
+    }
+
+    /// Executes instructions until their cost adds up to `fuel`, the script ends or it traps.
+    ///
+    /// A script that loops forever can be run a bit at a time this way, in between other work
+    /// of the host. Every instruction costs 1, and a method call costs more, see
+    /// `Instructions::cost`. An instruction that costs more than the fuel that is left is not
+    /// executed, so the next `run` starts with it. The fuel is raised to `Instructions::MAX_COST`
+    /// if it is lower, so every `run` executes at least one instruction.
+    ///
+    /// `wait_for_clock_high` and `wait_for_clock_low` return from `run` instead of blocking, so
+    /// the host can sleep until the clock changes and then run the script again.
+    pub fn run(&mut self, fuel: u32) -> Yield {
+        let mut fuel = fuel.max(Instructions::MAX_COST);
+        loop {
+            if let Some(trap) = self.trap {
+                return Yield::Trapped(trap);
+            }
+            if self.is_finished() {
+                return Yield::Finished;
+            }
+            match self.step_within(fuel) {
+                Ok(Some(cost)) => fuel -= cost,
+                Ok(None) => return Yield::OutOfFuel,
+                Err(trap) => return Yield::Trapped(trap),
+            }
+            if let Some(wait) = self.wait.take() {
+                return wait;
+            }
+        }
+    }
+
+    /// Whether the end of the bytecode has been reached.
+    pub fn is_finished(&self) -> bool {
+        self.program_counter >= self.bytecode.len()
+    }
+
+    /// Executes a single instruction if it costs at most `fuel`, returning its cost, or `None`
+    /// if it costs more.
+    fn step_within(&mut self, fuel: u32) -> Result<Option<u32>, Trap> {
+        if let Some(trap) = self.trap {
+            return Err(trap);
+        }
+        if self.is_finished() {
+            return Ok(Some(0));
+        }
+        let program_counter = self.program_counter;
+        let result = match Instructions::decode(&self.bytecode[program_counter..]) {
+            Ok(instruction) if instruction.cost() > fuel => return Ok(None),
+            Ok(instruction) => {
+                self.program_counter += instruction.size();
+                instruction.execute(self).map(|_| instruction.cost())
+            }
+            Err(error) => Err(TrapKind::InvalidInstruction(error)),
+        };
+        let trap = match result {
+            Ok(cost) => return Ok(Some(cost)),
+            Err(kind) => Trap {
+                program_counter,
+                kind,
+            },
+        };
+        let recovery = match self.trap_policy {
+            TrapPolicy::Halt => Recovery::Halt,
+            TrapPolicy::Restart => Recovery::Restart,
+            TrapPolicy::Handler => self.state.on_trap(trap),
+        };
+        match recovery {
+            Recovery::Halt => {
+                self.program_counter = program_counter;
+                self.trap = Some(trap);
+            }
+            Recovery::Restart => self.reset(),
+        }
+        Err(trap)
+    }
+
+    /// Starts the script over from its entry point, clearing every variable and buffer.
+    pub fn reset(&mut self) {
+        self.program_counter = self.header.entry_point as usize;
+        self.variables = [0; VARIABLE_COUNT];
+        self.compare_flag = false;
+        self.memory.clear();
+        self.trap = None;
+    }
+
+    pub fn get_value(&self, variable: &VariableRef) -> Result<i32, TrapKind> {
+        Ok(match variable {
+            VariableRef::None => 0,
+            VariableRef::Idx(idx) if *idx as u16 >= self.header.variable_count => {
+                return Err(TrapKind::VariableOutOfRange(*idx))
+            }
+            VariableRef::Idx(idx) => self.variables[*idx as usize],
+            VariableRef::Num(num) => *num,
+            VariableRef::Float(num) => *num as i32,
+            VariableRef::Const(idx) => match self.constants.get(*idx) {
+                Some(Constant::Number(num)) => num,
+                _ => return Err(TrapKind::InvalidConstant(*idx)),
+            },
+        })
+    }
+
+    /// The string a native that takes a `Type::String` is passed, by the index of its constant.
+    pub fn string(&self, idx: i32) -> Result<&'a str, TrapKind> {
+        match u8::try_from(idx)
+            .ok()
+            .and_then(|idx| self.constants.get(idx))
+        {
+            Some(Constant::String(text)) => Ok(text),
+            _ => Err(TrapKind::InvalidConstant(idx as u8)),
         }
     }
 
-    pub fn step(&mut self) {
-        let instruction =
-            crate::instructions::Instructions::get(&self.bytecode[self.program_counter..]);
-        instruction.execute(self);
+    pub fn set_value(&mut self, idx: u8, value: i32) -> Result<(), TrapKind> {
+        if idx as u16 >= self.header.variable_count {
+            return Err(TrapKind::VariableOutOfRange(idx));
+        }
+        self.variables[idx as usize] = value;
+        Ok(())
+    }
+}
+
+impl<S: AsyncState> Runtime<'_, S> {
+    /// Runs the script until it ends or traps, awaiting the clock with `AsyncState`.
+    ///
+    /// The script is run `fuel` at a time, giving other tasks on the executor a turn in between,
+    /// so a script that loops without waiting does not starve them. Only returns
+    /// `Yield::Finished` or `Yield::Trapped`.
+    pub async fn run_async(&mut self, fuel: u32) -> Yield {
+        loop {
+            match self.run(fuel) {
+                Yield::OutOfFuel => YieldNow(false).await,
+                Yield::WaitClockHigh => self.state.wait_for_clock_high().await,
+                Yield::WaitClockLow => self.state.wait_for_clock_low().await,
+                stop => return stop,
+            }
+        }
+    }
+}
+
+/// Returns `Pending` once, so the executor can poll other tasks first.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
     }
 }
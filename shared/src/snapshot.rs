@@ -0,0 +1,256 @@
+//! Saving the state of a running script, to continue it later or on another device.
+//!
+//! ```text
+//! offset  size  field
+//!      0     4  magic, `ESLS`
+//!      4     1  format version
+//!      5     4  checksum of the bytecode container, see `Runtime::bytecode_hash`
+//!      9     2  program counter
+//!     11     1  compare flag
+//!     12     2  amount of variables, n
+//!     14    4n  variables
+//!            1  amount of bit buffers, m
+//!               m buffers, each a byte with the `PixelFormat::index` and 4 bytes with the
+//!               amount of pixels, followed by the buffer
+//!            4  CRC32 of everything before it
+//! ```
+//!
+//! The script has no call stack, so the program counter, variables and buffers are all there
+//! is to save. A snapshot is checked before restoring it, as it may have been cut off by a
+//! power loss, and can only be restored with the bytecode it was taken from.
+
+use crate::container::crc32_update;
+use crate::pixel::PixelFormat;
+use crate::runtime::{Runtime, BUFFER_COUNT};
+use crate::traits::State;
+use crate::verifier;
+use arrayvec::ArrayVec;
+use byteorder::{ByteOrder, NetworkEndian};
+use core::ops::Range;
+
+pub const MAGIC: [u8; 4] = *b"ESLS";
+pub const VERSION: u8 = 3;
+const HEADER_SIZE: usize = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The snapshot is shorter than it says it should be.
+    Truncated,
+    /// The snapshot does not start with `MAGIC`.
+    BadMagic,
+    UnsupportedVersion(u8),
+    ChecksumMismatch {
+        expected: u32,
+        found: u32,
+    },
+    /// The snapshot was taken from different bytecode.
+    BytecodeMismatch {
+        expected: u32,
+        found: u32,
+    },
+    /// The snapshot does not fit the bytecode, like a program counter in the middle of an
+    /// instruction, or its buffers do not fit in the memory of the runtime.
+    Invalid,
+}
+
+impl<S: State> Runtime<'_, S> {
+    /// The size of the snapshot `snapshot` writes.
+    pub fn snapshot_len(&self) -> usize {
+        let buffers: usize = self.memory.iter().map(|(.., bytes)| 5 + bytes.len()).sum();
+        HEADER_SIZE + self.header.variable_count as usize * 4 + 1 + buffers + 4
+    }
+
+    /// Writes the state of the script to `buffer`, returning the amount of bytes written or
+    /// `None` if they do not fit. A script that halted on a trap is saved as it was before the
+    /// trapping instruction.
+    pub fn snapshot(&self, buffer: &mut [u8]) -> Option<usize> {
+        let len = self.snapshot_len();
+        let buffer = buffer.get_mut(..len)?;
+        buffer[..4].copy_from_slice(&MAGIC);
+        buffer[4] = VERSION;
+        NetworkEndian::write_u32(&mut buffer[5..], self.bytecode_hash);
+        NetworkEndian::write_u16(&mut buffer[9..], self.program_counter as u16);
+        buffer[11] = self.compare_flag as u8;
+        NetworkEndian::write_u16(&mut buffer[12..], self.header.variable_count);
+        let mut offset = HEADER_SIZE;
+        for variable in &self.variables[..self.header.variable_count as usize] {
+            NetworkEndian::write_i32(&mut buffer[offset..], *variable);
+            offset += 4;
+        }
+        buffer[offset] = self.memory.len() as u8;
+        offset += 1;
+        for (format, pixels, bytes) in self.memory.iter() {
+            buffer[offset] = format.index() as u8;
+            NetworkEndian::write_u32(&mut buffer[offset + 1..], pixels as u32);
+            buffer[offset + 5..offset + 5 + bytes.len()].copy_from_slice(bytes);
+            offset += 5 + bytes.len();
+        }
+        let checksum = !crc32_update(!0, &buffer[..offset]);
+        NetworkEndian::write_u32(&mut buffer[offset..], checksum);
+        Some(len)
+    }
+
+    /// Continues the script from a snapshot of it. The snapshot is checked completely before
+    /// anything is changed, so the runtime is left as it was if this fails.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), Error> {
+        if snapshot.len() < HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+        if snapshot[..4] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if snapshot[4] != VERSION {
+            return Err(Error::UnsupportedVersion(snapshot[4]));
+        }
+        let variable_count = NetworkEndian::read_u16(&snapshot[12..]) as usize;
+        let buffers_start = HEADER_SIZE + variable_count * 4;
+        let buffer_count = *snapshot.get(buffers_start).ok_or(Error::Truncated)? as usize;
+        // the format, amount of pixels and bytes of every buffer, checked before anything changes
+        let mut buffers: ArrayVec<[(PixelFormat, usize, Range<usize>); BUFFER_COUNT]> =
+            ArrayVec::new();
+        let mut checksum_start = buffers_start + 1;
+        for _ in 0..buffer_count {
+            let buffer = snapshot
+                .get(checksum_start..checksum_start + 5)
+                .ok_or(Error::Truncated)?;
+            // the size of a buffer depends on its format, so nothing after an unknown format can
+            // be read
+            let format = PixelFormat::from_index(buffer[0] as i32).ok_or(Error::Invalid)?;
+            let pixels = NetworkEndian::read_u32(&buffer[1..]) as usize;
+            let start = checksum_start + 5;
+            let end = format
+                .checked_byte_len(pixels)
+                .and_then(|len| start.checked_add(len))
+                .filter(|end| *end <= snapshot.len())
+                .ok_or(Error::Truncated)?;
+            // more buffers than fit are reported after the checksum
+            let _ = buffers.try_push((format, pixels, start..end));
+            checksum_start = end;
+        }
+        let checksum = snapshot
+            .get(checksum_start..checksum_start + 4)
+            .ok_or(Error::Truncated)?;
+        let expected = NetworkEndian::read_u32(checksum);
+        let found = !crc32_update(!0, &snapshot[..checksum_start]);
+        if expected != found {
+            return Err(Error::ChecksumMismatch { expected, found });
+        }
+        let found = NetworkEndian::read_u32(&snapshot[5..]);
+        if found != self.bytecode_hash {
+            return Err(Error::BytecodeMismatch {
+                expected: self.bytecode_hash,
+                found,
+            });
+        }
+        let program_counter = NetworkEndian::read_u16(&snapshot[9..]);
+        if variable_count != self.header.variable_count as usize
+            || buffer_count > buffers.len()
+            || !self
+                .memory
+                .fits(buffers.iter().map(|(format, pixels, _)| (*format, *pixels)))
+            || program_counter as usize > self.bytecode.len()
+            || !verifier::is_boundary(self.bytecode, program_counter)
+        {
+            return Err(Error::Invalid);
+        }
+
+        self.reset();
+        self.program_counter = program_counter as usize;
+        self.compare_flag = snapshot[11] != 0;
+        let variables = snapshot[HEADER_SIZE..buffers_start].chunks(4);
+        for (variable, bytes) in self.variables.iter_mut().zip(variables) {
+            *variable = NetworkEndian::read_i32(bytes);
+        }
+        self.memory.load(
+            buffers
+                .into_iter()
+                .map(|(format, pixels, range)| (format, pixels, &snapshot[range])),
+        );
+        Ok(())
+    }
+}
+
+#[test]
+#[cfg(feature = "compiler")]
+fn test_snapshot() {
+    let script = r#"
+buffer = get_bit_buffer(10)
+for x in 0, 10:
+    set_bit_buffer_index(buffer, x)
+    set_frame_buffer(buffer)
+"#;
+    let mut bytecode = [0u8; 256];
+    let len = crate::compiler::compile(script, &mut bytecode).unwrap();
+    let mut copy = bytecode;
+    let state = crate::test_state::TestState::default;
+    let mut runtime = Runtime::new(&mut bytecode[..len], state()).unwrap();
+    while runtime.state.screens.len() < 4 {
+        runtime.step().unwrap();
+    }
+    let mut snapshot = [0u8; 128];
+    let snapshot_len = runtime.snapshot(&mut snapshot).unwrap();
+    assert!(runtime
+        .snapshot(&mut snapshot[..snapshot_len - 1])
+        .is_none());
+
+    // continue in a runtime that was just loaded
+    let mut restored = Runtime::new(&mut copy[..len], state()).unwrap();
+    restored.restore(&snapshot[..snapshot_len]).unwrap();
+    assert_eq!(runtime.program_counter, restored.program_counter);
+    while !restored.is_finished() {
+        restored.step().unwrap();
+    }
+    assert_eq!(Some(&0b11_1111_1111), restored.state.screens.last());
+    assert_eq!(6, restored.state.screens.len());
+
+    assert_eq!(
+        Err(Error::Truncated),
+        restored.restore(&snapshot[..snapshot_len - 1])
+    );
+    snapshot[HEADER_SIZE] ^= 1;
+    assert!(matches!(
+        restored.restore(&snapshot[..snapshot_len]),
+        Err(Error::ChecksumMismatch { .. })
+    ));
+    snapshot[HEADER_SIZE] ^= 1;
+
+    // an unknown pixel format, with a checksum that matches
+    let mut unknown = snapshot;
+    let format_offset = HEADER_SIZE + restored.header.variable_count as usize * 4 + 1;
+    unknown[format_offset] = 0xFF;
+    let checksum = !crc32_update(!0, &unknown[..snapshot_len - 4]);
+    NetworkEndian::write_u32(&mut unknown[snapshot_len - 4..], checksum);
+    let program_counter = restored.program_counter;
+    assert_eq!(
+        Err(Error::Invalid),
+        restored.restore(&unknown[..snapshot_len])
+    );
+    assert_eq!(program_counter, restored.program_counter);
+
+    // a pixel count far past the end of the snapshot, or of a `usize` on a 32-bit target
+    let mut huge = snapshot;
+    huge[format_offset] = PixelFormat::Rgb888.index() as u8;
+    NetworkEndian::write_u32(&mut huge[format_offset + 1..], u32::MAX);
+    assert_eq!(
+        Err(Error::Truncated),
+        restored.restore(&huge[..snapshot_len])
+    );
+    assert_eq!(program_counter, restored.program_counter);
+
+    // buffers that do not fit in the memory of the runtime
+    let mut region = [0u8; 1];
+    restored.memory = crate::memory::Memory::new(&mut region);
+    assert_eq!(
+        Err(Error::Invalid),
+        restored.restore(&snapshot[..snapshot_len])
+    );
+    assert_eq!(program_counter, restored.program_counter);
+
+    let mut other = [0u8; 256];
+    let other_len = crate::compiler::compile("buffer = get_bit_buffer(8)", &mut other).unwrap();
+    let mut other = Runtime::new(&mut other[..other_len], state()).unwrap();
+    assert!(matches!(
+        other.restore(&snapshot[..snapshot_len]),
+        Err(Error::BytecodeMismatch { .. })
+    ));
+}
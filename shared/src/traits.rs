@@ -1,6 +1,36 @@
-pub trait State {
-    fn fill_random_bit_buffer(&mut self, bit_buffer: &mut u128);
-    fn draw_screen(&mut self, bit_buffer: u128);
-    fn wait_for_clock_high(&mut self);
-    fn wait_for_clock_low(&mut self);
-}
+use crate::pixel::PixelFormat;
+use crate::runtime::{Recovery, Trap};
+
+/// The host a script runs on. The screen and random methods are used by `Native::DEFAULTS`.
+///
+/// Buffers are passed as their bytes, see `pixel` for how the pixels are laid out.
+pub trait State {
+    fn fill_random_bit_buffer(&mut self, bit_buffer: &mut [u8]);
+    fn draw_screen(&mut self, bit_buffer: &[u8]);
+    /// Shows a buffer of any format on the screen, called by `set_frame_buffer`. Hosts with a
+    /// colour screen implement this, the default draws 1-bit buffers with `draw_screen` and
+    /// ignores the others.
+    fn draw_frame(&mut self, format: PixelFormat, data: &[u8]) {
+        if format == PixelFormat::Mono {
+            self.draw_screen(data);
+        }
+    }
+    /// Width and height of the screen, used by `xy_to_buffer_index`.
+    fn screen_size(&self) -> (u32, u32);
+    /// Called when the script traps with `TrapPolicy::Handler`, to report the trap and decide
+    /// what happens next. Halts by default.
+    fn on_trap(&mut self, trap: Trap) -> Recovery {
+        Recovery::Halt
+    }
+}
+
+/// A `State` for hosts with an async executor, which wait for the clock without blocking. Used
+/// by `Runtime::run_async`.
+// The executors this is for run on a single thread, so the futures do not need to be `Send`
+#[allow(async_fn_in_trait)]
+pub trait AsyncState: State {
+    /// Resolves once the clock is high.
+    async fn wait_for_clock_high(&mut self);
+    /// Resolves once the clock is low.
+    async fn wait_for_clock_low(&mut self);
+}
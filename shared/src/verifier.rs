@@ -0,0 +1,362 @@
+//! Checks bytecode once before it is run, so the runtime does not have to check every
+//! instruction while running it.
+//!
+//! A verified program only contains valid instructions, only refers to variable slots that are
+//! counted in its header and to numbers in its constant pool, only calls natives the host has
+//! and passes them the amount of arguments they take, only passes strings to the arguments that
+//! take one, and only jumps to the start of an instruction or the end of the code.
+//! There are no call instructions, so there is no stack that could overflow.
+
+use crate::container::Header;
+use crate::instructions::{DecodeError, Instructions, VariableRef};
+use crate::natives::{self, Signature, Type};
+use crate::pool::{Constant, ConstantPool};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error {
+    /// Offset in the code of the offending instruction
+    pub offset: usize,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Decode(DecodeError),
+    /// A jump goes past the end of the code.
+    JumpOutOfRange(u16),
+    /// A jump, or the entry point, lands in the middle of an instruction.
+    JumpIntoInstruction(u16),
+    /// A variable slot is used that is not counted in the header.
+    VariableOutOfRange(u8),
+    /// A constant is used that is not in the constant pool.
+    ConstantOutOfRange(u8),
+    /// A constant is used as a value, but it is not a number.
+    NotANumber(u8),
+    /// A native that takes a string is passed something other than a string constant.
+    NotAString,
+    /// A native is called that the host does not have.
+    UnknownNative(u8),
+    /// An argument of a call is left out while an argument after it is not, by its index. Only
+    /// trailing arguments can be left out.
+    MissingArgument(usize),
+    /// A native is not passed a value for every argument it takes.
+    WrongArgumentCount {
+        expected: usize,
+        found: usize,
+    },
+    /// A native result is stored in something other than a variable, or a result is stored
+    /// from a native that does not return anything.
+    InvalidResult,
+}
+
+pub fn verify<'n>(
+    header: &Header,
+    code: &[u8],
+    constants: &ConstantPool,
+    natives: &[impl AsRef<Signature<'n>>],
+) -> Result<(), Error> {
+    let boundaries = Boundaries::find(code)?;
+    if !boundaries.contains(header.entry_point) {
+        return Err(Error {
+            offset: header.entry_point as usize,
+            kind: ErrorKind::JumpIntoInstruction(header.entry_point),
+        });
+    }
+
+    let mut offset = 0;
+    while offset < code.len() {
+        let error = |kind| Error { offset, kind };
+        let instruction =
+            Instructions::decode(&code[offset..]).map_err(|e| error(ErrorKind::Decode(e)))?;
+
+        let check_variable = |variable: &VariableRef| match variable {
+            VariableRef::Idx(idx) if *idx as u16 >= header.variable_count => {
+                Err(error(ErrorKind::VariableOutOfRange(*idx)))
+            }
+            VariableRef::Const(idx) => match constants.get(*idx) {
+                Some(Constant::Number(_)) => Ok(()),
+                Some(_) => Err(error(ErrorKind::NotANumber(*idx))),
+                None => Err(error(ErrorKind::ConstantOutOfRange(*idx))),
+            },
+            _ => Ok(()),
+        };
+        match &instruction {
+            Instructions::CallMethod {
+                result_variable,
+                method,
+                args,
+            } => {
+                let signature = natives::get(natives, method.index())
+                    .ok_or_else(|| error(ErrorKind::UnknownNative(method.index())))?;
+                match result_variable {
+                    VariableRef::None => {}
+                    VariableRef::Idx(_) if signature.result.is_some() => {
+                        check_variable(result_variable)?
+                    }
+                    _ => return Err(error(ErrorKind::InvalidResult)),
+                }
+                let args = &args[..method.arg_len()];
+                let found = args
+                    .iter()
+                    .rposition(|arg| *arg != VariableRef::None)
+                    .map_or(0, |last| last + 1);
+                if let Some(missing) = args[..found]
+                    .iter()
+                    .position(|arg| *arg == VariableRef::None)
+                {
+                    return Err(error(ErrorKind::MissingArgument(missing)));
+                }
+                if found != signature.args.len() {
+                    return Err(error(ErrorKind::WrongArgumentCount {
+                        expected: signature.args.len(),
+                        found,
+                    }));
+                }
+                for (arg, ty) in args.iter().zip(signature.args) {
+                    match (arg, ty) {
+                        (VariableRef::Const(idx), Type::String) => match constants.get(*idx) {
+                            Some(Constant::String(_)) => {}
+                            Some(_) => return Err(error(ErrorKind::NotAString)),
+                            None => return Err(error(ErrorKind::ConstantOutOfRange(*idx))),
+                        },
+                        (_, Type::String) => return Err(error(ErrorKind::NotAString)),
+                        (arg, _) => check_variable(arg)?,
+                    }
+                }
+            }
+            Instructions::CompareEquals { left, right }
+            | Instructions::CompareLessThan { left, right }
+            | Instructions::CompareLessOrEqualTo { left, right } => {
+                check_variable(left)?;
+                check_variable(right)?;
+            }
+            Instructions::Move { target, value } => {
+                check_variable(&VariableRef::Idx(*target))?;
+                check_variable(value)?;
+            }
+            Instructions::Add {
+                target,
+                left,
+                right,
+            }
+            | Instructions::Subtract {
+                target,
+                left,
+                right,
+            }
+            | Instructions::Multiply {
+                target,
+                left,
+                right,
+            }
+            | Instructions::ShiftLeft {
+                target,
+                left,
+                right,
+            } => {
+                check_variable(&VariableRef::Idx(*target))?;
+                check_variable(left)?;
+                check_variable(right)?;
+            }
+            Instructions::Jump { target }
+            | Instructions::JumpIfTrue { target }
+            | Instructions::JumpIfFalse { target } => {
+                if *target as usize > code.len() {
+                    return Err(error(ErrorKind::JumpOutOfRange(*target)));
+                }
+                if !boundaries.contains(*target) {
+                    return Err(error(ErrorKind::JumpIntoInstruction(*target)));
+                }
+            }
+        }
+        offset += instruction.size();
+    }
+    Ok(())
+}
+
+/// The offsets instructions start at, found in a single pass so every jump can be checked
+/// without walking the code again. Takes 8 KiB, a bit for every offset a jump can have.
+struct Boundaries {
+    bits: [u8; (u16::MAX as usize + 1) / 8],
+}
+
+impl Boundaries {
+    fn find(code: &[u8]) -> Result<Self, Error> {
+        let mut boundaries = Boundaries {
+            bits: [0; (u16::MAX as usize + 1) / 8],
+        };
+        let mut offset = 0;
+        // the end of the code counts as a boundary, so it is marked as well
+        loop {
+            if let Some(byte) = boundaries.bits.get_mut(offset / 8) {
+                *byte |= 1 << (offset % 8);
+            }
+            if offset >= code.len() {
+                return Ok(boundaries);
+            }
+            let instruction = Instructions::decode(&code[offset..]).map_err(|e| Error {
+                offset,
+                kind: ErrorKind::Decode(e),
+            })?;
+            offset += instruction.size();
+        }
+    }
+
+    fn contains(&self, offset: u16) -> bool {
+        self.bits[offset as usize / 8] & 1 << (offset % 8) != 0
+    }
+}
+
+/// Checks that code can continue at `target`, like a jump does.
+pub(crate) fn check_target(code: &[u8], target: u16) -> Result<(), ErrorKind> {
+    if target as usize > code.len() {
+        return Err(ErrorKind::JumpOutOfRange(target));
+    }
+    if !is_boundary(code, target) {
+        return Err(ErrorKind::JumpIntoInstruction(target));
+    }
+    Ok(())
+}
+
+/// Whether an instruction starts at `target`, or `target` is the end of the code.
+///
+/// Walks the code from the start, for checking a single offset after `verify`, which finds every
+/// boundary at once. Only called on code that decodes up to `target`.
+pub(crate) fn is_boundary(code: &[u8], target: u16) -> bool {
+    let target = target as usize;
+    let mut offset = 0;
+    while offset < target {
+        match Instructions::decode(&code[offset..]) {
+            Ok(instruction) => offset += instruction.size(),
+            Err(_) => return false,
+        }
+    }
+    offset == target
+}
+
+#[test]
+fn test_verify() {
+    use crate::instructions::MethodRef;
+    use crate::natives::{DEFAULT_SIGNATURES, MAX_ARGS};
+
+    let header = Header {
+        required_features: 0,
+        variable_count: 2,
+        entry_point: 0,
+        code_len: 0,
+        natives_hash: 0,
+        pool_len: 0,
+    };
+    // a number, followed by a string
+    let pool = [2, 0, 5, 0, 10, 0x01, 0, 0, 0, 42, 0x03, 2, b'h', b'i'];
+    let constants = ConstantPool::read(&pool).unwrap();
+    let verify_code = |code: &[Instructions]| {
+        let mut bytecode = [0u8; 64];
+        let mut len = 0;
+        for instruction in code {
+            instruction.write(&mut bytecode[len..]);
+            len += instruction.size();
+        }
+        verify(&header, &bytecode[..len], &constants, &DEFAULT_SIGNATURES)
+            .map_err(|e| (e.offset, e.kind))
+    };
+    let move_to = |target| Instructions::Move {
+        target,
+        value: VariableRef::Num(1),
+    };
+
+    assert_eq!(
+        Ok(()),
+        verify_code(&[move_to(1), Instructions::Jump { target: 6 }])
+    );
+    assert_eq!(
+        Err((3, ErrorKind::JumpIntoInstruction(2))),
+        verify_code(&[move_to(1), Instructions::Jump { target: 2 }])
+    );
+    assert_eq!(
+        Err((3, ErrorKind::JumpOutOfRange(7))),
+        verify_code(&[move_to(1), Instructions::Jump { target: 7 }])
+    );
+    assert_eq!(
+        Err((0, ErrorKind::VariableOutOfRange(2))),
+        verify_code(&[move_to(2)])
+    );
+    let move_const = |idx| Instructions::Move {
+        target: 0,
+        value: VariableRef::Const(idx),
+    };
+    assert_eq!(Ok(()), verify_code(&[move_const(0)]));
+    assert_eq!(
+        Err((0, ErrorKind::NotANumber(1))),
+        verify_code(&[move_const(1)])
+    );
+    assert_eq!(
+        Err((0, ErrorKind::ConstantOutOfRange(2))),
+        verify_code(&[move_const(2)])
+    );
+    assert_eq!(
+        Err((
+            0,
+            ErrorKind::WrongArgumentCount {
+                expected: 2,
+                found: 1
+            }
+        )),
+        verify_code(&[Instructions::CallMethod {
+            result_variable: VariableRef::None,
+            method: MethodRef::new(2, 2).unwrap(),
+            args: {
+                let mut args = [VariableRef::None; MAX_ARGS];
+                args[0] = VariableRef::Idx(0);
+                args
+            },
+        }])
+    );
+    // set_bit_buffer_index(buffer, index) with its buffer left out
+    assert_eq!(
+        Err((0, ErrorKind::MissingArgument(0))),
+        verify_code(&[Instructions::CallMethod {
+            result_variable: VariableRef::None,
+            method: MethodRef::new(2, 2).unwrap(),
+            args: {
+                let mut args = [VariableRef::None; MAX_ARGS];
+                args[1] = VariableRef::Idx(0);
+                args
+            },
+        }])
+    );
+    assert_eq!(
+        Err((0, ErrorKind::UnknownNative(40))),
+        verify_code(&[Instructions::CallMethod {
+            result_variable: VariableRef::None,
+            method: MethodRef::new(40, 0).unwrap(),
+            args: [VariableRef::None; MAX_ARGS],
+        }])
+    );
+    // draw_text(buffer, x, y, text) only takes a string constant as its text
+    let draw_text = |text| Instructions::CallMethod {
+        result_variable: VariableRef::None,
+        method: MethodRef::new(21, 4).unwrap(),
+        args: [
+            VariableRef::Idx(0),
+            VariableRef::Num(0),
+            VariableRef::Num(0),
+            text,
+            VariableRef::None,
+            VariableRef::None,
+        ],
+    };
+    assert_eq!(Ok(()), verify_code(&[draw_text(VariableRef::Const(1))]));
+    assert_eq!(
+        Err((0, ErrorKind::NotAString)),
+        verify_code(&[draw_text(VariableRef::Const(0))])
+    );
+    assert_eq!(
+        Err((0, ErrorKind::NotAString)),
+        verify_code(&[draw_text(VariableRef::Num(1))])
+    );
+    assert_eq!(
+        Err((0, ErrorKind::Decode(DecodeError::InvalidOpcode(0xFF)))),
+        verify(&header, &[0xFF], &constants, &DEFAULT_SIGNATURES).map_err(|e| (e.offset, e.kind))
+    );
+}